@@ -41,6 +41,8 @@ impl Arch for ArchII {
             "sd" => false,
             "sdc1" => false,
             "sdc2" => false,
+            "lld" => false,
+            "scd" => false,
             "ldl" => false,
             "ldr" => false,
             "sdl" => false,
@@ -62,10 +64,14 @@ impl Arch for ArchI {
             "bnel" => false,
             "bgtzl" => false,
             "bgezl" => false,
-            "btlzl" => false,
+            "bltzl" => false,
             "blezl" => false,
-            "btlzall" => false,
+            "bltzall" => false,
             "bgezall" => false,
+            "ll" => false,
+            "sc" => false,
+            "tge" | "tgeu" | "tlt" | "tltu" | "teq" | "tne" => false,
+            "tgei" | "tgeiu" | "tlti" | "tltiu" | "teqi" | "tnei" => false,
             _ => true,
         }
     }
@@ -1,6 +1,7 @@
 use bitfield::bitfield;
 
 use super::decode::REG_NAMES;
+use super::mmu::TlbError;
 use super::{Cop, Cop0, CpuContext, Exception};
 use emu::dbg::{DebuggerRenderer, DecodedInsn, Operand, RegisterSize, RegisterView, Result, Tracer};
 use emu::int::Numerics;
@@ -43,6 +44,13 @@ const COP0_REG_NAMES: [&'static str; 32] = [
     "?31?",
 ];
 
+// Processor revision (VR4300, revision 2.2)
+const PRID: u32 = 0x0000_0B22;
+
+// Config at reset. Only K0, CU, BE and EP are writable.
+const CONFIG_RESET: u32 = 0x7006_E463;
+const CONFIG_MASK: u32 = 0x0F00_800F;
+
 bitfield! {
     #[derive(Default, Copy, Clone, Serialize, Deserialize)]
     struct RegStatus(u32);
@@ -55,7 +63,7 @@ bitfield! {
     #[inline] pub sr, set_sr: 20;   // Is this a soft reset?
     #[inline] pub ts, set_ts: 21;   // Multiple TLB match
     #[inline] pub bev, set_bev: 22; // Exception vector location (normal/bootstrap)
-    #[inline] pub rp, set_rp: 21;   // Reduced power
+    #[inline] pub rp, set_rp: 27;   // Reduced power
     #[inline] pub fr, set_fr: 26;   // Is FPU in 64-bit mode?
     #[inline] pub cu0, set_cu0: 28; // Is COP0 active?
     #[inline] pub cu1, set_cu1: 29; // Is COP1 active?
@@ -82,7 +90,15 @@ struct Cp0Context {
     reg_errorepc: u64,
     reg_epc: u64,
     reg_index: u32,
+    reg_wired: u32,
+    reg_context: u64,
+    reg_xcontext: u64,
+    reg_badvaddr: u64,
+    reg_config: u32,
+    reg_watchlo: u32,
+    reg_watchhi: u32,
     reg_pagemask: u32,
+    reg_taglo: u32,
     reg_entryhi: u64,
     reg_entrylo0: u64,
     reg_entrylo1: u64,
@@ -90,6 +106,7 @@ struct Cp0Context {
     last_count: u32,
     last_count_clock: i64,
     next_timer_interrupt: i64,
+    random_clock: i64,
 }
 
 pub struct Cp0 {
@@ -130,12 +147,35 @@ impl Cp0 {
         self.set_hwint_line(5, false);
     }
 
+    // Random decrements at each cycle from 31 down to Wired, and then wraps
+    // around. It is reset to 31 when Wired is written.
+    fn get_random(&self, cpu: &CpuContext) -> u32 {
+        let wired = if self.ctx.reg_wired < 32 {
+            self.ctx.reg_wired
+        } else {
+            0
+        };
+        let elapsed = (cpu.clock - self.ctx.random_clock) as u64;
+        31 - (elapsed % (32 - wired) as u64) as u32
+    }
+
+    fn set_wired(&mut self, cpu: &CpuContext, val: u32) {
+        self.ctx.reg_wired = val & 0x3F;
+        self.ctx.random_clock = cpu.clock;
+    }
+
     fn update_timer_interrupt(&mut self, cpu: &CpuContext) {
         // Compute the CPU clock at which there will be the next timer interrupt.
         // There always is a potential timer interrupt in the future because of
-        // the 32-bit wrap-around.
-        self.ctx.next_timer_interrupt =
-            cpu.clock + ((self.ctx.reg_compare.wrapping_sub(self.get_count(cpu)) as i64) << 1);
+        // the 32-bit wrap-around: if Count already matches Compare, the next
+        // match is a full period away.
+        let delta = self
+            .ctx
+            .reg_compare
+            .wrapping_sub(self.get_count(cpu))
+            .wrapping_sub(1) as i64
+            + 1;
+        self.ctx.next_timer_interrupt = cpu.clock + (delta << 1);
         info!(self.logger, "COP0 update timer IRQ";
             "clock" => cpu.clock,
             "next_irq" => self.ctx.next_timer_interrupt,
@@ -160,7 +200,7 @@ impl Cop0 for Cp0 {
         let ctx = unsafe { self.ctx.as_mut() };
         if cpu.clock >= ctx.next_timer_interrupt {
             self.set_hwint_line(5, true);
-            ctx.next_timer_interrupt += 0x8000_0000; // 2**32 / 2
+            ctx.next_timer_interrupt += 1 << 33; // 2**32 counts, at half the clock
             info!(self.logger, "COP0 timer IRQ raised");
         }
         if ctx.reg_status.ie()
@@ -180,51 +220,71 @@ impl Cop0 for Cp0 {
 
         match exc {
             ColdReset => {
-                // ctx.reg_random = 31;
-                // ctx.reg_wired = 0;
-                // ctx.reg_config.set_k0(2);
-                // ctx.reg_config[0..3] should be configured as specified in MipsConfig
+                ctx.reg_wired = 0;
+                ctx.random_clock = cpu.clock;
+                ctx.reg_config = CONFIG_RESET;
                 ctx.reg_status.set_rp(false);
                 ctx.reg_status.set_bev(true);
                 ctx.reg_status.set_ts(false);
                 ctx.reg_status.set_sr(false);
                 ctx.reg_status.set_nmi(false);
                 ctx.reg_status.set_erl(true);
-                // self.watch_lo[..] = 0;
-                // ctx.reg_perfcnt[..].set_ie(0);
-                ctx.reg_epc = cpu.pc;
+                ctx.reg_watchlo = 0;
+                ctx.reg_errorepc = cpu.pc;
                 cpu.set_pc(0xFFFF_FFFF_BFC0_0000);
             }
             SoftReset => {
-                // self.ref_config.set_k0(2);
                 ctx.reg_status.set_rp(false);
                 ctx.reg_status.set_bev(true);
                 ctx.reg_status.set_ts(false);
                 ctx.reg_status.set_sr(true);
                 ctx.reg_status.set_nmi(false);
                 ctx.reg_status.set_erl(true);
-                // self.watch_lo[..] = 0;
-                // ctx.reg_perfcnt[..].set_ie(0);
-                ctx.reg_epc = cpu.pc;
+                ctx.reg_watchlo = 0;
+                ctx.reg_errorepc = cpu.pc;
                 cpu.set_pc(0xFFFF_FFFF_BFC0_0000);
             }
             Nmi => {
                 error!(self.logger, "unimplemented exception type"; "exc" => ?exc);
             }
             _ => {
+                match exc {
+                    TlbRefill { vaddr, .. }
+                    | XTlbRefill { vaddr, .. }
+                    | TlbInvalid { vaddr, .. }
+                    | TlbModified { vaddr } => {
+                        ctx.reg_badvaddr = vaddr;
+                        ctx.reg_context =
+                            (ctx.reg_context & !0x7F_FFF0) | ((vaddr >> 9) & 0x7F_FFF0);
+                        ctx.reg_xcontext = (ctx.reg_xcontext & !0x1_FFFF_FFF0)
+                            | ((vaddr >> 9) & 0x7FFF_FFF0)
+                            | ((vaddr >> 62) << 31);
+                        ctx.reg_entryhi =
+                            (ctx.reg_entryhi & 0xFF) | (vaddr & 0xC000_00FF_FFFF_E000);
+                    }
+                    _ => {}
+                }
+
+                // Interrupts are taken between two insns; all other
+                // exceptions are reported at the insn that caused them.
+                let (pc, delay_slot) = match exc {
+                    Interrupt => (cpu.pc, cpu.delay_slot),
+                    _ => (cpu.insn_pc, cpu.insn_delay_slot),
+                };
+
                 // Standard exception
                 let vector = if !ctx.reg_status.exl() {
-                    if !cpu.delay_slot {
-                        ctx.reg_epc = cpu.pc;
+                    if !delay_slot {
+                        ctx.reg_epc = pc;
                         ctx.reg_cause.set_bd(false);
                     } else {
-                        ctx.reg_epc = cpu.pc - 4;
+                        ctx.reg_epc = pc - 4;
                         ctx.reg_cause.set_bd(true);
                     }
 
                     match exc {
-                        TlbRefill => 0x0,
-                        XTlbRefill => 0x80,
+                        TlbRefill { .. } => 0x0,
+                        XTlbRefill { .. } => 0x80,
                         Interrupt if ctx.reg_cause.iv() => 0x200,
                         _ => 0x180,
                     }
@@ -244,21 +304,68 @@ impl Cop0 for Cp0 {
             }
         };
     }
+
+    fn next_event(&self) -> Option<i64> {
+        Some(self.ctx.next_timer_interrupt)
+    }
+
+    fn translate(
+        &self,
+        cpu: &CpuContext,
+        vaddr: u32,
+        write: bool,
+    ) -> std::result::Result<u32, Exception> {
+        // KSEG0 and KSEG1 are directly mapped to physical memory
+        if vaddr >> 30 == 0b10 {
+            return Ok(vaddr & 0x1FFF_FFFF);
+        }
+
+        let vaddr = vaddr as i32 as u64;
+        let asid = self.ctx.reg_entryhi as u8;
+        cpu.mmu
+            .translate(vaddr, asid, write)
+            .map_err(|err| match err {
+                TlbError::Miss => Exception::TlbRefill { vaddr, write },
+                TlbError::Invalid => Exception::TlbInvalid { vaddr, write },
+                TlbError::Modified => Exception::TlbModified { vaddr },
+            })
+    }
+
+    fn watch(&self, paddr: u32, write: bool) -> bool {
+        // WatchLo: PAddr0 (bits 31:3), R (bit 1), W (bit 0). WatchHi holds
+        // physical address bits 35:32, which are always zero on N64.
+        let lo = self.ctx.reg_watchlo;
+        let enabled = if write { lo & 1 != 0 } else { lo & 2 != 0 };
+        enabled && self.ctx.reg_watchhi == 0 && (paddr ^ lo) & !7 == 0 && !self.ctx.reg_status.exl()
+    }
 }
 
 impl Cop for Cp0 {
     fn reg(&self, cpu: &CpuContext, idx: usize) -> u128 {
         match idx {
             0 => self.ctx.reg_index as u128,
+            1 => self.get_random(cpu) as u128,
             2 => self.ctx.reg_entrylo0 as u128,
             3 => self.ctx.reg_entrylo1 as u128,
+            4 => self.ctx.reg_context as u128,
             5 => self.ctx.reg_pagemask as u128,
+            6 => self.ctx.reg_wired as u128,
+            8 => self.ctx.reg_badvaddr as u128,
             9 => self.get_count(cpu) as u128,
             10 => self.ctx.reg_entryhi as u128,
             11 => self.ctx.reg_compare as u128,
             12 => self.ctx.reg_status.0 as u128,
             13 => self.ctx.reg_cause.0 as u128,
             14 => self.ctx.reg_epc as u128,
+            15 => PRID as u128,
+            16 => self.ctx.reg_config as u128,
+            17 => cpu.lladdr as u128,
+            18 => self.ctx.reg_watchlo as u128,
+            19 => self.ctx.reg_watchhi as u128,
+            20 => self.ctx.reg_xcontext as u128,
+            26 | 27 => 0, // Parity/cache errors are never reported
+            28 => self.ctx.reg_taglo as u128,
+            29 => 0, // TagHi is reserved (always zero)
             30 => self.ctx.reg_errorepc as u128,
             _ => {
                 error!(
@@ -274,11 +381,19 @@ impl Cop for Cp0 {
     fn set_reg(&mut self, cpu: &mut CpuContext, idx: usize, val: u128) {
         match idx {
             0 => self.ctx.reg_index = val as u32 & 0x3F,
-            2 => self.ctx.reg_entrylo0 = val as u64,
-            3 => self.ctx.reg_entrylo1 = val as u64,
-            5 => self.ctx.reg_pagemask = val as u32,
+            1 => {} // Random is read-only
+            2 => self.ctx.reg_entrylo0 = val as u64 & 0x3FFF_FFFF,
+            3 => self.ctx.reg_entrylo1 = val as u64 & 0x3FFF_FFFF,
+            4 => {
+                // BadVPN2 is read-only
+                let ctx = &mut self.ctx;
+                ctx.reg_context = (ctx.reg_context & 0x7F_FFFF) | (val as u64 & !0x7F_FFFF);
+            }
+            5 => self.ctx.reg_pagemask = val as u32 & 0x01FF_E000,
+            6 => self.set_wired(cpu, val as u32),
+            8 => {} // BadVAddr is read-only
             9 => self.set_count(cpu, val as u32),
-            10 => self.ctx.reg_entryhi = val as u64,
+            10 => self.ctx.reg_entryhi = val as u64 & 0xC000_00FF_FFFF_E0FF,
             11 => self.set_compare(cpu, val as u32),
             12 => {
                 self.ctx.reg_status.0 = val as u32;
@@ -286,10 +401,29 @@ impl Cop for Cp0 {
                 cpu.tight_exit = true;
             }
             13 => {
-                self.ctx.reg_cause.0 = val as u32;
+                // Only the software interrupts (IP0/IP1) are writable
+                let cause = &mut self.ctx.reg_cause.0;
+                *cause = (*cause & !0x300) | (val as u32 & 0x300);
                 cpu.tight_exit = true;
             }
             14 => self.ctx.reg_epc = val as u64,
+            15 => {} // PRId is read-only
+            16 => {
+                let config = &mut self.ctx.reg_config;
+                *config = (*config & !CONFIG_MASK) | (val as u32 & CONFIG_MASK);
+            }
+            17 => cpu.lladdr = val as u32,
+            18 => self.ctx.reg_watchlo = val as u32 & 0xFFFF_FFFB,
+            19 => self.ctx.reg_watchhi = val as u32 & 0xF,
+            20 => {
+                // Only PTEBase is writable
+                let ctx = &mut self.ctx;
+                ctx.reg_xcontext =
+                    (ctx.reg_xcontext & 0x1_FFFF_FFFF) | (val as u64 & !0x1_FFFF_FFFF);
+            }
+            26 | 27 => {}
+            28 => self.ctx.reg_taglo = val as u32 & 0x0FFF_FFC0,
+            29 => {}
             30 => self.ctx.reg_errorepc = val as u64,
             _ => {
                 error!(
@@ -324,8 +458,8 @@ impl Cop for Cp0 {
                     // TLBR
                     let entry = cpu.mmu.read((ctx.reg_index & 0x1F) as usize);
                     ctx.reg_entryhi = entry.hi();
-                    ctx.reg_entrylo0 = entry.lo0;
-                    ctx.reg_entrylo1 = entry.lo1;
+                    ctx.reg_entrylo0 = (entry.lo0 & !1) | entry.global as u64;
+                    ctx.reg_entrylo1 = (entry.lo1 & !1) | entry.global as u64;
                     ctx.reg_pagemask = entry.page_mask;
                     info!(self.logger, "read TLB entry";
                         "idx" => ctx.reg_index,
@@ -345,6 +479,21 @@ impl Cop for Cp0 {
                         "idx" => ctx.reg_index,
                         "tlb" => ?cpu.mmu.read((ctx.reg_index & 0x1F) as usize));
                 }
+                0x06 => {
+                    // TLBWR
+                    let idx = self.get_random(cpu) as usize;
+                    cpu.mmu.write(
+                        idx,
+                        ctx.reg_pagemask,
+                        ctx.reg_entryhi,
+                        ctx.reg_entrylo0,
+                        ctx.reg_entrylo1,
+                    );
+
+                    info!(self.logger, "wrote random TLB entry";
+                        "idx" => idx,
+                        "tlb" => ?cpu.mmu.read(idx));
+                }
                 0x08 => {
                     // TLBP
                     match cpu.mmu.probe(ctx.reg_entryhi, ctx.reg_entryhi as u8) {
@@ -365,6 +514,8 @@ impl Cop for Cp0 {
                 0x18 => {
                     // ERET
                     // FIXME: verify that it's a NOP when ERL/EXL are 0
+                    // ERET breaks any pending LL/SC sequence.
+                    cpu.llbit = false;
                     if ctx.reg_status.erl() {
                         ctx.reg_status.set_erl(false);
                         cpu.set_pc(ctx.reg_errorepc);
@@ -404,6 +555,7 @@ impl Cop for Cp0 {
             0x10..=0x1F => match func {
                 0x1 => DecodedInsn::new0("tlbr"),
                 0x2 => DecodedInsn::new0("tlbwi"),
+                0x6 => DecodedInsn::new0("tlbwr"),
                 0x8 => DecodedInsn::new0("tlbp"),
                 0x18 => DecodedInsn::new0("eret"),
                 _ => DecodedInsn::new1("cop0op?", Imm32(func)),
@@ -456,7 +608,13 @@ impl RegisterView for Cp0 {
                 visit("EPC", Reg64(&mut ctx.reg_epc), None);
                 visit("ErrorEPC", Reg64(&mut ctx.reg_errorepc), None);
 
+                visit("BadVAddr", Reg64(&mut ctx.reg_badvaddr), None);
+
+                visit("Config", Reg32(&mut ctx.reg_config), None);
+                visit("WatchLo", Reg32(&mut ctx.reg_watchlo), None);
+
                 visit("Index", Reg32(&mut ctx.reg_index), None);
+                visit("Wired", Reg32(&mut ctx.reg_wired), None);
                 visit("PageMask", Reg32(&mut ctx.reg_pagemask), None);
                 visit("EntryHi", Reg64(&mut ctx.reg_entryhi), None);
                 visit("EntryLo0", Reg64(&mut ctx.reg_entrylo0), None);
@@ -1,5 +1,6 @@
 use super::decode::{decode, REG_NAMES};
 use super::mmu::Mmu;
+use super::trace::{CpuTrace, TraceFilter};
 use super::{Arch, Config, Cop, Cop0};
 
 use emu::bus::be::{Bus, MemIoR};
@@ -15,30 +16,43 @@ use emu::sync;
 use byteorder::ByteOrder;
 use serde_derive::{Deserialize, Serialize};
 use slog;
+use std::path::Path;
 
 #[derive(Copy, Clone, Debug)]
 pub enum Exception {
     Interrupt,  // Interrupt
     Breakpoint, // Breakpoint
+    Syscall,    // System call
     ColdReset,
     SoftReset,
     Nmi,
-    TlbRefill,
-    XTlbRefill,
+    TlbRefill { vaddr: u64, write: bool },
+    XTlbRefill { vaddr: u64, write: bool },
+    TlbInvalid { vaddr: u64, write: bool },
+    TlbModified { vaddr: u64 },
+    Overflow,
     Trap,
+    FloatingPoint,
+    Watch,
 }
 
 impl Exception {
     pub(crate) fn exc_code(&self) -> Option<u32> {
         match self {
             Exception::Interrupt => Some(0x00),
+            Exception::Syscall => Some(0x08),
             Exception::Breakpoint => Some(0x09),
             Exception::ColdReset => None,
             Exception::Nmi => None,
             Exception::SoftReset => None,
-            Exception::TlbRefill => None,
-            Exception::XTlbRefill => None,
+            Exception::TlbModified { .. } => Some(0x01),
+            Exception::TlbRefill { write, .. }
+            | Exception::XTlbRefill { write, .. }
+            | Exception::TlbInvalid { write, .. } => Some(if *write { 0x03 } else { 0x02 }),
+            Exception::Overflow => Some(0x0C),
             Exception::Trap => Some(0x0D),
+            Exception::FloatingPoint => Some(0x0F),
+            Exception::Watch => Some(0x17),
         }
     }
 }
@@ -50,17 +64,23 @@ struct Lines {
 
 #[derive(Default, Copy, Clone, Serialize, Deserialize)]
 pub struct CpuContext {
-    pub regs: [u64; 32],  // 32 64-bit GPR
-    pub hi: u64,          // HI mul register
-    pub lo: u64,          // LO mul register
-    pub pc: u64,          // Program counter
-    pub next_pc: u64,     // Next program counter (for jumps)
-    pub clock: i64,       // Current clock
-    pub tight_exit: bool, // True if we need to exit the tight loop
-    pub delay_slot: bool, // True if the current insn is a delay slot
-    pub mmu: Mmu,         // The MMU
-    pub fpu64: bool,      // True if the FPU (if any) is in 64-bit mode
+    pub regs: [u64; 32],              // 32 64-bit GPR
+    pub hi: u64,                      // HI mul register
+    pub lo: u64,                      // LO mul register
+    pub pc: u64,                      // Program counter
+    pub next_pc: u64,                 // Next program counter (for jumps)
+    pub clock: i64,                   // Current clock
+    pub tight_exit: bool,             // True if we need to exit the tight loop
+    pub delay_slot: bool,             // True if the current insn is a delay slot
+    pub mmu: Mmu,                     // The MMU
+    pub fpu64: bool,                  // True if the FPU (if any) is in 64-bit mode
+    pub llbit: bool,                  // Set by LL/LLD, cleared by ERET
+    pub lladdr: u32,                  // Physical address of the last LL/LLD (>> 4)
+    pub(crate) insn_pc: u64,          // Address of the current insn
+    pub(crate) insn_delay_slot: bool, // True if the current insn is a delay slot
     lines: Lines,
+    #[serde(skip)]
+    exception: Option<Exception>, // Exception raised by a coprocessor op
 }
 
 pub struct Cpu<C: Config> {
@@ -77,6 +97,8 @@ pub struct Cpu<C: Config> {
     until: i64,
 
     last_busy_check: u64,
+    trace: Option<CpuTrace>,
+    break_resumed: Option<u64>, // PC of a BREAK that stopped the debugger
 }
 
 struct Mipsop<'a, C: Config> {
@@ -187,6 +209,12 @@ impl CpuContext {
     pub fn get_pc(&self) -> u64 {
         self.pc
     }
+
+    // Raise an exception from a coprocessor op. It is triggered as soon as
+    // the op returns.
+    pub fn raise_exception(&mut self, exc: Exception) {
+        self.exception = Some(exc);
+    }
 }
 
 macro_rules! branch {
@@ -194,16 +222,18 @@ macro_rules! branch {
         branch!($op, $cond, $tgt, link(false), likely(false));
     }};
     ($op:ident, $cond:expr, $tgt:expr,link($link:expr)) => {{
-        branch!($op, $cond, $tgt, link($link), likely(true));
+        branch!($op, $cond, $tgt, link($link), likely(false));
     }};
     ($op:ident, $cond:expr, $tgt:expr,likely($lkl:expr)) => {{
         branch!($op, $cond, $tgt, link(false), likely($lkl));
     }};
     ($op:ident, $cond:expr, $tgt:expr,link($link:expr),likely($lkl:expr)) => {{
+        // Operands are read before the link register is written (eg: BLTZAL
+        // on RA). The link is written even if the branch is not taken.
+        let (cond, tgt) = ($cond, $tgt);
         if $link {
             $op.ctx.regs[31] = $op.ctx.pc + 4;
         }
-        let (cond, tgt) = ($cond, $tgt);
         $op.ctx.branch(cond, tgt, $lkl);
 
         // See if this is a short loop (less than 5 instructions). Short loops
@@ -237,9 +267,32 @@ macro_rules! check_overflow_sub {
     }};
 }
 
+// Conditional trap (TEQ/TNE/TGE/TLT and immediate variants).
+macro_rules! trap {
+    ($op:ident, $cond:expr) => {{
+        if $cond {
+            $op.cpu.exception(Exception::Trap)
+        }
+    }};
+}
+
+// Translate the effective address of a load/store. If the translation
+// triggers an exception, the instruction is aborted.
+macro_rules! ea {
+    ($op:ident, $write:expr) => {
+        match $op.cpu.translate($op.ea(), $write) {
+            Some(addr) => addr,
+            None => return Ok(()),
+        }
+    };
+}
+
 macro_rules! if_cop {
     ($op:ident, $cop:ident, $do:expr) => {{
         if !$op.cpu.$cop.is_null_obj() {
+            // Bodies that go through the CPU (eg: memory accesses, that are
+            // translated by the TLB) reach the coprocessor via $op.cpu.
+            #[allow(unused_variables)]
             let $cop = &mut $op.cpu.$cop;
             $do
         } else {
@@ -277,11 +330,30 @@ impl<C: Config> Cpu<C> {
             logger: logger,
             until: 0,
             last_busy_check: 0,
+            trace: None,
+            break_resumed: None,
         };
         cpu.exception(Exception::ColdReset); // Trigger a reset exception at startup
         cpu
     }
 
+    /// Start writing an execution trace of the instructions that match the
+    /// specified filter.
+    pub fn start_trace(&mut self, path: &Path, filter: TraceFilter) -> std::io::Result<()> {
+        self.trace = Some(CpuTrace::create(path, filter)?);
+        Ok(())
+    }
+
+    pub fn stop_trace(&mut self) {
+        self.trace = None;
+    }
+
+    /// Disassemble a single instruction (including coprocessor instructions),
+    /// resolving branch targets relative to the specified PC.
+    pub fn disasm(&self, opcode: u32, pc: u64) -> DecodedInsn {
+        decode(self, opcode, pc)
+    }
+
     pub fn ctx(&self) -> &CpuContext {
         &self.ctx
     }
@@ -290,6 +362,12 @@ impl<C: Config> Cpu<C> {
         &mut self.ctx
     }
 
+    /// Address of the instruction being executed (or of the last one, if
+    /// the CPU is not running).
+    pub fn insn_pc(&self) -> u64 {
+        self.ctx.insn_pc
+    }
+
     pub fn reset(&mut self) {
         self.exception(Exception::SoftReset);
     }
@@ -298,8 +376,53 @@ impl<C: Config> Cpu<C> {
         self.cop0.exception(&mut self.ctx, exc);
     }
 
+    // Translate the virtual address of a data access. If the translation
+    // fails, the TLB exception is triggered and None is returned.
+    fn translate(&mut self, vaddr: u32, write: bool) -> Option<u32> {
+        match self.cop0.translate(&self.ctx, vaddr, write) {
+            Ok(paddr) if self.cop0.watch(paddr, write) => {
+                self.exception(Exception::Watch);
+                None
+            }
+            Ok(paddr) => {
+                self.ctx.clock += C::mem_stall(vaddr, write);
+                Some(paddr)
+            }
+            Err(exc) => {
+                self.exception(exc);
+                None
+            }
+        }
+    }
+
+    // Integer overflow in ADD/ADDI/SUB and 64-bit variants. The destination
+    // register is left untouched.
     fn trap_overflow(&mut self) {
-        unimplemented!();
+        self.exception(Exception::Overflow);
+    }
+
+    // Execute an op, and write it to the execution trace if it matches the
+    // trace filter.
+    #[inline(never)]
+    fn op_traced(&mut self, ctx: &mut CpuContext, opcode: u32, t: &Tracer) -> Result<()> {
+        let pc = ctx.insn_pc as u32;
+        if !self.trace.as_ref().unwrap().filter.matches(pc, opcode) {
+            return self.op(ctx, opcode, t);
+        }
+
+        let disasm = self.disasm(opcode, ctx.insn_pc).disasm();
+        let before = *ctx;
+        self.op(ctx, opcode, t)?;
+        let res = self
+            .trace
+            .as_mut()
+            .unwrap()
+            .log(pc, opcode, &disasm, &before, ctx);
+        if let Err(err) = res {
+            error!(self.logger, "cannot write execution trace, tracing stopped"; "err" => %err);
+            self.trace = None;
+        }
+        Ok(())
     }
 
     #[inline(never)]
@@ -321,9 +444,34 @@ impl<C: Config> Cpu<C> {
                 0x06 if h("srll") => *op.mrd64() = (op.rt32() >> (op.rs32() & 0x1F)).sx64(), // SRLV
                 0x07 if h("srav") => *op.mrd64() = (op.irt32() >> (op.rs32() & 0x1F)).sx64(), // SRAV
                 0x08 if h("jr") => branch!(op, true, op.rs64(), link(false)),                 // JR
-                0x09 if h("jalr") => branch!(op, true, op.rs64(), link(true)), // JALR
-                0x0D if h("break") => op.cpu.exception(Exception::Breakpoint), // BREAK
-                0x0F if h("sync") => {}                                        // SYNC
+                0x09 if h("jalr") => {
+                    // JALR: link into RD rather than RA
+                    let tgt = op.rs64();
+                    *op.mrd64() = op.ctx.pc + 4;
+                    branch!(op, true, tgt)
+                }
+                0x0C if h("syscall") => op.cpu.exception(Exception::Syscall), // SYSCALL
+                0x0D if h("break") => {
+                    // BREAK: if the debugger stops here, rewind to the BREAK
+                    // itself without raising the exception, so that the state
+                    // seen while stopped is untouched. The exception is taken
+                    // when execution resumes and the BREAK runs again.
+                    let pc = op.ctx.insn_pc;
+                    if op.cpu.break_resumed != Some(pc) {
+                        let res = t.trace_break(&op.cpu.name, C::pc_mask(pc as u32) as u64);
+                        if res.is_err() {
+                            op.cpu.break_resumed = Some(pc);
+                            op.ctx.next_pc = op.ctx.pc;
+                            op.ctx.pc = pc;
+                            op.ctx.delay_slot = op.ctx.insn_delay_slot;
+                            op.ctx.clock -= 1;
+                            return res;
+                        }
+                    }
+                    op.cpu.break_resumed = None;
+                    op.cpu.exception(Exception::Breakpoint);
+                }
+                0x0F if h("sync") => {} // SYNC
 
                 0x10 if h("mfhi") => *op.mrd64() = op.ctx.hi, // MFHI
                 0x11 if h("mthi") => op.ctx.hi = op.rs64(),   // MTHI
@@ -334,29 +482,45 @@ impl<C: Config> Cpu<C> {
                 0x17 if h("dsrav") => *op.mrd64() = (op.irt64() >> (op.rs32() & 0x3F)) as u64, // DSRAV
                 0x18 if h("mult") => {
                     // MULT
+                    op.ctx.clock += C::op_stall("mult");
                     let (hi, lo) =
                         (i64::wrapping_mul(op.rt32().isx64(), op.rs32().isx64()) as u64).hi_lo();
-                    op.ctx.lo = lo;
-                    op.ctx.hi = hi;
+                    op.ctx.lo = (lo as u32).sx64();
+                    op.ctx.hi = (hi as u32).sx64();
                 }
                 0x19 if h("multu") => {
                     // MULTU
+                    op.ctx.clock += C::op_stall("multu");
                     let (hi, lo) = u64::wrapping_mul(op.rt32() as u64, op.rs32() as u64).hi_lo();
-                    op.ctx.lo = lo;
-                    op.ctx.hi = hi;
+                    op.ctx.lo = (lo as u32).sx64();
+                    op.ctx.hi = (hi as u32).sx64();
                 }
                 0x1A if h("div") => {
                     // DIV
-                    op.ctx.lo = op.irs32().wrapping_div(op.irt32()).sx64();
-                    op.ctx.hi = op.irs32().wrapping_rem(op.irt32()).sx64();
+                    op.ctx.clock += C::op_stall("div");
+                    if op.irt32() != 0 {
+                        op.ctx.lo = op.irs32().wrapping_div(op.irt32()).sx64();
+                        op.ctx.hi = op.irs32().wrapping_rem(op.irt32()).sx64();
+                    } else {
+                        // Division by zero does not trap
+                        op.ctx.lo = if op.irs32() < 0 { 1 } else { u64::max_value() };
+                        op.ctx.hi = op.rs32().sx64();
+                    }
                 }
                 0x1B if h("divu") => {
                     // DIVU
-                    op.ctx.lo = op.rs32().wrapping_div(op.rt32()).sx64();
-                    op.ctx.hi = op.rs32().wrapping_rem(op.rt32()).sx64();
+                    op.ctx.clock += C::op_stall("divu");
+                    if op.rt32() != 0 {
+                        op.ctx.lo = op.rs32().wrapping_div(op.rt32()).sx64();
+                        op.ctx.hi = op.rs32().wrapping_rem(op.rt32()).sx64();
+                    } else {
+                        op.ctx.lo = u64::max_value();
+                        op.ctx.hi = op.rs32().sx64();
+                    }
                 }
                 0x1C if h("dmult") => {
                     // DMULT
+                    op.ctx.clock += C::op_stall("dmult");
                     let (hi, lo) =
                         i128::wrapping_mul(op.irt64() as i128, op.irs64() as i128).hi_lo();
                     op.ctx.lo = lo as u64;
@@ -364,19 +528,32 @@ impl<C: Config> Cpu<C> {
                 }
                 0x1D if h("dmultu") => {
                     // DMULTU
+                    op.ctx.clock += C::op_stall("dmultu");
                     let (hi, lo) = u128::wrapping_mul(op.rt64() as u128, op.rs64() as u128).hi_lo();
                     op.ctx.lo = lo as u64;
                     op.ctx.hi = hi as u64;
                 }
                 0x1E if h("ddiv") => {
                     // DDIV
-                    op.ctx.lo = op.irs64().wrapping_div(op.irt64()) as u64;
-                    op.ctx.hi = op.irs64().wrapping_rem(op.irt64()) as u64;
+                    op.ctx.clock += C::op_stall("ddiv");
+                    if op.irt64() != 0 {
+                        op.ctx.lo = op.irs64().wrapping_div(op.irt64()) as u64;
+                        op.ctx.hi = op.irs64().wrapping_rem(op.irt64()) as u64;
+                    } else {
+                        op.ctx.lo = if op.irs64() < 0 { 1 } else { u64::max_value() };
+                        op.ctx.hi = op.rs64();
+                    }
                 }
                 0x1F if h("ddivu") => {
                     // DDIVU
-                    op.ctx.lo = op.rs64().wrapping_div(op.rt64());
-                    op.ctx.hi = op.rs64().wrapping_rem(op.rt64());
+                    op.ctx.clock += C::op_stall("ddivu");
+                    if op.rt64() != 0 {
+                        op.ctx.lo = op.rs64().wrapping_div(op.rt64());
+                        op.ctx.hi = op.rs64().wrapping_rem(op.rt64());
+                    } else {
+                        op.ctx.lo = u64::max_value();
+                        op.ctx.hi = op.rs64();
+                    }
                 }
 
                 0x20 if h("add") => check_overflow_add!(op, *op.mrd64(), op.irs32(), op.irt32()), // ADD
@@ -387,19 +564,19 @@ impl<C: Config> Cpu<C> {
                 0x25 if h("or") => *op.mrd64() = op.rs64() | op.rt64(),            // OR
                 0x26 if h("xor") => *op.mrd64() = op.rs64() ^ op.rt64(),           // XOR
                 0x27 if h("nor") => *op.mrd64() = !(op.rs64() | op.rt64()),        // NOR
-                0x2A if h("slt") => *op.mrd64() = (op.irs32() < op.irt32()) as u64, // SLT
-                0x2B if h("sltu") => *op.mrd64() = (op.rs32() < op.rt32()) as u64, // SLTU
+                0x2A if h("slt") => *op.mrd64() = (op.irs64() < op.irt64()) as u64, // SLT
+                0x2B if h("sltu") => *op.mrd64() = (op.rs64() < op.rt64()) as u64, // SLTU
                 0x2C if h("dadd") => check_overflow_add!(op, *op.mrd64(), op.irs64(), op.irt64()), // DADD
                 0x2D if h("daddu") => *op.mrd64() = op.rs64() + op.rt64(), // DADDU
                 0x2E if h("dsub") => check_overflow_sub!(op, *op.mrd64(), op.irs64(), op.irt64()), // DSUB
                 0x2F if h("dsubu") => *op.mrd64() = op.rs64() - op.rt64(), // DSUBU
 
-                0x34 if h("teq") => {
-                    // TEQ
-                    if op.rs64() == op.rt64() {
-                        op.cpu.exception(Exception::Trap)
-                    }
-                }
+                0x30 if h("tge") => trap!(op, op.irs64() >= op.irt64()), // TGE
+                0x31 if h("tgeu") => trap!(op, op.rs64() >= op.rt64()),  // TGEU
+                0x32 if h("tlt") => trap!(op, op.irs64() < op.irt64()),  // TLT
+                0x33 if h("tltu") => trap!(op, op.rs64() < op.rt64()),   // TLTU
+                0x34 if h("teq") => trap!(op, op.rs64() == op.rt64()),   // TEQ
+                0x36 if h("tne") => trap!(op, op.rs64() != op.rt64()),   // TNE
 
                 0x38 if h("dsll") => *op.mrd64() = op.rt64() << op.sa(), // DSLL
                 0x3A if h("dsrl") => *op.mrd64() = op.rt64() >> op.sa(), // DSRL
@@ -424,12 +601,18 @@ impl<C: Config> Cpu<C> {
                 0x01 if h("bgez") => {
                     branch!(op, op.irs64() >= 0, op.btgt(), link(false), likely(false))
                 }
-                0x02 if h("btlzl") => {
+                0x02 if h("bltzl") => {
                     branch!(op, op.irs64() < 0, op.btgt(), link(false), likely(true))
                 }
                 0x03 if h("bgezl") => {
                     branch!(op, op.irs64() >= 0, op.btgt(), link(false), likely(true))
                 }
+                0x08 if h("tgei") => trap!(op, op.irs64() >= op.sximm64()), // TGEI
+                0x09 if h("tgeiu") => trap!(op, op.rs64() >= op.sximm64() as u64), // TGEIU
+                0x0A if h("tlti") => trap!(op, op.irs64() < op.sximm64()),  // TLTI
+                0x0B if h("tltiu") => trap!(op, op.rs64() < op.sximm64() as u64), // TLTIU
+                0x0C if h("teqi") => trap!(op, op.irs64() == op.sximm64()), // TEQI
+                0x0E if h("tnei") => trap!(op, op.irs64() != op.sximm64()), // TNEI
                 0x10 if h("bltzal") => {
                     branch!(op, op.irs64() < 0, op.btgt(), link(true), likely(false))
                 }
@@ -457,15 +640,20 @@ impl<C: Config> Cpu<C> {
             0x07 if h("bgtz") => branch!(op, op.irs64() > 0, op.btgt()), // BGTZ
             0x08 if h("addi") => check_overflow_add!(op, *op.mrt64(), op.irs32(), op.sximm32()), // ADDI
             0x09 if h("addiu") => *op.mrt64() = (op.irs32() + op.sximm32()).sx64(), // ADDIU
-            0x0A if h("slti") => *op.mrt64() = (op.irs32() < op.sximm32()) as u64,  // SLTI
-            0x0B if h("sltiu") => *op.mrt64() = (op.rs32() < op.sximm32() as u32) as u64, // SLTIU
+            0x0A if h("slti") => *op.mrt64() = (op.irs64() < op.sximm64()) as u64,  // SLTI
+            0x0B if h("sltiu") => *op.mrt64() = (op.rs64() < op.sximm64() as u64) as u64, // SLTIU
             0x0C if h("andi") => *op.mrt64() = op.rs64() & op.imm64(),              // ANDI
             0x0D if h("ori") => *op.mrt64() = op.rs64() | op.imm64(),               // ORI
             0x0E if h("xori") => *op.mrt64() = op.rs64() ^ op.imm64(),              // XORI
             0x0F if h("lui") => *op.mrt64() = (op.sximm32() << 16).sx64(),          // LUI
 
             0x10 => if_cop!(op, cop0, { return cop0.op(&mut op.ctx, opcode, t) }), // COP0
-            0x11 => if_cop!(op, cop1, { return cop1.op(&mut op.ctx, opcode, t) }), // COP1
+            0x11 => if_cop!(op, cop1, {
+                cop1.op(&mut op.ctx, opcode, t)?;
+                if let Some(exc) = op.ctx.exception.take() {
+                    op.cpu.exception(exc);
+                }
+            }), // COP1
             0x12 => if_cop!(op, cop2, { return cop2.op(&mut op.ctx, opcode, t) }), // COP2
             0x13 => if_cop!(op, cop3, { return cop3.op(&mut op.ctx, opcode, t) }), // COP3
             0x14 if h("beql") => branch!(op, op.rs64() == op.rt64(), op.btgt(), likely(true)), // BEQL
@@ -474,52 +662,114 @@ impl<C: Config> Cpu<C> {
             0x17 if h("bgtzl") => branch!(op, op.irs64() > 0, op.btgt(), likely(true)),  // BGTZL
             0x18 if h("daddi") => check_overflow_add!(op, *op.mrt64(), op.irs64(), op.sximm64()), // DADDI
             0x19 if h("daddiu") => *op.mrt64() = (op.irs64() + op.sximm64()) as u64, // DADDIU
-            0x1a if h("ldl") => *op.mrt64() = op.cpu.lwl::<u64>(op.ea(), op.rt64(), t)?, // LDL
-            0x1b if h("ldr") => *op.mrt64() = op.cpu.lwr::<u64>(op.ea(), op.rt64(), t)?, // LDR
-
-            0x20 if h("lb") => *op.mrt64() = op.cpu.read::<u8>(op.ea(), t)?.sx64(), // LB
-            0x21 if h("lh") => *op.mrt64() = op.cpu.read::<u16>(op.ea(), t)?.sx64(), // LH
-            0x22 if h("lwl") => *op.mrt64() = op.cpu.lwl::<u32>(op.ea(), op.rt32(), t)?.sx64(), // LWL
-            0x23 if h("lw") => *op.mrt64() = op.cpu.read::<u32>(op.ea(), t)?.sx64(), // LW
-            0x24 if h("lbu") => *op.mrt64() = op.cpu.read::<u8>(op.ea(), t)? as u64, // LBU
-            0x25 if h("lhu") => *op.mrt64() = op.cpu.read::<u16>(op.ea(), t)? as u64, // LHU
-            0x26 if h("lwr") => *op.mrt64() = op.cpu.lwr::<u32>(op.ea(), op.rt32(), t)?.sx64(), // LWR
-            0x27 if h("lwu") => *op.mrt64() = op.cpu.read::<u32>(op.ea(), t)? as u64, // LWU
-            0x28 if h("sb") => op.cpu.write::<u8>(op.ea(), op.rt32() as u8, t)?,      // SB
-            0x29 if h("sh") => op.cpu.write::<u16>(op.ea(), op.rt32() as u16, t)?,    // SH
+            0x1a if h("ldl") => *op.mrt64() = op.cpu.lwl::<u64>(ea!(op, false), op.rt64(), t)?, // LDL
+            0x1b if h("ldr") => *op.mrt64() = op.cpu.lwr::<u64>(ea!(op, false), op.rt64(), t)?, // LDR
+
+            0x20 if h("lb") => *op.mrt64() = op.cpu.read::<u8>(ea!(op, false), t)?.sx64(), // LB
+            0x21 if h("lh") => *op.mrt64() = op.cpu.read::<u16>(ea!(op, false), t)?.sx64(), // LH
+            0x22 if h("lwl") => {
+                *op.mrt64() = op.cpu.lwl::<u32>(ea!(op, false), op.rt32(), t)?.sx64()
+            } // LWL
+            0x23 if h("lw") => *op.mrt64() = op.cpu.read::<u32>(ea!(op, false), t)?.sx64(), // LW
+            0x24 if h("lbu") => *op.mrt64() = op.cpu.read::<u8>(ea!(op, false), t)? as u64, // LBU
+            0x25 if h("lhu") => *op.mrt64() = op.cpu.read::<u16>(ea!(op, false), t)? as u64, // LHU
+            0x26 if h("lwr") => {
+                *op.mrt64() = op.cpu.lwr::<u32>(ea!(op, false), op.rt32(), t)?.sx64()
+            } // LWR
+            0x27 if h("lwu") => *op.mrt64() = op.cpu.read::<u32>(ea!(op, false), t)? as u64, // LWU
+            0x28 if h("sb") => op.cpu.write::<u8>(ea!(op, true), op.rt32() as u8, t)?,     // SB
+            0x29 if h("sh") => op.cpu.write::<u16>(ea!(op, true), op.rt32() as u16, t)?,   // SH
             0x2A if h("swl") => {
                 // SWL
-                op.cpu
-                    .write::<u32>(op.ea(), op.cpu.swl(op.ea(), op.rt32(), t)?, t)?
+                let addr = ea!(op, true);
+                let val = op.cpu.swl(addr, op.rt32(), t)?;
+                op.cpu.write::<u32>(addr, val, t)?
             }
-            0x2B if h("sw") => op.cpu.write::<u32>(op.ea(), op.rt32(), t)?, // SW
+            0x2B if h("sw") => op.cpu.write::<u32>(ea!(op, true), op.rt32(), t)?, // SW
             0x2C if h("sdl") => {
                 // SDL
-                op.cpu
-                    .write::<u64>(op.ea(), op.cpu.swl(op.ea(), op.rt64(), t)?, t)?
+                let addr = ea!(op, true);
+                let val = op.cpu.swl(addr, op.rt64(), t)?;
+                op.cpu.write::<u64>(addr, val, t)?
             }
             0x2D if h("sdr") => {
                 // SDR
-                op.cpu
-                    .write::<u64>(op.ea(), op.cpu.swr(op.ea(), op.rt64(), t)?, t)?
+                let addr = ea!(op, true);
+                let val = op.cpu.swr(addr, op.rt64(), t)?;
+                op.cpu.write::<u64>(addr, val, t)?
             }
             0x2E if h("swr") => {
                 // SWR
-                op.cpu
-                    .write::<u32>(op.ea(), op.cpu.swr(op.ea(), op.rt32(), t)?, t)?
+                let addr = ea!(op, true);
+                let val = op.cpu.swr(addr, op.rt32(), t)?;
+                op.cpu.write::<u32>(addr, val, t)?
+            }
+            0x2F => {
+                // CACHE
+                // Caches are not emulated (memory is always coherent), so all
+                // operations are no-ops. Hit operations (and Create Dirty
+                // Exclusive) still go through the TLB, and can fault.
+                if op.rt() >> 2 >= 3 {
+                    let _ = ea!(op, false);
+                }
+            }
+            0x30 if h("ll") => {
+                // LL
+                let addr = ea!(op, false);
+                let val = op.cpu.read::<u32>(addr, t)?;
+                op.ctx.llbit = true;
+                op.ctx.lladdr = C::addr_mask::<u32>(addr) >> 4;
+                *op.mrt64() = val.sx64();
             }
-            0x2F => {} // CACHE
 
-            0x31 if h("lwc1") => if_cop_loadstore!(op, cop1, lwc, t), // LWC1
+            0x31 if h("lwc1") => if_cop!(op, cop1, {
+                // LWC1
+                let (rt, val) = (op.rt(), op.cpu.read::<u32>(ea!(op, false), t)?);
+                op.cpu.cop1.set_reg32(&mut op.ctx, rt, val);
+            }),
             0x32 if h("lwc2") => if_cop_loadstore!(op, cop2, lwc, t), // LWC2
-            0x35 if h("ldc1") => if_cop_loadstore!(op, cop1, ldc, t), // LDC1
+            0x35 if h("ldc1") => if_cop!(op, cop1, {
+                // LDC1
+                let (rt, val) = (op.rt(), op.cpu.read::<u64>(ea!(op, false), t)?);
+                op.cpu.cop1.set_reg(&mut op.ctx, rt, val as u128);
+            }),
             0x36 if h("ldc2") => if_cop_loadstore!(op, cop2, ldc, t), // LDC2
-            0x37 if h("ld") => *op.mrt64() = op.cpu.read::<u64>(op.ea(), t)?, // LD
-            0x39 if h("swc1") => if_cop_loadstore!(op, cop1, swc, t), // SWC1
+            0x34 if h("lld") => {
+                // LLD
+                let addr = ea!(op, false);
+                let val = op.cpu.read::<u64>(addr, t)?;
+                op.ctx.llbit = true;
+                op.ctx.lladdr = C::addr_mask::<u64>(addr) >> 4;
+                *op.mrt64() = val;
+            }
+            0x37 if h("ld") => *op.mrt64() = op.cpu.read::<u64>(ea!(op, false), t)?, // LD
+            0x38 if h("sc") => {
+                // SC
+                if op.ctx.llbit {
+                    op.cpu.write::<u32>(ea!(op, true), op.rt32(), t)?;
+                }
+                *op.mrt64() = op.ctx.llbit as u64;
+            }
+            0x39 if h("swc1") => if_cop!(op, cop1, {
+                // SWC1
+                let val = op.cpu.cop1.reg32(&op.ctx, op.rt());
+                op.cpu.write::<u32>(ea!(op, true), val, t)?
+            }),
             0x3A if h("swc2") => if_cop_loadstore!(op, cop2, swc, t), // SWC2
-            0x3D if h("sdc1") => if_cop_loadstore!(op, cop1, sdc, t), // SDC1
+            0x3C if h("scd") => {
+                // SCD
+                if op.ctx.llbit {
+                    op.cpu.write::<u64>(ea!(op, true), op.rt64(), t)?;
+                }
+                *op.mrt64() = op.ctx.llbit as u64;
+            }
+            0x3D if h("sdc1") => if_cop!(op, cop1, {
+                // SDC1
+                let val = op.cpu.cop1.reg(&op.ctx, op.rt()) as u64;
+                op.cpu.write::<u64>(ea!(op, true), val, t)?
+            }),
             0x3E if h("sdc2") => if_cop_loadstore!(op, cop2, sdc, t), // SDC2
-            0x3F if h("sd") => op.cpu.write::<u64>(op.ea(), op.rt64(), t)?, // SD
+            0x3F if h("sd") => op.cpu.write::<u64>(ea!(op, true), op.rt64(), t)?, // SD
 
             _ => {
                 panic!(
@@ -586,8 +836,13 @@ impl<C: Config> Cpu<C> {
                 let sximm32 = (opcode & 0xffff) as i16 as i32;
                 let rs = ((opcode >> 21) & 0x1f) as usize;
                 let ea = self.ctx.regs[rs] as u32 + sximm32 as u32;
-                let mem = self.bus.fetch_read_nolog::<u32>(C::addr_mask::<u32>(ea));
-                return mem.is_mem();
+                return match self.cop0.translate(&self.ctx, ea, false) {
+                    Ok(addr) => self
+                        .bus
+                        .fetch_read_nolog::<u32>(C::addr_mask::<u32>(addr))
+                        .is_mem(),
+                    Err(_) => false,
+                };
             }
             0x28 | 0x29 | 0x2A | 0x2B | 0x2E => {
                 // Store opcode. Check if the address is raw memory, in which
@@ -595,8 +850,13 @@ impl<C: Config> Cpu<C> {
                 let sximm32 = (opcode & 0xffff) as i16 as i32;
                 let rs = ((opcode >> 21) & 0x1f) as usize;
                 let ea = self.ctx.regs[rs] as u32 + sximm32 as u32;
-                let mem = self.bus.fetch_write_nolog::<u32>(C::addr_mask::<u32>(ea));
-                return mem.is_mem();
+                return match self.cop0.translate(&self.ctx, ea, true) {
+                    Ok(addr) => self
+                        .bus
+                        .fetch_write_nolog::<u32>(C::addr_mask::<u32>(addr))
+                        .is_mem(),
+                    Err(_) => false,
+                };
             }
             // All other opcodes by default are unstable
             _ => return false,
@@ -604,33 +864,75 @@ impl<C: Config> Cpu<C> {
     }
 
     fn detect_busy_wait(&mut self, pc: u64, loop_len: usize) -> bool {
-        let mem = self.fetch(pc);
+        let mem = match self.fetch(pc) {
+            Ok(mem) => mem,
+            Err(_) => return false,
+        };
         let iter = mem.iter().unwrap();
 
-        // FIXME: this is buggy if the memory area is shorter than the loop
+        let mut len = 0;
         for op in iter.take(loop_len) {
             if !self.op_is_stable_in_loop(op) {
                 return false;
             }
+            len += 1;
+        }
+        if len < loop_len {
+            // The loop crosses the end of the memory area
+            return false;
         }
-        self.ctx.clock = self.until;
+
+        // Fast-forward to the end of the current slice, unless COP0 is going
+        // to raise an interrupt before that: in that case, stop there so
+        // that the interrupt is taken on time.
+        self.ctx.clock = match self.cop0.next_event() {
+            Some(clock) if clock > self.ctx.clock => self.until.min(clock),
+            _ => self.until,
+        };
         return true;
     }
 
-    fn fetch(&mut self, addr: u64) -> MemIoR<u32> {
-        self.bus.fetch_read::<u32>(C::pc_mask(addr as u32))
+    fn fetch(&mut self, addr: u64) -> std::result::Result<MemIoR<u32>, Exception> {
+        let paddr = self.cop0.translate(&self.ctx, addr as u32, false)?;
+        Ok(self.bus.fetch_read::<u32>(C::pc_mask(paddr)))
+    }
+
+    // Return the address space size if an access of the specified size
+    // at addr crosses its end and must be wrapped.
+    fn wraps<U: MemInt>(addr: u32) -> Option<u32> {
+        C::addr_wrap().filter(|&size| addr as usize + U::SIZE > size as usize)
     }
 
     fn read<U: MemInt>(&self, addr: u32, t: &Tracer) -> Result<U> {
         let addr = C::addr_mask::<U>(addr);
-        let val = self.bus.read::<U>(addr);
+        let val = match Self::wraps::<U>(addr) {
+            Some(size) => {
+                // Split the access into bytes, wrapping each address
+                let mut val = 0u64;
+                for i in 0..U::SIZE as u32 {
+                    val = (val << 8) | self.bus.read::<u8>((addr + i) % size) as u64;
+                }
+                U::truncate_from(val)
+            }
+            None => self.bus.read::<U>(addr),
+        };
         t.trace_mem_read(&self.name, addr.into(), U::ACCESS_SIZE, val.into())?;
         Ok(val)
     }
 
     fn write<U: MemInt>(&mut self, addr: u32, val: U, t: &Tracer) -> Result<()> {
         let addr = C::addr_mask::<U>(addr);
-        self.bus.write::<U>(addr, val);
+        match Self::wraps::<U>(addr) {
+            Some(size) => {
+                let val64: u64 = val.into();
+                for i in 0..U::SIZE as u32 {
+                    let shift = (U::SIZE as u32 - 1 - i) * 8;
+                    self.bus
+                        .write::<u8>((addr + i) % size, (val64 >> shift) as u8);
+                }
+            }
+            None => self.bus.write::<U>(addr, val),
+        }
         t.trace_mem_write(&self.name, addr.into(), U::ACCESS_SIZE, val.into())
     }
 
@@ -638,8 +940,8 @@ impl<C: Config> Cpu<C> {
         self.until = until;
 
         let ctx = unsafe { self.ctx.as_mut() };
-        let mut mem = self.fetch(ctx.pc);
-        let mut last_mem_pc = ctx.pc;
+        let mut mem = MemIoR::default();
+        let mut last_mem_pc = None;
 
         while ctx.clock < self.until {
             if ctx.lines.halt {
@@ -652,22 +954,44 @@ impl<C: Config> Cpu<C> {
 
             // Fetch the next memory area (unless we're looping, in which case
             // we already have the memory pointer).
-            if ctx.pc != last_mem_pc {
-                mem = self.fetch(ctx.pc);
-                last_mem_pc = ctx.pc;
+            if last_mem_pc != Some(ctx.pc) {
+                match self.fetch(ctx.pc) {
+                    Ok(m) => {
+                        mem = m;
+                        last_mem_pc = Some(ctx.pc);
+                    }
+                    Err(exc) => {
+                        // Fetch exceptions are reported at the insn that
+                        // could not be fetched.
+                        ctx.insn_pc = ctx.pc;
+                        ctx.insn_delay_slot = ctx.delay_slot;
+                        self.exception(exc);
+                        continue;
+                    }
+                }
             }
 
+            // Memory is contiguous only up to the end of the current page
+            // (the next page might be mapped elsewhere).
+            let page_left = (0x1000 - (ctx.pc & 0xFFF)) as usize / 4;
             let mut iter = mem
                 .iter()
-                .unwrap_or_else(|| panic!("jumped to non-linear memory: {}", ctx.pc.hex()));
+                .unwrap_or_else(|| panic!("jumped to non-linear memory: {}", ctx.pc.hex()))
+                .take(page_left);
 
             // Tight loop: go through continuous memory, no branches, no IRQs
             while let Some(op) = iter.next() {
                 ctx.tight_exit = ctx.delay_slot;
+                ctx.insn_pc = ctx.pc;
+                ctx.insn_delay_slot = ctx.delay_slot;
                 ctx.delay_slot = false;
                 ctx.pc = ctx.next_pc;
                 ctx.next_pc += 4;
-                self.op(ctx, op, t)?;
+                if self.trace.is_some() {
+                    self.op_traced(ctx, op, t)?;
+                } else {
+                    self.op(ctx, op, t)?;
+                }
                 t.trace_insn(&self.name, C::pc_mask(ctx.pc as u32) as u64)?;
                 if ctx.clock >= self.until || ctx.tight_exit {
                     break;
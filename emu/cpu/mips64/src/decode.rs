@@ -48,7 +48,8 @@ fn decode1<C: Config>(cpu: &Cpu<C>, opcode: u32, pc: u64) -> DecodedInsn {
             0x06 => DecodedInsn::new3("srlv", OReg(rd), IReg(rt), IReg(rs)),
             0x07 => DecodedInsn::new3("srav", OReg(rd), IReg(rt), IReg(rs)),
             0x08 => DecodedInsn::new1("jr", IReg(rs)),
-            0x09 => DecodedInsn::new1("jalr", IReg(rs)),
+            0x09 => DecodedInsn::new2("jalr", OReg(rd), IReg(rs)),
+            0x0C => DecodedInsn::new0("syscall"),
             0x0D => DecodedInsn::new0("break"),
             0x0F => DecodedInsn::new0("sync"),
 
@@ -83,7 +84,12 @@ fn decode1<C: Config>(cpu: &Cpu<C>, opcode: u32, pc: u64) -> DecodedInsn {
             0x2E => DecodedInsn::new3("dsub", OReg(rd), IReg(rs), IReg(rt)),
             0x2F => DecodedInsn::new3("dsubu", OReg(rd), IReg(rs), IReg(rt)),
 
+            0x30 => DecodedInsn::new2("tge", IReg(rs), IReg(rt)),
+            0x31 => DecodedInsn::new2("tgeu", IReg(rs), IReg(rt)),
+            0x32 => DecodedInsn::new2("tlt", IReg(rs), IReg(rt)),
+            0x33 => DecodedInsn::new2("tltu", IReg(rs), IReg(rt)),
             0x34 => DecodedInsn::new2("teq", IReg(rs), IReg(rt)),
+            0x36 => DecodedInsn::new2("tne", IReg(rs), IReg(rt)),
 
             0x38 => DecodedInsn::new3("dsll", OReg(rd), IReg(rt), Imm8(sa)),
             0x3A => DecodedInsn::new3("dsrl", OReg(rd), IReg(rt), Imm8(sa)),
@@ -99,6 +105,12 @@ fn decode1<C: Config>(cpu: &Cpu<C>, opcode: u32, pc: u64) -> DecodedInsn {
             0x01 => DecodedInsn::new2("bgez", IReg(rs), Target(btgt.into())),
             0x02 => DecodedInsn::new2("bltzl", IReg(rs), Target(btgt.into())),
             0x03 => DecodedInsn::new2("bgezl", IReg(rs), Target(btgt.into())),
+            0x08 => DecodedInsn::new2("tgei", IReg(rs), Imm16(imm16)),
+            0x09 => DecodedInsn::new2("tgeiu", IReg(rs), Imm16(imm16)),
+            0x0A => DecodedInsn::new2("tlti", IReg(rs), Imm16(imm16)),
+            0x0B => DecodedInsn::new2("tltiu", IReg(rs), Imm16(imm16)),
+            0x0C => DecodedInsn::new2("teqi", IReg(rs), Imm16(imm16)),
+            0x0E => DecodedInsn::new2("tnei", IReg(rs), Imm16(imm16)),
             0x10 => DecodedInsn::new2("bltzal", IReg(rs), Target(btgt.into())),
             0x11 => DecodedInsn::new2("bgezal", IReg(rs), Target(btgt.into())),
             0x12 => DecodedInsn::new2("bltzall", IReg(rs), Target(btgt.into())),
@@ -129,8 +141,8 @@ fn decode1<C: Config>(cpu: &Cpu<C>, opcode: u32, pc: u64) -> DecodedInsn {
         0x15 => DecodedInsn::new3("bnel", IReg(rs), IReg(rt), Target(btgt.into())),
         0x16 => DecodedInsn::new2("blezl", IReg(rs), Target(btgt.into())),
         0x17 => DecodedInsn::new2("bgtzl", IReg(rs), Target(btgt.into())),
-        0x18 => DecodedInsn::new3("daddi", OReg(rt), IReg(rs), Target(btgt.into())),
-        0x19 => DecodedInsn::new3("daddiu", OReg(rt), IReg(rs), Target(btgt.into())),
+        0x18 => DecodedInsn::new3("daddi", OReg(rt), IReg(rs), Imm16(imm16)),
+        0x19 => DecodedInsn::new3("daddiu", OReg(rt), IReg(rs), Imm16(imm16)),
         0x1A => DecodedInsn::new3("ldl", OReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
         0x1B => DecodedInsn::new3("ldr", OReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
 
@@ -149,15 +161,20 @@ fn decode1<C: Config>(cpu: &Cpu<C>, opcode: u32, pc: u64) -> DecodedInsn {
         0x2C => DecodedInsn::new3("sdl", IReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
         0x2D => DecodedInsn::new3("sdr", IReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
         0x2E => DecodedInsn::new3("swr", IReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
-        0x2F => DecodedInsn::new0("cache"),
+        0x2F => DecodedInsn::new3("cache", Imm8(vrt as u8), Imm32(sximm32), IReg(rs))
+            .with_fmt(MEMOP_FMT),
+        0x30 => DecodedInsn::new3("ll", OReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
 
         0x31 => decode_cop!(cpu, opcode, pc, cop1, "lwc1?"),
         0x32 => decode_cop!(cpu, opcode, pc, cop2, "lwc2?"),
         0x35 => decode_cop!(cpu, opcode, pc, cop1, "ldc1?"),
         0x36 => decode_cop!(cpu, opcode, pc, cop2, "ldc2?"),
+        0x34 => DecodedInsn::new3("lld", OReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
         0x37 => DecodedInsn::new3("ld", OReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
+        0x38 => DecodedInsn::new3("sc", IReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
         0x39 => decode_cop!(cpu, opcode, pc, cop1, "swc1?"),
         0x3A => decode_cop!(cpu, opcode, pc, cop2, "swc2?"),
+        0x3C => DecodedInsn::new3("scd", IReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
         0x3D => decode_cop!(cpu, opcode, pc, cop1, "sdc1?"),
         0x3E => decode_cop!(cpu, opcode, pc, cop2, "sdc2?"),
         0x3F => DecodedInsn::new3("sd", IReg(rt), Imm32(sximm32), IReg(rs)).with_fmt(MEMOP_FMT),
@@ -170,6 +187,7 @@ fn humanize(insn: DecodedInsn) -> DecodedInsn {
     use self::Operand::*;
 
     let zr = REG_NAMES[0].into();
+    let ra = REG_NAMES[31].into();
     let op0 = insn.args[0];
     let op1 = insn.args[1];
     let op2 = insn.args[2];
@@ -177,6 +195,8 @@ fn humanize(insn: DecodedInsn) -> DecodedInsn {
     match insn.op {
         "sll" if op0 == OReg(zr) && op1 == IReg(zr) => DecodedInsn::new0("nop"),
         "addi" | "addiu" | "ori" | "xori" if op1 == IReg(zr) => DecodedInsn::new2("li", op0, op2),
+        "daddi" | "daddiu" if op1 == IReg(zr) => DecodedInsn::new2("li", op0, op2),
+        "jalr" if op0 == OReg(ra) => DecodedInsn::new1("jalr", op1),
         "bne" if op1 == IReg(zr) => DecodedInsn::new2("bnez", op0, op2),
         "beq" if op0 == IReg(zr) && op1 == IReg(zr) => DecodedInsn::new1("j", op2), // relocatable encoding
         "beq" if op1 == IReg(zr) => DecodedInsn::new2("beqz", op0, op2),
@@ -1,5 +1,5 @@
 use super::decode::{MEMOP_FMT, REG_NAMES};
-use super::{Cop, CpuContext};
+use super::{Cop, CpuContext, Exception};
 
 use emu::dbg::{DebuggerRenderer, DecodedInsn, Operand, RegisterSize, RegisterView, Result, Tracer};
 use emu::int::Numerics;
@@ -10,6 +10,7 @@ use serde_derive::{Deserialize, Serialize};
 use slog;
 use slog::*;
 use std::marker::PhantomData;
+use std::num::FpCategory;
 
 const FPU_REG_NAMES: [&'static str; 32] = [
     "f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8", "f9", "f10", "f11", "f12", "f13", "f14",
@@ -23,38 +24,98 @@ const FPU_CREG_NAMES: [&'static str; 32] = [
     "?25?", "?26?", "?27?", "?28?", "?29?", "?30?", "FCSR",
 ];
 
+// Implementation/revision register (FCR0) of the VR4300 FPU.
+const FCR0_REVISION: u64 = 0x0A00;
+
+// IEEE exceptions, in the bit order used by the FCSR flag (bits 2-6),
+// enable (bits 7-11) and cause (bits 12-17) fields. Unimplemented operation
+// only exists as a cause, and cannot be disabled.
+const FPE_INEXACT: u64 = 1 << 0;
+const FPE_UNDERFLOW: u64 = 1 << 1;
+const FPE_OVERFLOW: u64 = 1 << 2;
+const FPE_DIVZERO: u64 = 1 << 3;
+const FPE_INVALID: u64 = 1 << 4;
+const FPE_UNIMPLEMENTED: u64 = 1 << 5;
+
+// FCSR Flush-to-zero bit: denormal results are flushed to zero instead of
+// raising an unimplemented operation exception.
+const FCSR_FS: u64 = 1 << 24;
+
 #[derive(Default, Copy, Clone, Serialize, Deserialize)]
 struct FpuContext {
     regs: [u64; 32],
     _fir: u64,
-    fccr: u64,
+    // Condition codes of save states made before they moved into FCSR. They
+    // are folded into FCSR at the first COP1 instruction after loading.
+    #[serde(default)]
+    fccr: Option<u64>,
     _fexr: u64,
     _fenr: u64,
     fcsr: u64,
     fpu64: bool,
 }
 
+// The register file is made of 32 64-bit registers. With Status.FR=0, only
+// the 16 even registers are accessible as 64-bit, and odd registers alias
+// the high 32 bits of the even ones.
 impl FpuContext {
-    fn get_fgr(&self, idx: usize) -> u64 {
-        if self.fpu64 {
+    fn fgr(&self, fr: bool, idx: usize) -> u64 {
+        if fr {
             self.regs[idx]
         } else {
-            (self.regs[idx + 0] & 0xFFFF_FFFF) | (self.regs[idx + 1] << 32)
+            self.regs[idx & !1]
         }
     }
-    fn set_fgr(&mut self, idx: usize, val: u64) {
-        if self.fpu64 {
+    fn set_fgr_fr(&mut self, fr: bool, idx: usize, val: u64) {
+        if fr {
             self.regs[idx] = val;
         } else {
-            self.regs[idx + 0] = val & 0xFFFF_FFFF;
-            self.regs[idx + 1] = val >> 32;
+            self.regs[idx & !1] = val;
+        }
+    }
+    // Position (register and shift) of the 32-bit view of a register
+    fn fgr32_pos(fr: bool, idx: usize) -> (usize, usize) {
+        if fr {
+            (idx, 0)
+        } else {
+            (idx & !1, (idx & 1) * 32)
         }
     }
+    fn fgr32(&self, fr: bool, idx: usize) -> u32 {
+        let (idx, shift) = FpuContext::fgr32_pos(fr, idx);
+        (self.regs[idx] >> shift) as u32
+    }
+    fn set_fgr32_fr(&mut self, fr: bool, idx: usize, val: u32) {
+        let (idx, shift) = FpuContext::fgr32_pos(fr, idx);
+        let reg = &mut self.regs[idx];
+        *reg = (*reg & !(0xFFFF_FFFF << shift)) | ((val as u64) << shift);
+    }
+
+    fn get_fgr(&self, idx: usize) -> u64 {
+        self.fgr(self.fpu64, idx)
+    }
+    fn set_fgr(&mut self, idx: usize, val: u64) {
+        self.set_fgr_fr(self.fpu64, idx, val)
+    }
+    fn get_fgr32(&self, idx: usize) -> u32 {
+        self.fgr32(self.fpu64, idx)
+    }
+    fn set_fgr32(&mut self, idx: usize, val: u32) {
+        self.set_fgr32_fr(self.fpu64, idx, val)
+    }
     fn get_fpr<F: FloatRawConvert>(&self, idx: usize) -> F {
-        F::from_u64bits(self.get_fgr(idx))
+        if std::mem::size_of::<F>() == 4 {
+            F::from_u64bits(self.get_fgr32(idx) as u64)
+        } else {
+            F::from_u64bits(self.get_fgr(idx))
+        }
     }
     fn set_fpr<F: FloatRawConvert>(&mut self, idx: usize, val: F) {
-        self.set_fgr(idx, val.to_u64bits());
+        if std::mem::size_of::<F>() == 4 {
+            self.set_fgr32(idx, val.to_u64bits() as u32);
+        } else {
+            self.set_fgr(idx, val.to_u64bits());
+        }
     }
 }
 
@@ -70,7 +131,6 @@ trait FloatRawConvert {
     fn bankers_round(self) -> Self;
     fn to_f32(self) -> f32;
     fn to_f64(self) -> f64;
-    fn to_u64(self) -> u64;
 }
 
 impl FloatRawConvert for f32 {
@@ -94,9 +154,6 @@ impl FloatRawConvert for f32 {
     fn to_f64(self) -> f64 {
         self as f64
     }
-    fn to_u64(self) -> u64 {
-        self as u64
-    }
 }
 
 impl FloatRawConvert for f64 {
@@ -120,9 +177,28 @@ impl FloatRawConvert for f64 {
     fn to_f64(self) -> f64 {
         self as f64
     }
-    fn to_u64(self) -> u64 {
-        self as u64
+}
+
+// Apply the FCSR rounding mode `rm` to a result that was rounded to nearest,
+// by moving it one ulp toward zero, +inf or -inf when it lies on the wrong
+// side of the exact result. `err` is the rounding error (exact result minus
+// `v`): only its sign matters.
+fn round_directed<F: Float + FloatRawConvert>(rm: u64, v: F, err: f64) -> F {
+    if rm == 0 || err == 0.0 || err.is_nan() || v == F::zero() || v.is_nan() {
+        return v;
     }
+    let up = err > 0.0;
+    let neg = v.is_sign_negative();
+    let adjust = match rm {
+        1 => up == neg,
+        2 => up,
+        _ => !up,
+    };
+    if !adjust {
+        return v;
+    }
+    let bits = v.to_u64bits();
+    F::from_u64bits(if up != neg { bits + 1 } else { bits - 1 })
 }
 
 struct Fop<'a, F: Float + FloatRawConvert> {
@@ -167,13 +243,101 @@ impl<'a, F: Float + FloatRawConvert> Fop<'a, F> {
     fn set_fgd(&mut self, v: u64) {
         self.ctx.set_fgr(self.rd(), v);
     }
+    fn set_fgd32(&mut self, v: u32) {
+        self.ctx.set_fgr32(self.rd(), v);
+    }
+
+    // Write the result of an arithmetic operation, after rounding it as
+    // selected by FCSR and checking operands and result for IEEE exceptions.
+    // `res` is the result rounded to nearest, and `err` its rounding error.
+    // The result is discarded if a floating point exception is triggered.
+    fn set_fd_checked(&mut self, inputs: &[F], mut res: F, err: F) {
+        let mut cause = 0;
+        for &v in inputs {
+            if v.is_nan() {
+                cause |= FPE_INVALID;
+            } else if v.classify() == FpCategory::Subnormal {
+                // The VR4300 doesn't handle denormals in hardware
+                cause |= FPE_UNIMPLEMENTED;
+            }
+        }
+        if cause == 0 {
+            if res.is_nan() {
+                cause |= FPE_INVALID;
+            } else if res.is_infinite() {
+                if inputs.iter().all(|v| v.is_finite()) {
+                    // The exact result is finite, so the directed rounding
+                    // modes might round it to the largest finite value.
+                    res = round_directed(self.ctx.fcsr & 3, res, -res.to_f64());
+                    cause |= FPE_OVERFLOW | FPE_INEXACT;
+                }
+            } else if res.classify() == FpCategory::Subnormal {
+                if self.ctx.fcsr & FCSR_FS != 0 {
+                    res = if res.is_sign_negative() {
+                        F::neg_zero()
+                    } else {
+                        F::zero()
+                    };
+                    cause |= FPE_UNDERFLOW | FPE_INEXACT;
+                } else {
+                    cause |= FPE_UNIMPLEMENTED;
+                }
+            } else if err != F::zero() {
+                res = round_directed(self.ctx.fcsr & 3, res, err.to_f64());
+                cause |= if res.is_infinite() {
+                    FPE_OVERFLOW | FPE_INEXACT
+                } else {
+                    FPE_INEXACT
+                };
+            }
+        }
+        if self.fpu.set_cause(self.cpu, cause) {
+            self.set_fd(res);
+        }
+    }
+
+    // Round to an integral value, as selected by the rounding mode in FCSR.
+    fn round_fcsr(&self, v: F) -> F {
+        match self.ctx.fcsr & 3 {
+            0 => v.bankers_round(),
+            1 => v.trunc(),
+            2 => v.ceil(),
+            _ => v.floor(),
+        }
+    }
 }
 
+// Conversions to integer raise an unimplemented operation exception if the
+// value doesn't fit the destination format (or is a NaN).
 macro_rules! approx {
-    ($op:ident, $t:expr, $round:ident, $size:ident, $fallback:expr) => {{
-        match $op.fs().$round().$size() {
-            Some(v) => $op.set_fgd(v as u64),
-            None => $op.set_fgd($fallback as u64),
+    ($op:ident, $v:expr, i64) => {{
+        let fs = $op.fs();
+        let v = $v;
+        match v.to_i64() {
+            Some(iv) => {
+                let cause = if v != fs { FPE_INEXACT } else { 0 };
+                if $op.fpu.set_cause($op.cpu, cause) {
+                    $op.set_fgd(iv as u64);
+                }
+            }
+            None => {
+                $op.fpu.set_cause($op.cpu, FPE_UNIMPLEMENTED);
+            }
+        }
+    }};
+    ($op:ident, $v:expr, i32) => {{
+        let fs = $op.fs();
+        let v = $v;
+        match v.to_i32() {
+            Some(iv) => {
+                let cause = if v != fs { FPE_INEXACT } else { 0 };
+                if $op.fpu.set_cause($op.cpu, cause) {
+                    $op.set_fgd32(iv as u32);
+                }
+            }
+            None => {
+                $op.fpu.set_cause($op.cpu, FPE_UNIMPLEMENTED);
+            }
         }
     }};
 }
@@ -186,7 +350,10 @@ macro_rules! cond {
         let less = if !nan { fs < ft } else { false };
         let equal = if !nan { fs == ft } else { false };
         if nan && $func & 8 != 0 {
-            panic!("signal FPU NaN in comparison");
+            // Signaling compares report unordered operands as invalid
+            if !$op.fpu.set_cause($op.cpu, FPE_INVALID) {
+                return Ok(());
+            }
         }
 
         let cond =
@@ -196,6 +363,20 @@ macro_rules! cond {
     }};
 }
 
+// Additional cycles taken by the slower FPU operations, on top of the single
+// cycle accounted to each instruction (VR4300 latencies).
+fn fop_stall(func: u32, double: bool) -> i64 {
+    match (func, double) {
+        (0x00, _) | (0x01, _) => 2,                    // ADD, SUB
+        (0x02, false) => 4,                            // MUL.S
+        (0x02, true) => 7,                             // MUL.D
+        (0x03, false) | (0x04, false) => 28,           // DIV.S, SQRT.S
+        (0x03, true) | (0x04, true) => 57,             // DIV.D, SQRT.D
+        (0x08..=0x0F, _) | (0x24, _) | (0x25, _) => 4, // Conversions to integer
+        _ => 0,
+    }
+}
+
 macro_rules! fp_suffix {
     ($name:expr, $op:ident) => {
         match $op {
@@ -217,23 +398,46 @@ impl Fpu {
         }
     }
 
-    fn set_cc(&mut self, cc: usize, val: bool) {
-        if cc > 8 {
-            panic!("invalid cc code");
-        }
-        self.ctx.fccr = (self.ctx.fccr & !(1 << cc)) | ((val as u64) << cc);
-        let mut cc2 = cc + 23;
-        if cc > 0 {
-            cc2 += 1;
+    // Condition codes live in FCSR (bit 23 for cc0, bits 25-31 for cc1-7),
+    // so that they are also visible to CFC1/CTC1.
+    fn cc_bit(cc: usize) -> usize {
+        if cc == 0 {
+            23
+        } else {
+            24 + cc
         }
-        self.ctx.fcsr = (self.ctx.fcsr & !(1 << cc2)) | ((val as u64) << cc2);
+    }
+
+    fn set_cc(&mut self, cc: usize, val: bool) {
+        let bit = Fpu::cc_bit(cc);
+        self.ctx.fcsr = (self.ctx.fcsr & !(1 << bit)) | ((val as u64) << bit);
     }
 
     fn get_cc(&mut self, cc: usize) -> bool {
-        if cc > 8 {
-            panic!("invalid cc code");
+        (self.ctx.fcsr & (1 << Fpu::cc_bit(cc))) != 0
+    }
+
+    fn fold_fccr(&mut self) {
+        if let Some(fccr) = self.ctx.fccr.take() {
+            for cc in 0..8 {
+                self.set_cc(cc, fccr & (1 << cc) != 0);
+            }
         }
-        (self.ctx.fccr & (1 << cc)) != 0
+    }
+
+    // Record the IEEE exceptions raised by an operation in the FCSR cause
+    // bits. If any of them is enabled, a floating point exception is
+    // triggered and false is returned (the result must not be written);
+    // otherwise, they are accumulated in the flag bits.
+    fn set_cause(&mut self, cpu: &mut CpuContext, cause: u64) -> bool {
+        self.ctx.fcsr = (self.ctx.fcsr & !(0x3F << 12)) | (cause << 12);
+        let enables = ((self.ctx.fcsr >> 7) & 0x1F) | FPE_UNIMPLEMENTED;
+        if cause & enables != 0 {
+            cpu.raise_exception(Exception::FloatingPoint);
+            return false;
+        }
+        self.ctx.fcsr |= (cause & 0x1F) << 2;
+        true
     }
 
     fn fop<M: Float + FloatRawConvert>(
@@ -250,30 +454,50 @@ impl Fpu {
             phantom: PhantomData,
         };
         match op.func() {
-            0x00 => {
-                // ADD.fmt
-                let v = op.fs() + op.ft();
-                op.set_fd(v)
-            }
-            0x01 => {
-                // SUB.fmt
-                let v = op.fs() - op.ft();
-                op.set_fd(v)
+            0x00 | 0x01 => {
+                // ADD.fmt / SUB.fmt
+                let (fs, ft) = (op.fs(), op.ft());
+                let ft = if op.func() == 0x01 { -ft } else { ft };
+                let mut v = fs + ft;
+                // Rounding error of the sum (2Sum algorithm)
+                let vt = v - fs;
+                let err = (fs - (v - vt)) + (ft - vt);
+                // An exact zero sum is -0 when rounding toward -inf, unless
+                // both operands are +0.
+                if v == F::zero() && op.ctx.fcsr & 3 == 3 {
+                    if fs.is_sign_negative() || ft.is_sign_negative() {
+                        v = F::neg_zero();
+                    }
+                }
+                op.set_fd_checked(&[fs, ft], v, err)
             }
             0x02 => {
-                // MUL.with_fmtt
-                let v = op.fs() * op.ft();
-                op.set_fd(v)
+                // MUL.fmt
+                let (fs, ft) = (op.fs(), op.ft());
+                let v = fs * ft;
+                op.set_fd_checked(&[fs, ft], v, fs.mul_add(ft, -v))
             }
             0x03 => {
                 // DIV.fmt
-                let v = op.fs() / op.ft();
-                op.set_fd(v)
+                let (fs, ft) = (op.fs(), op.ft());
+                if ft == F::zero() && fs.is_finite() && fs != F::zero() {
+                    if op.fpu.set_cause(op.cpu, FPE_DIVZERO) {
+                        op.set_fd(fs / ft);
+                    }
+                } else {
+                    // The sign of the error is the sign of the remainder,
+                    // divided by the divisor.
+                    let v = fs / ft;
+                    let rem = -v.mul_add(ft, -fs);
+                    let err = if ft.is_sign_negative() { -rem } else { rem };
+                    op.set_fd_checked(&[fs, ft], v, err)
+                }
             }
             0x04 => {
                 // SQRT.fmt
-                let v = op.fs().sqrt();
-                op.set_fd(v)
+                let fs = op.fs();
+                let v = fs.sqrt();
+                op.set_fd_checked(&[fs], v, -v.mul_add(v, -fs))
             }
             0x05 => {
                 // ABS.fmt
@@ -290,19 +514,24 @@ impl Fpu {
                 let v = op.fs().neg();
                 op.set_fd(v)
             }
-            0x08 => approx!(op, t, bankers_round, to_i64, i64::max_value()), // ROUND.L.fmt
-            0x09 => approx!(op, t, trunc, to_i64, i64::max_value()),         // TRUNC.L.fmt
-            0x0A => approx!(op, t, ceil, to_i64, i64::max_value()),          // CEIL.L.fmt
-            0x0B => approx!(op, t, floor, to_i64, i64::max_value()),         // FLOOR.L.fmt
-            0x0C => approx!(op, t, bankers_round, to_i32, i32::max_value()), // ROUND.W.fmt
-            0x0D => approx!(op, t, trunc, to_i32, i32::max_value()),         // TRUNC.W.fmt
-            0x0E => approx!(op, t, ceil, to_i32, i32::max_value()),          // CEIL.W.fmt
-            0x0F => approx!(op, t, floor, to_i32, i32::max_value()),         // FLOOR.W.fmt
-
-            0x20 => op.set_fgd(op.fs().to_f32().to_u64bits()), // CVT.S.fmt
+            0x08 => approx!(op, op.fs().bankers_round(), i64), // ROUND.L.fmt
+            0x09 => approx!(op, op.fs().trunc(), i64),         // TRUNC.L.fmt
+            0x0A => approx!(op, op.fs().ceil(), i64),          // CEIL.L.fmt
+            0x0B => approx!(op, op.fs().floor(), i64),         // FLOOR.L.fmt
+            0x0C => approx!(op, op.fs().bankers_round(), i32), // ROUND.W.fmt
+            0x0D => approx!(op, op.fs().trunc(), i32),         // TRUNC.W.fmt
+            0x0E => approx!(op, op.fs().ceil(), i32),          // CEIL.W.fmt
+            0x0F => approx!(op, op.fs().floor(), i32),         // FLOOR.W.fmt
+
+            0x20 => {
+                // CVT.S.fmt
+                let fs = op.fs().to_f64();
+                let v = round_directed(op.ctx.fcsr & 3, fs as f32, fs - fs as f32 as f64);
+                op.set_fgd32(v.to_bits())
+            }
             0x21 => op.set_fgd(op.fs().to_f64().to_u64bits()), // CVT.D.fmt
-            0x24 => op.set_fgd(op.fs().to_u64() as u32 as u64), // CVT.W.fmt
-            0x25 => op.set_fgd(op.fs().to_u64()),              // CVT.L.fmt
+            0x24 => approx!(op, op.round_fcsr(op.fs()), i32),  // CVT.W.fmt
+            0x25 => approx!(op, op.round_fcsr(op.fs()), i64),  // CVT.L.fmt
 
             0x30 => cond!(op, 0x30), // C.T.fmt
             0x31 => cond!(op, 0x31), // C.UN.fmt
@@ -311,7 +540,7 @@ impl Fpu {
             0x34 => cond!(op, 0x34), // C.OLT.fmt
             0x35 => cond!(op, 0x35), // C.ULT.fmt
             0x36 => cond!(op, 0x36), // C.OLE.fmt
-            0x37 => cond!(op, 0x37), // C.ULT.fmt
+            0x37 => cond!(op, 0x37), // C.ULE.fmt
             0x38 => cond!(op, 0x38), // C.SF.fmt
             0x39 => cond!(op, 0x39), // C.NGLE.fmt
             0x3A => cond!(op, 0x3A), // C.SEQ.fmt
@@ -335,35 +564,53 @@ impl Fpu {
 }
 
 impl Cop for Fpu {
-    fn reg(&self, _cpu: &CpuContext, idx: usize) -> u128 {
-        self.ctx.regs[idx] as u128
+    fn reg(&self, cpu: &CpuContext, idx: usize) -> u128 {
+        self.ctx.fgr(cpu.fpu64, idx) as u128
+    }
+    fn set_reg(&mut self, cpu: &mut CpuContext, idx: usize, val: u128) {
+        self.ctx.set_fgr_fr(cpu.fpu64, idx, val as u64);
+    }
+    fn reg32(&self, cpu: &CpuContext, idx: usize) -> u32 {
+        self.ctx.fgr32(cpu.fpu64, idx)
     }
-    fn set_reg(&mut self, _cpu: &mut CpuContext, idx: usize, val: u128) {
-        self.ctx.regs[idx] = val as u64;
+    fn set_reg32(&mut self, cpu: &mut CpuContext, idx: usize, val: u32) {
+        self.ctx.set_fgr32_fr(cpu.fpu64, idx, val);
     }
 
     fn op(&mut self, cpu: &mut CpuContext, opcode: u32, t: &Tracer) -> Result<()> {
         self.ctx.fpu64 = cpu.fpu64; // copy current fpu64 mode bit (from COP0)
+        self.fold_fccr();
         let func = opcode & 0x3f;
         let fmt = (opcode >> 21) & 0x1F;
         let rt = ((opcode >> 16) & 0x1F) as usize;
         let rs = ((opcode >> 11) & 0x1F) as usize;
         let rd = ((opcode >> 6) & 0x1F) as usize;
         match fmt {
-            0x0 => cpu.regs[rt] = (self.ctx.regs[rs] as u32).sx64(), // MFC1
+            0x0 => cpu.regs[rt] = self.ctx.get_fgr32(rs).sx64(), // MFC1
+            0x1 => cpu.regs[rt] = self.ctx.get_fgr(rs),          // DMFC1
             0x2 => match rs {
                 // CFC1
+                0 => cpu.regs[rt] = FCR0_REVISION,
                 31 => cpu.regs[rt] = self.ctx.fcsr,
                 _ => {
                     error!(self.logger, "CFC1 from unknown register: {:x}", rs);
                     return t.break_here("CFC1 from unknown register");
                 }
             },
-            0x4 => self.ctx.regs[rs] = (cpu.regs[rt] as u32) as u64, // MTC1
-            0x5 => self.ctx.set_fgr(rs, cpu.regs[rt]), // DMTC1
+            0x4 => self.ctx.set_fgr32(rs, cpu.regs[rt] as u32), // MTC1
+            0x5 => self.ctx.set_fgr(rs, cpu.regs[rt]),          // DMTC1
             0x6 => match rs {
                 // CTC1
-                31 => self.ctx.fcsr = cpu.regs[rt],
+                31 => {
+                    // Writing a cause bit whose exception is enabled
+                    // triggers the exception immediately.
+                    self.ctx.fcsr = cpu.regs[rt] & 0xFFFF_FFFF;
+                    let cause = (self.ctx.fcsr >> 12) & 0x3F;
+                    let enables = ((self.ctx.fcsr >> 7) & 0x1F) | FPE_UNIMPLEMENTED;
+                    if cause & enables != 0 {
+                        cpu.raise_exception(Exception::FloatingPoint);
+                    }
+                }
                 _ => {
                     error!(self.logger, "CTC1 to unknown register: {:x}", rs);
                     return t.break_here("CTC1 to unknown register");
@@ -377,18 +624,27 @@ impl Cop for Fpu {
                 let cond = self.get_cc(cc) == tf;
                 cpu.branch(cond, tgt, nd);
             }
-            0x10 => return self.fop::<f32>(cpu, opcode, t),
-            0x11 => return self.fop::<f64>(cpu, opcode, t),
+            0x10 => {
+                cpu.clock += fop_stall(func, false);
+                return self.fop::<f32>(cpu, opcode, t);
+            }
+            0x11 => {
+                cpu.clock += fop_stall(func, true);
+                return self.fop::<f64>(cpu, opcode, t);
+            }
 
             0x14 => match func {
                 0x20 => {
                     // CVT.S.W
-                    let fgs = self.ctx.get_fgr(rs);
-                    self.ctx.set_fpr(rd, fgs as i32 as f32);
+                    let fgs = self.ctx.get_fgr32(rs) as i32;
+                    let v = fgs as f32;
+                    let err = (fgs as i64 - v as i64) as f64;
+                    let v = round_directed(self.ctx.fcsr & 3, v, err);
+                    self.ctx.set_fpr(rd, v);
                 }
                 0x21 => {
                     // CVT.D.W
-                    let fgs = self.ctx.get_fgr(rs);
+                    let fgs = self.ctx.get_fgr32(rs);
                     self.ctx.set_fpr(rd, fgs as i32 as f64);
                 }
                 _ => {
@@ -399,13 +655,19 @@ impl Cop for Fpu {
             0x15 => match func {
                 0x20 => {
                     // CVT.S.L
-                    let fgs = self.ctx.get_fgr(rs);
-                    self.ctx.set_fpr(rd, fgs as i64 as f32);
+                    let fgs = self.ctx.get_fgr(rs) as i64;
+                    let v = fgs as f32;
+                    let err = (fgs as i128 - v as i128) as f64;
+                    let v = round_directed(self.ctx.fcsr & 3, v, err);
+                    self.ctx.set_fpr(rd, v);
                 }
                 0x21 => {
                     // CVT.D.L
-                    let fgs = self.ctx.get_fgr(rs);
-                    self.ctx.set_fpr(rd, fgs as i64 as f64);
+                    let fgs = self.ctx.get_fgr(rs) as i64;
+                    let v = fgs as f64;
+                    let err = (fgs as i128 - v as i128) as f64;
+                    let v = round_directed(self.ctx.fcsr & 3, v, err);
+                    self.ctx.set_fpr(rd, v);
                 }
                 _ => {
                     error!(self.logger, "unimplemented COP1 L: func={:x?}", func);
@@ -605,38 +867,179 @@ impl RegisterView for Fpu {
     {
         use self::RegisterSize::*;
 
-        if self.ctx.fpu64 {
+        // Registers are accessed through the same view used by instructions:
+        // with FR=1 there are 32 independent 64-bit registers, while with
+        // FR=0 odd registers are the high halves of the even ones.
+        let fr = self.ctx.fpu64;
+        if fr {
             for idx in 0..16 {
-                let idx = idx + col * 8;
+                let idx = idx + col * 16;
 
-                let val = self.ctx.regs[idx];
+                let mut val = self.ctx.fgr(fr, idx);
                 let desc = if val >> 32 == 0 {
                     format!("S:{:.5}", f32::from_u64bits(val))
                 } else {
                     format!("D:{:.5}", f64::from_u64bits(val))
                 };
-                visit(
-                    FPU_REG_NAMES[idx],
-                    Reg64(&mut self.ctx.regs[idx]),
-                    Some(&desc),
-                );
+                visit(FPU_REG_NAMES[idx], Reg64(&mut val), Some(&desc));
+                self.ctx.set_fgr_fr(fr, idx, val);
             }
         } else {
             for idx in 0..8 {
                 let idx = idx * 2 + col * 16;
 
-                let val = self.ctx.get_fgr(idx);
+                let val = self.ctx.fgr(fr, idx);
                 let desc = if val >> 32 == 0 {
                     format!("S:{:.5}", f32::from_u64bits(val))
                 } else {
                     format!("D:{:.5}", f64::from_u64bits(val))
                 };
 
-                let mut reg0 = self.ctx.regs[idx + 0] as u32;
-                let mut reg1 = self.ctx.regs[idx + 1] as u32;
+                let mut reg0 = self.ctx.fgr32(fr, idx);
+                let mut reg1 = self.ctx.fgr32(fr, idx + 1);
                 visit(FPU_REG_NAMES[idx + 0], Reg32(&mut reg0), None);
                 visit(FPU_REG_NAMES[idx + 1], Reg32(&mut reg1), Some(&desc));
+                self.ctx.set_fgr32_fr(fr, idx, reg0);
+                self.ctx.set_fgr32_fr(fr, idx + 1, reg1);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FMT_S: u32 = 0x10;
+    const FMT_D: u32 = 0x11;
+    const FMT_L: u32 = 0x15;
+
+    // Run f0 = f2 <func> f4 once per rounding mode (nearest, zero, +inf,
+    // -inf), and return the raw results.
+    fn run_rm(fmt: u32, func: u32, fs: u64, ft: u64) -> [u64; 4] {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut fpu = Fpu::new("test", logger);
+        let mut cpu = CpuContext::default();
+        cpu.fpu64 = true;
+        let mut res = [0; 4];
+        for rm in 0..4 {
+            fpu.ctx.fcsr = rm as u64;
+            fpu.ctx.regs[2] = fs;
+            fpu.ctx.regs[4] = ft;
+            let opcode = 0x4400_0000 | fmt << 21 | 4 << 16 | 2 << 11 | func;
+            fpu.op(&mut cpu, opcode, &Tracer::null()).unwrap();
+            res[rm] = fpu.ctx.regs[0];
+        }
+        res
+    }
+
+    fn s(v: f32) -> u64 {
+        v.to_bits() as u64
+    }
+
+    fn d(v: f64) -> u64 {
+        v.to_bits()
+    }
+
+    #[test]
+    fn fold_fccr() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut fpu = Fpu::new("test", logger);
+        let mut cpu = CpuContext::default();
+        fpu.ctx.fccr = Some(0b1000_0011);
+        fpu.op(&mut cpu, 0x4442_F800, &Tracer::null()).unwrap(); // CFC1 v0, FCSR
+        assert_eq!(cpu.regs[2], (1 << 23) | (1 << 25) | (1 << 31));
+        assert_eq!(fpu.ctx.fccr, None);
+    }
+
+    #[test]
+    fn add_sub_rounding() {
+        let half_ulp = 2f32.powi(-24);
+        assert_eq!(
+            run_rm(FMT_S, 0x00, s(1.0), s(half_ulp)),
+            [0x3F80_0000, 0x3F80_0000, 0x3F80_0001, 0x3F80_0000]
+        );
+        assert_eq!(
+            run_rm(FMT_S, 0x00, s(-1.0), s(-half_ulp)),
+            [0xBF80_0000, 0xBF80_0000, 0xBF80_0000, 0xBF80_0001]
+        );
+        assert_eq!(
+            run_rm(FMT_S, 0x01, s(1.0), s(2f32.powi(-30))),
+            [0x3F80_0000, 0x3F7F_FFFF, 0x3F80_0000, 0x3F7F_FFFF]
+        );
+        assert_eq!(
+            run_rm(FMT_S, 0x01, s(1.0), s(1.0)),
+            [0x0000_0000, 0x0000_0000, 0x0000_0000, 0x8000_0000]
+        );
+    }
+
+    #[test]
+    fn mul_rounding() {
+        let v = 1.0 + 2f64.powi(-52);
+        assert_eq!(
+            run_rm(FMT_D, 0x02, d(v), d(v)),
+            [
+                0x3FF0_0000_0000_0002,
+                0x3FF0_0000_0000_0002,
+                0x3FF0_0000_0000_0003,
+                0x3FF0_0000_0000_0002
+            ]
+        );
+        // Overflows round to the largest finite value, unless rounding away
+        // from zero.
+        assert_eq!(
+            run_rm(FMT_S, 0x02, s(std::f32::MAX), s(2.0)),
+            [0x7F80_0000, 0x7F7F_FFFF, 0x7F80_0000, 0x7F7F_FFFF]
+        );
+        assert_eq!(
+            run_rm(FMT_S, 0x02, s(std::f32::MAX), s(-2.0)),
+            [0xFF80_0000, 0xFF7F_FFFF, 0xFF7F_FFFF, 0xFF80_0000]
+        );
+    }
+
+    #[test]
+    fn div_rounding() {
+        assert_eq!(
+            run_rm(FMT_S, 0x03, s(1.0), s(3.0)),
+            [0x3EAA_AAAB, 0x3EAA_AAAA, 0x3EAA_AAAB, 0x3EAA_AAAA]
+        );
+        assert_eq!(
+            run_rm(FMT_S, 0x03, s(1.0), s(-3.0)),
+            [0xBEAA_AAAB, 0xBEAA_AAAA, 0xBEAA_AAAA, 0xBEAA_AAAB]
+        );
+    }
+
+    #[test]
+    fn sqrt_rounding() {
+        assert_eq!(
+            run_rm(FMT_D, 0x04, d(2.0), 0),
+            [
+                0x3FF6_A09E_667F_3BCD,
+                0x3FF6_A09E_667F_3BCC,
+                0x3FF6_A09E_667F_3BCD,
+                0x3FF6_A09E_667F_3BCC
+            ]
+        );
+    }
+
+    #[test]
+    fn cvt_rounding() {
+        assert_eq!(
+            run_rm(FMT_D, 0x20, d(1.0 / 3.0), 0),
+            [0x3EAA_AAAB, 0x3EAA_AAAA, 0x3EAA_AAAB, 0x3EAA_AAAA]
+        );
+        assert_eq!(
+            run_rm(FMT_L, 0x20, (1 << 24) + 1, 0),
+            [0x4B80_0000, 0x4B80_0000, 0x4B80_0001, 0x4B80_0000]
+        );
+        assert_eq!(
+            run_rm(FMT_L, 0x21, (-(1i64 << 53) - 1) as u64, 0),
+            [
+                0xC340_0000_0000_0000,
+                0xC340_0000_0000_0000,
+                0xC340_0000_0000_0000,
+                0xC340_0000_0000_0001
+            ]
+        );
+    }
+}
@@ -14,6 +14,7 @@ mod arch;
 mod cp0;
 mod cpu;
 mod fpu;
+mod trace;
 mod traits;
 
 pub(crate) mod decode;
@@ -24,4 +25,5 @@ pub use self::cp0::Cp0;
 pub use self::cpu::{Cpu, CpuContext, Exception};
 pub use self::decode::REG_NAMES;
 pub use self::fpu::Fpu;
+pub use self::trace::{InsnClass, TraceFilter};
 pub use self::traits::{Arch, Config, Cop, Cop0, CopNull};
@@ -26,7 +26,6 @@ impl TlbEntry {
     pub fn hi(&self) -> u64 {
         ((self.vpn2 & 0x1800_0000) as u64) << 35
             | ((self.vpn2 & 0x07FF_FFFF) as u64) << 13
-            | (self.global as u64) << 12
             | self.asid as u64
     }
 
@@ -81,6 +80,17 @@ impl fmt::Debug for TlbEntry {
     }
 }
 
+/// Reason of a failed TLB translation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TlbError {
+    /// No entry matches the virtual address
+    Miss,
+    /// The matching page is not valid
+    Invalid,
+    /// Write to a page which is not dirty (write-protected)
+    Modified,
+}
+
 // Memory mapping unit of a MIPS processor
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub struct Mmu([TlbEntry; 32]);
@@ -103,6 +113,30 @@ impl Mmu {
         None
     }
 
+    /// Translates a virtual address into a physical address, through the
+    /// entry matching the virtual page and the ASID.
+    pub fn translate(&self, vaddr: u64, vasid: u8, write: bool) -> Result<u32, TlbError> {
+        let entry = match self.probe(vaddr, vasid) {
+            Some(i) => &self.0[i],
+            None => return Err(TlbError::Miss),
+        };
+
+        // Each entry maps a pair of consecutive pages (even/odd).
+        let offset_mask = (entry.page_mask | 0x1FFF) >> 1;
+        let (pfn, valid, dirty) = if vaddr as u32 & (offset_mask + 1) == 0 {
+            (entry.pfn0(), entry.valid0(), entry.dirty0())
+        } else {
+            (entry.pfn1(), entry.valid1(), entry.dirty1())
+        };
+        if !valid {
+            return Err(TlbError::Invalid);
+        }
+        if write && !dirty {
+            return Err(TlbError::Modified);
+        }
+        Ok((pfn & !offset_mask) | (vaddr as u32 & offset_mask))
+    }
+
     /// Reads a specific TLB index.
     pub fn read(&self, index: usize) -> &TlbEntry {
         &self.0[index]
@@ -120,7 +154,7 @@ impl Mmu {
         let entry = &mut self.0[index];
 
         entry.page_mask = page_mask;
-        entry.vpn2 = calc_vpn2(entry_hi);
+        entry.vpn2 = calc_vpn2(entry_hi) & !(page_mask >> 13);
 
         entry.asid = entry_hi as u8;
         entry.global = entry_lo0.get_bit(0) && entry_lo1.get_bit(0);
@@ -215,6 +249,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mmu_translate() {
+        let mut mmu = Mmu::default();
+
+        mmu.write(
+            0,
+            PAGE_MASK_16_KB,
+            0x0000_0000_0040_0001,
+            0b0000_0000_0000_0000_1000_0000_0000_0110, // valid, dirty
+            0b0000_0000_0000_0000_0100_0000_0000_0010, // valid, not dirty
+        );
+        mmu.write(
+            1,
+            PAGE_MASK_4_KB,
+            0x0000_0000_0080_0001,
+            0b0000_0000_0000_0000_0000_0000_0000_0000, // not valid
+            0b0000_0000_0000_0000_0000_0000_0000_0000, // not valid
+        );
+
+        // even page
+        assert_eq!(mmu.translate(0x0040_1234, 1, false), Ok(0x0020_1234));
+        assert_eq!(mmu.translate(0x0040_1234, 1, true), Ok(0x0020_1234));
+        // odd page
+        assert_eq!(mmu.translate(0x0040_5678, 1, false), Ok(0x0010_1678));
+        assert_eq!(mmu.translate(0x0040_5678, 1, true), Err(TlbError::Modified));
+        // not valid
+        assert_eq!(mmu.translate(0x0080_0000, 1, false), Err(TlbError::Invalid));
+        // non matching asid
+        assert_eq!(mmu.translate(0x0040_1234, 2, false), Err(TlbError::Miss));
+        // unmapped
+        assert_eq!(mmu.translate(0x0100_0000, 1, false), Err(TlbError::Miss));
+    }
+
     #[bench]
     fn bench_tlb_probe_match(b: &mut Bencher) {
         let mut mmu = Mmu::default();
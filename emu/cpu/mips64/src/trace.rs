@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::decode::REG_NAMES;
+use super::CpuContext;
+
+/// Broad classes of instructions, used to filter execution traces.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InsnClass {
+    Alu,
+    Branch,
+    Load,
+    Store,
+    Cop0,
+    Cop1,
+    Cop2,
+}
+
+impl InsnClass {
+    pub fn of(opcode: u32) -> InsnClass {
+        use self::InsnClass::*;
+        match opcode >> 26 {
+            0x00 => match opcode & 0x3F {
+                0x08 | 0x09 => Branch, // JR, JALR
+                _ => Alu,
+            },
+            0x01 => match (opcode >> 16) & 0x1F {
+                0x08..=0x0F => Alu, // Traps
+                _ => Branch,
+            },
+            0x02..=0x07 | 0x14..=0x17 => Branch,
+            0x10 => Cop0,
+            0x11 => Cop1,
+            0x12 => Cop2,
+            0x1A | 0x1B | 0x20..=0x27 | 0x30..=0x37 => Load,
+            0x28..=0x2E | 0x38..=0x3F => Store,
+            _ => Alu,
+        }
+    }
+}
+
+impl FromStr for InsnClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::InsnClass::*;
+        Ok(match s {
+            "alu" => Alu,
+            "branch" => Branch,
+            "load" => Load,
+            "store" => Store,
+            "cop0" => Cop0,
+            "cop1" => Cop1,
+            "cop2" => Cop2,
+            _ => return Err(format!("invalid instruction class: {}", s)),
+        })
+    }
+}
+
+/// Select which instructions are written to an execution trace.
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    /// Only trace instructions within this (inclusive) range of addresses.
+    pub pc_range: Option<(u32, u32)>,
+    /// Only trace instructions of these classes (all of them, if empty).
+    pub classes: Vec<InsnClass>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: u32, opcode: u32) -> bool {
+        if let Some((start, end)) = self.pc_range {
+            if pc < start || pc > end {
+                return false;
+            }
+        }
+        self.classes.is_empty() || self.classes.contains(&InsnClass::of(opcode))
+    }
+}
+
+/// An execution trace being written to a file. Each line contains the
+/// address, the opcode and the disassembly of an instruction, followed by
+/// the registers that it modified, so that traces can be diffed against
+/// those of other emulators.
+pub(crate) struct CpuTrace {
+    out: BufWriter<File>,
+    pub(crate) filter: TraceFilter,
+}
+
+impl CpuTrace {
+    pub(crate) fn create(path: &Path, filter: TraceFilter) -> std::io::Result<CpuTrace> {
+        Ok(CpuTrace {
+            out: BufWriter::new(File::create(path)?),
+            filter,
+        })
+    }
+
+    pub(crate) fn log(
+        &mut self,
+        pc: u32,
+        opcode: u32,
+        disasm: &str,
+        before: &CpuContext,
+        after: &CpuContext,
+    ) -> std::io::Result<()> {
+        write!(self.out, "{:08x}: {:08x}  {:<32}", pc, opcode, disasm)?;
+        for i in 1..32 {
+            if after.regs[i] != before.regs[i] {
+                write!(self.out, " {}={:x}", REG_NAMES[i], after.regs[i])?;
+            }
+        }
+        if after.hi != before.hi {
+            write!(self.out, " hi={:x}", after.hi)?;
+        }
+        if after.lo != before.lo {
+            write!(self.out, " lo={:x}", after.lo)?;
+        }
+        writeln!(self.out)
+    }
+}
@@ -39,6 +39,28 @@ pub trait Config {
     fn addr_mask<U: MemInt>(addr: u32) -> u32 {
         addr & 0x1FFF_FFFF & !(U::SIZE as u32 - 1)
     }
+
+    // Size of the data address space, for architectures where accesses
+    // crossing its end wrap around to its beginning (after addr_mask).
+    fn addr_wrap() -> Option<u32> {
+        None
+    }
+
+    // Additional cycles taken by a multi-cycle instruction (eg: multiplications
+    // and divisions), on top of the single cycle accounted to each instruction.
+    // Like Arch::has_op, this is meant to be called with literals, so that it
+    // folds to a constant.
+    #[inline(always)]
+    fn op_stall(_op: &'static str) -> i64 {
+        0
+    }
+
+    // Additional cycles taken by a data access to the specified virtual
+    // address (eg: uncached accesses that go straight to the bus).
+    #[inline(always)]
+    fn mem_stall(_vaddr: u32, _write: bool) -> i64 {
+        0
+    }
 }
 
 /// Cop is a MIPS64 coprocessor that can be installed within the core.
@@ -46,16 +68,30 @@ pub trait Cop {
     fn reg(&self, cpu: &CpuContext, idx: usize) -> u128;
     fn set_reg(&mut self, cpu: &mut CpuContext, idx: usize, val: u128);
 
+    // Access the 32-bit view of a register (as done by LWC/SWC). By default,
+    // these are the low 32 bits of the register.
+    fn reg32(&self, cpu: &CpuContext, idx: usize) -> u32 {
+        self.reg(cpu, idx) as u32
+    }
+    fn set_reg32(&mut self, cpu: &mut CpuContext, idx: usize, val: u32) {
+        self.set_reg(cpu, idx, val as u128);
+    }
+
     fn op(&mut self, cpu: &mut CpuContext, opcode: u32, t: &Tracer) -> Result<()>;
     fn decode(&self, _opcode: u32, _pc: u64) -> DecodedInsn {
         DecodedInsn::new0("unkcop")
     }
 
+    // Load/store instructions (LWCz, LDCz, SWCz, SDCz). The default
+    // implementations access the bus directly with the address masked to
+    // 29 bits, so they bypass the TLB: they are only meant for cores without
+    // a MMU. Cores with a TLB handle these instructions in the CPU instead
+    // (see COP1 in Cpu::op).
     fn lwc(&mut self, op: u32, ctx: &mut CpuContext, bus: &Bus, _t: &Tracer) -> Result<()> {
         let rt = ((op >> 16) & 0x1f) as usize;
         let ea = ctx.regs[((op >> 21) & 0x1f) as usize] as u32 + (op & 0xffff) as i16 as i32 as u32;
-        let val = bus.read::<u32>(ea & 0x1FFF_FFFC) as u64;
-        self.set_reg(ctx, rt, val as u128);
+        let val = bus.read::<u32>(ea & 0x1FFF_FFFC);
+        self.set_reg32(ctx, rt, val);
         Ok(())
     }
 
@@ -70,7 +106,7 @@ pub trait Cop {
     fn swc(&mut self, op: u32, ctx: &CpuContext, bus: &mut Bus, _t: &Tracer) -> Result<()> {
         let rt = ((op >> 16) & 0x1f) as usize;
         let ea = ctx.regs[((op >> 21) & 0x1f) as usize] as u32 + (op & 0xffff) as i16 as i32 as u32;
-        let val = self.reg(ctx, rt) as u32;
+        let val = self.reg32(ctx, rt);
         bus.write::<u32>(ea & 0x1FFF_FFFC, val);
         Ok(())
     }
@@ -114,6 +150,32 @@ pub trait Cop0: Cop {
 
     /// Trigger the specified excepion.
     fn exception(&mut self, ctx: &mut CpuContext, exc: Exception);
+
+    /// Return the clock at which the next internal interrupt (eg: timer)
+    /// will be raised, if any. Busy-wait loops are never fast-forwarded
+    /// past it.
+    fn next_event(&self) -> Option<i64> {
+        None
+    }
+
+    /// Translate the virtual address of an access into a physical address.
+    /// If the translation fails, the exception to be triggered is returned.
+    /// The default implementation is for cores without a MMU.
+    fn translate(
+        &self,
+        _ctx: &CpuContext,
+        vaddr: u32,
+        _write: bool,
+    ) -> std::result::Result<u32, Exception> {
+        Ok(vaddr)
+    }
+
+    /// Check whether a data access to the specified physical address hits
+    /// the hardware watchpoint, in which case a Watch exception is raised
+    /// and the access is aborted.
+    fn watch(&self, _paddr: u32, _write: bool) -> bool {
+        false
+    }
 }
 
 pub struct CopNull {}
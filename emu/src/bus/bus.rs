@@ -227,6 +227,88 @@ impl Default for BusFill {
     }
 }
 
+/// OpenBus specifies the value returned by reads of addresses where nothing
+/// is mapped, that is the value left floating on the bus when no device
+/// answers the access. See
+/// [`Bus::map_open_bus()`](struct.Bus.html#method.map_open_bus).
+///
+/// OpenBus implements the `Default` trait with all bits set.
+#[derive(Debug, Copy, Clone)]
+pub enum OpenBus {
+    /// `Fixed` returns the specified value, truncated to the access size.
+    Fixed(u64),
+
+    /// `AddressLow` returns the lower 16 bits of the address, replicated
+    /// over the access size. This is what is read back on buses where
+    /// address and data are multiplexed over the same lines, and the last
+    /// address put on the bus is still latched.
+    AddressLow,
+
+    /// `AddressHigh` returns the upper 16 bits of the address, replicated
+    /// over the access size.
+    AddressHigh,
+}
+
+impl Default for OpenBus {
+    fn default() -> Self {
+        OpenBus::Fixed(0xffff_ffff_ffff_ffff)
+    }
+}
+
+impl OpenBus {
+    fn hwio_r(self) -> HwIoR {
+        const REPEAT16: u64 = 0x0001_0001_0001_0001;
+        match self {
+            OpenBus::Fixed(val) => HwIoR::Func(Rc::new(move |_| val)),
+            OpenBus::AddressLow => HwIoR::Func(Rc::new(|addr| (addr & 0xFFFF) as u64 * REPEAT16)),
+            OpenBus::AddressHigh => HwIoR::Func(Rc::new(|addr| (addr >> 16) as u64 * REPEAT16)),
+        }
+    }
+}
+
+struct OpenBusRegion {
+    begin: u32,
+    end: u32,
+    hwio: HwIoR,
+    log: bool,
+}
+
+/// BusWatch specifies which kind of accesses trigger a bus watchpoint.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BusWatch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// An access that hit a bus watchpoint, as passed to its callback.
+#[derive(Debug, Copy, Clone)]
+pub struct BusAccess {
+    pub addr: u32,
+    pub size: AccessSize,
+    pub val: u64,
+    pub write: bool,
+}
+
+struct BusWatchpoint {
+    id: usize,
+    begin: u32,
+    end: u32,
+    kind: BusWatch,
+    cb: Rc<RefCell<FnMut(&BusAccess)>>,
+}
+
+impl BusWatchpoint {
+    fn matches(&self, acc: &BusAccess, size: u32) -> bool {
+        let kind = match self.kind {
+            BusWatch::Read => !acc.write,
+            BusWatch::Write => acc.write,
+            BusWatch::ReadWrite => true,
+        };
+        kind && acc.addr <= self.end && acc.addr.saturating_add(size - 1) >= self.begin
+    }
+}
+
 pub(crate) fn unmapped_area_r() -> HwIoR {
     thread_local!(
         static FN: Rc<Fn(u32)->u64> = Rc::new(|_| {
@@ -258,6 +340,10 @@ pub struct Bus<Order: ByteOrderCombiner> {
     fillers: [ArrayField<u8>; 256],
     unmap_r: HwIoR,
     unmap_w: HwIoW,
+    unmap_log: bool,
+    open_bus: Vec<OpenBusRegion>,
+    watchpoints: Vec<BusWatchpoint>,
+    next_watchpoint: usize,
 
     logger: slog::Logger,
     mems: Vec<MemoryDesc>, // List of mapped memory areas (for debugging)
@@ -289,6 +375,10 @@ where
             fillers: array![|idx| ArrayField::internal_new(&format!("Bus::filler{}", idx), idx as u8, 64, false); 256],
             unmap_r: unmapped_area_r(),
             unmap_w: unmapped_area_w(),
+            unmap_log: true,
+            open_bus: Vec::new(),
+            watchpoints: Vec::new(),
+            next_watchpoint: 0,
             logger: logger,
             mems: Vec::new(),
             phantom: PhantomData,
@@ -296,13 +386,21 @@ where
     }
 
     pub fn read<U: MemInt + 'a>(&self, addr: u32) -> U {
-        self.internal_fetch_read::<U>(addr, true)
-            .read::<Order, U>(addr)
+        let val = self
+            .internal_fetch_read::<U>(addr, true)
+            .read::<Order, U>(addr);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints::<U>(addr, val.into(), false);
+        }
+        val
     }
 
     pub fn write<U: MemInt + 'a>(&mut self, addr: u32, val: U) {
         self.internal_fetch_write::<U>(addr, true)
             .write::<Order, U>(addr, val);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints::<U>(addr, val.into(), true);
+        }
     }
 
     #[inline(never)]
@@ -327,15 +425,17 @@ where
 
     #[inline(always)]
     fn internal_fetch_read<U: MemInt + 'a>(&'b self, addr: u32, unmapped_log: bool) -> &'b HwIoR {
-        self.reads[U::ACCESS_SIZE]
-            .lookup(addr)
-            .or_else(|| {
-                if unmapped_log {
-                    error!(self.logger, "unmapped bus read"; o!("addr" => format!("0x{:x}", addr), "size" => U::SIZE));
-                }
-                Some(&self.unmap_r)
-            })
-            .unwrap()
+        if let Some(hwio) = self.reads[U::ACCESS_SIZE].lookup(addr) {
+            return hwio;
+        }
+        let (hwio, log) = match self.open_bus_region(addr) {
+            Some(region) => (&region.hwio, region.log),
+            None => (&self.unmap_r, self.unmap_log),
+        };
+        if unmapped_log && log {
+            error!(self.logger, "unmapped bus read"; o!("addr" => format!("0x{:x}", addr), "size" => U::SIZE));
+        }
+        hwio
     }
 
     #[inline(always)]
@@ -347,12 +447,155 @@ where
         if let Some(hwio) = self.writes[U::ACCESS_SIZE].lookup_mut(addr) {
             return hwio;
         }
-        if unmapped_log {
+        let log = self
+            .open_bus_region(addr)
+            .map_or(self.unmap_log, |region| region.log);
+        if unmapped_log && log {
             error!(self.logger, "unmapped bus write"; o!("addr" => format!("0x{:x}", addr), "size" => U::SIZE));
         }
         &mut self.unmap_w
     }
 
+    #[inline(never)]
+    fn check_watchpoints<U: MemInt>(&self, addr: u32, val: u64, write: bool) {
+        let acc = BusAccess {
+            addr,
+            size: U::ACCESS_SIZE,
+            val,
+            write,
+        };
+        for wp in self.watchpoints.iter() {
+            if wp.matches(&acc, U::SIZE as u32) {
+                // Accesses made by the callback itself are not reported.
+                if let Ok(mut cb) = wp.cb.try_borrow_mut() {
+                    (&mut *cb)(&acc);
+                }
+            }
+        }
+    }
+
+    /// Add a watchpoint over the **inclusive** address range `begin`/`end`.
+    /// `cb` is called after each access of the specified kind that overlaps
+    /// the range, with a description of the access. Returns an identifier
+    /// that can be passed to
+    /// [`Bus::remove_watchpoint()`](struct.Bus.html#method.remove_watchpoint).
+    ///
+    /// Watchpoints see accesses made through [`Bus::read()`](struct.Bus.html#method.read)
+    /// and [`Bus::write()`](struct.Bus.html#method.write), and writes reported
+    /// with [`Bus::notify_write()`](struct.Bus.html#method.notify_write); not
+    /// those made through handles returned by the `fetch_*` functions.
+    ///
+    /// ```
+    /// use emu::bus::le::{Bus, BusWatch, Mem, MemFlags, BusFill};
+    /// use slog;
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    ///
+    /// fn main() {
+    ///     let logger = slog::Logger::root(slog::Discard, slog::o!());
+    ///     let mut bus = Bus::new(logger);
+    ///     let ram1 = Mem::new("mem", 1024, MemFlags::default(), None);
+    ///     bus.map_mem(0x0400_0000, 0x0400_03FF, &ram1, BusFill::None).unwrap();
+    ///
+    ///     let last = Rc::new(Cell::new(0));
+    ///     let last2 = last.clone();
+    ///     bus.add_watchpoint(0x0400_0100, 0x0400_0103, BusWatch::Write, move |acc| {
+    ///         last2.set(acc.val);
+    ///     });
+    ///
+    ///     bus.write::<u32>(0x0400_0100, 0xaabbccdd);
+    ///     bus.write::<u32>(0x0400_0104, 0x11223344);
+    ///     assert_eq!(last.get(), 0xaabbccdd);
+    /// }
+    /// ```
+    pub fn add_watchpoint<F>(&mut self, begin: u32, end: u32, kind: BusWatch, cb: F) -> usize
+    where
+        F: FnMut(&BusAccess) + 'static,
+    {
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.push(BusWatchpoint {
+            id,
+            begin,
+            end,
+            kind,
+            cb: Rc::new(RefCell::new(cb)),
+        });
+        id
+    }
+
+    /// Report to watchpoints a write to `addr` that did not go through
+    /// [`Bus::write()`](struct.Bus.html#method.write), like a DMA engine
+    /// copying directly into a memory that it owns.
+    pub fn notify_write<U: MemInt + 'a>(&self, addr: u32, val: U) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints::<U>(addr, val.into(), true);
+        }
+    }
+
+    /// Remove a watchpoint previously added with
+    /// [`Bus::add_watchpoint()`](struct.Bus.html#method.add_watchpoint).
+    /// Returns false if the watchpoint did not exist.
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|wp| wp.id != id);
+        self.watchpoints.len() != len
+    }
+
+    fn open_bus_region(&self, addr: u32) -> Option<&OpenBusRegion> {
+        // Later configurations take precedence.
+        self.open_bus
+            .iter()
+            .rev()
+            .find(|r| addr >= r.begin && addr <= r.end)
+    }
+
+    /// Configure the behavior of unmapped accesses within the **inclusive**
+    /// address range `begin`/`end`: reads return the value specified by
+    /// `value`, and accesses are logged as errors only if `log` is true.
+    /// Writes are always ignored. The configuration only affects addresses
+    /// where nothing is mapped, so it can span over mapped devices.
+    ///
+    /// ```
+    /// use emu::bus::le::{Bus, OpenBus};
+    /// use slog;
+    ///
+    /// fn main() {
+    ///     let logger = slog::Logger::root(slog::Discard, slog::o!());
+    ///     let mut bus = Bus::new(logger);
+    ///
+    ///     bus.map_open_bus(0x1000_0000, 0x1FFF_FFFF, OpenBus::AddressLow, false).unwrap();
+    ///     assert_eq!(bus.read::<u32>(0x1234_5678), 0x5678_5678);
+    ///     assert_eq!(bus.read::<u32>(0x2000_0000), 0xffff_ffff);
+    /// }
+    /// ```
+    pub fn map_open_bus(
+        &mut self,
+        begin: u32,
+        end: u32,
+        value: OpenBus,
+        log: bool,
+    ) -> Result<(), &'static str> {
+        if end < begin {
+            return Err("Bus::map_open_bus: invalid arguments: end must be bigger than begin");
+        }
+        self.open_bus.push(OpenBusRegion {
+            begin,
+            end,
+            hwio: value.hwio_r(),
+            log,
+        });
+        Ok(())
+    }
+
+    /// Configure the behavior of unmapped accesses outside of the ranges
+    /// configured with [`Bus::map_open_bus()`](struct.Bus.html#method.map_open_bus).
+    /// By default, reads return all bits set and accesses are logged.
+    pub fn set_open_bus(&mut self, value: OpenBus, log: bool) {
+        self.unmap_r = value.hwio_r();
+        self.unmap_log = log;
+    }
+
     fn mapreg_partial<U: 'static, S>(
         &mut self,
         addr: u32,
@@ -707,6 +950,72 @@ mod tests {
         assert_eq!(bus.read::<u32>(0x0500_1000), 0x6c6c_6c6c);
     }
 
+    #[test]
+    fn open_bus() {
+        let ram1 = Mem::new("mem", 1024, MemFlags::default(), None);
+        let mut bus = Bus::<LittleEndian>::new(logger());
+        bus.map_mem(0x0400_0000, 0x0400_03FF, &ram1, BusFill::None)
+            .unwrap();
+
+        assert_eq!(
+            bus.map_open_bus(0x0500_0000, 0x04FF_FFFF, OpenBus::AddressLow, false)
+                .is_ok(),
+            false
+        );
+        bus.map_open_bus(0x0400_0000, 0x07FF_FFFF, OpenBus::AddressLow, false)
+            .unwrap();
+        bus.map_open_bus(0x0600_0000, 0x06FF_FFFF, OpenBus::AddressHigh, false)
+            .unwrap();
+
+        // Mapped areas are not affected
+        bus.write::<u32>(0x0400_0100, 0xaabbccdd);
+        assert_eq!(bus.read::<u32>(0x0400_0100), 0xaabb_ccdd);
+
+        bus.write::<u32>(0x0500_1234, 0xaabbccdd);
+        assert_eq!(bus.read::<u32>(0x0500_1234), 0x1234_1234);
+        assert_eq!(bus.read::<u16>(0x0400_5678), 0x5678);
+        assert_eq!(bus.read::<u32>(0x0612_3456), 0x0612_0612);
+        assert_eq!(bus.read::<u32>(0x0800_0000), 0xffff_ffff);
+
+        bus.set_open_bus(OpenBus::Fixed(0), false);
+        assert_eq!(bus.read::<u32>(0x0800_0000), 0);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let ram1 = Mem::new("mem", 1024, MemFlags::default(), None);
+        let mut bus = Bus::<LittleEndian>::new(logger());
+        bus.map_mem(0x0400_0000, 0x0400_03FF, &ram1, BusFill::None)
+            .unwrap();
+
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let hits2 = hits.clone();
+        let wp = bus.add_watchpoint(0x0400_0102, 0x0400_0102, BusWatch::ReadWrite, move |acc| {
+            hits2.borrow_mut().push((acc.addr, acc.val, acc.write))
+        });
+
+        // Accesses overlapping the range trigger the watchpoint
+        bus.write::<u32>(0x0400_0100, 0xaabbccdd);
+        bus.write::<u8>(0x0400_0101, 0x11);
+        assert_eq!(bus.read::<u16>(0x0400_0102), 0xaabb);
+        // Writes performed outside of the bus can be reported too
+        bus.notify_write::<u64>(0x0400_0100, 0x1122334455667788);
+        bus.notify_write::<u64>(0x0400_0108, 0);
+        assert_eq!(
+            *hits.borrow(),
+            vec![
+                (0x0400_0100, 0xaabbccdd, true),
+                (0x0400_0102, 0xaabb, false),
+                (0x0400_0100, 0x1122334455667788, true)
+            ]
+        );
+
+        assert_eq!(bus.remove_watchpoint(wp), true);
+        assert_eq!(bus.remove_watchpoint(wp), false);
+        bus.write::<u32>(0x0400_0100, 0);
+        assert_eq!(hits.borrow().len(), 3);
+    }
+
     #[test]
     fn basic_reg() {
         let mut reg1 = Reg32::new_basic("reg1");
@@ -6,14 +6,16 @@ mod mem;
 mod radix;
 mod regs;
 
-pub use self::bus::{Bus, BusFill, MemIoR, MemIoRIterator, MemIoW};
+pub use self::bus::{Bus, BusAccess, BusFill, BusWatch, MemIoR, MemIoRIterator, MemIoW, OpenBus};
 pub use self::device::{CurrentDeviceMap, Device, DeviceMap};
 pub use self::mem::{Mem, MemFlags};
 pub use self::regs::{Reg, RegDeref, RegFlags, RegRef};
 
 pub mod le {
     use super::byteorder::LittleEndian;
-    pub use super::{BusFill, Device, Mem, MemFlags, RegDeref, RegFlags};
+    pub use super::{
+        BusAccess, BusFill, BusWatch, Device, Mem, MemFlags, OpenBus, RegDeref, RegFlags,
+    };
     pub type Bus = super::Bus<LittleEndian>;
     pub type Reg8 = super::Reg<LittleEndian, u8>;
     pub type Reg16 = super::Reg<LittleEndian, u16>;
@@ -26,7 +28,9 @@ pub mod le {
 
 pub mod be {
     use super::byteorder::BigEndian;
-    pub use super::{BusFill, Device, Mem, MemFlags, RegDeref, RegFlags};
+    pub use super::{
+        BusAccess, BusFill, BusWatch, Device, Mem, MemFlags, OpenBus, RegDeref, RegFlags,
+    };
     pub type Bus = super::Bus<BigEndian>;
     pub type Reg8 = super::Reg<BigEndian, u8>;
     pub type Reg16 = super::Reg<BigEndian, u16>;
@@ -94,6 +94,15 @@ impl Tracer<'_> {
         }
     }
 
+    /// Check whether a BREAK instruction executed at the specified PC must
+    /// stop the debugger.
+    pub fn trace_break(&self, cpu_name: &str, pc: u64) -> Result<()> {
+        match self.dbg {
+            Some(dbg) => dbg.trace_break(cpu_name, pc),
+            None => Ok(()),
+        }
+    }
+
     #[inline(always)]
     pub fn trace_mem_write(
         &self,
@@ -250,6 +259,11 @@ struct DbgCpu {
     breakpoints: Vec<Breakpoint>,
     watchpoints: Vec<Watchpoint>,
 
+    // PCs of the BREAK instructions that stop the debugger. The Breakpoint
+    // exception is taken only once execution is resumed.
+    #[serde(default)]
+    break_stops: Vec<u64>,
+
     #[serde(skip)]
     bp_oneshot: Option<u64>, // Special one-shot breakpoint
 
@@ -368,6 +382,13 @@ impl Debugger {
             .unwrap()
             .add_breakpoint(pc, description);
     }
+
+    pub fn add_break_stop(&mut self, cpu_name: &str, pc: u64) {
+        let cpu = self.cpus.get_mut(cpu_name).unwrap();
+        if !cpu.break_stops.contains(&pc) {
+            cpu.break_stops.push(pc);
+        }
+    }
 }
 
 impl Debugger {
@@ -406,6 +427,14 @@ impl Debugger {
         }
     }
 
+    fn trace_break(&self, cpu_name: &str, pc: u64) -> Result<()> {
+        if self.cpus[cpu_name].break_stops.contains(&pc) {
+            Err(box TraceEvent::GenericBreak(format!("BREAK instruction at {:x}", pc)))
+        } else {
+            Ok(())
+        }
+    }
+
     fn trace_mem_read(&self, cpu_name: &str, addr: u64, _size: AccessSize, val: u64) -> Result<()> {
         let cpu = &self.cpus[cpu_name];
         match cpu.wp_fastmap.get(&addr) {
@@ -595,6 +624,15 @@ impl Debugger {
             ctx.new_wp_value = 0;
             ui.open_popup(im_str!("##wp#new"));
         }
+        ui.same_line(0.0);
+        ui.text_disabled(im_str!("(?)"));
+        if ui.is_item_hovered() {
+            ui.tooltip_text(im_str!(
+                "Watchpoints only trigger on loads and stores made by this CPU.\n\
+                 Writes made by DMA transfers or by the RDP are not detected;\n\
+                 use --bus-watch to log DMA writes."
+            ));
+        }
 
         let mut wp_changed = false;
 
@@ -639,6 +677,46 @@ impl Debugger {
         }
     }
 
+    fn render_break_stops(&mut self, ui: &Ui<'_>, ctx: &mut UiCtx, cpu_name: &str) {
+        let cpu = self.cpus.get_mut(cpu_name).unwrap();
+
+        ui.popup(im_str!("##brk#new"), || {
+            ui.text(im_str!("PC:"));
+            ui.same_line(60.0);
+            imgui_input_hex(ui, im_str!("###brk#new_pc"), &mut ctx.new_bp_pc, false);
+
+            if ui.button(im_str!("Add"), [40.0, 20.0]) {
+                if !cpu.break_stops.contains(&ctx.new_bp_pc) {
+                    cpu.break_stops.push(ctx.new_bp_pc);
+                }
+                ui.close_current_popup();
+            }
+        });
+        if ui.small_button(im_str!("New BREAK stop")) {
+            ctx.new_bp_pc = 0;
+            ui.open_popup(im_str!("##brk#new"));
+        }
+
+        let mut removed = None;
+        ui.columns(2, im_str!(""), true);
+        ui.set_column_offset(1, 80.0);
+        for (idx, pc) in cpu.break_stops.iter_mut().enumerate() {
+            let name = im_str!("###breakstops#pc#{}", idx);
+            imgui_input_hex(ui, &name, pc, true);
+            ui.next_column();
+
+            if ui.small_button(&im_str!("Remove###breakstops#remove#{}", idx)) {
+                removed = Some(idx);
+            }
+            ui.next_column();
+        }
+        ui.columns(1, im_str!(""), false);
+
+        if let Some(idx) = removed {
+            cpu.break_stops.remove(idx);
+        }
+    }
+
     fn render_points(&mut self, ui: &Ui<'_>, ctx: &mut UiCtx) {
         for idx in 0..ctx.cpus.len() {
             let cpu_name = ctx.cpus[idx].clone();
@@ -660,6 +738,13 @@ impl Debugger {
                     {
                         self.render_watchpoints(ui, ctx, &cpu_name);
                     }
+                    if ui
+                        .collapsing_header(im_str!("BREAK stops"))
+                        .default_open(false)
+                        .build()
+                    {
+                        self.render_break_stops(ui, ctx, &cpu_name);
+                    }
                 });
         }
     }
@@ -0,0 +1,166 @@
+//! Timing of DMA transfers.
+//!
+//! Most DMA engines have the same shape: they copy a block of bytes between
+//! main memory and a device, which takes some time depending on the speed
+//! of the device, and raise an interrupt when done. A [`DmaChannel`](struct.DmaChannel.html)
+//! schedules transfers over time, so that busy flags and completion
+//! interrupts are observed when the real hardware would; the device that
+//! owns the channel still moves the data itself.
+use crate::state::{ArrayField, Field};
+
+/// Timing parameters of a DMA channel. All durations are expressed in cycles
+/// of the clock of the device that owns the channel.
+#[derive(Debug, Copy, Clone)]
+pub struct DmaTiming {
+    /// Cycles spent before the first word of each burst is transferred.
+    pub latency: i64,
+
+    /// Number of bytes transferred in each burst (that is, between two
+    /// latency periods).
+    pub burst_size: usize,
+
+    /// Number of bytes transferred in each word.
+    pub word_size: usize,
+
+    /// Cycles spent to transfer each word.
+    pub cycles_per_word: i64,
+}
+
+impl DmaTiming {
+    /// Return the number of cycles taken by a transfer of `len` bytes.
+    pub fn duration(&self, len: usize) -> i64 {
+        let bursts = (len + self.burst_size - 1) / self.burst_size;
+        let words = (len + self.word_size - 1) / self.word_size;
+        bursts as i64 * self.latency + words as i64 * self.cycles_per_word
+    }
+}
+
+/// A DMA channel, that keeps track of the transfers in flight. Transfers are
+/// processed in order: each one starts when the previous one is completed.
+/// The channel can queue up to a fixed number of transfers (the depth),
+/// including the one in progress.
+pub struct DmaChannel {
+    pub timing: DmaTiming,
+    completions: ArrayField<i64>, // Completion cycle of each queued transfer
+    count: Field<usize>,          // Number of queued transfers
+}
+
+impl DmaChannel {
+    /// Create a DMA channel with the specified name (used for serialization),
+    /// timing and depth.
+    pub fn new(name: &str, timing: DmaTiming, depth: usize) -> Self {
+        DmaChannel {
+            timing,
+            completions: ArrayField::new(&format!("{}::completions", name), 0, depth),
+            count: Field::new(&format!("{}::count", name), 0),
+        }
+    }
+
+    /// Number of transfers queued (including the one in progress).
+    pub fn pending(&self) -> usize {
+        *self.count
+    }
+
+    /// Return true if a transfer is in progress.
+    pub fn is_busy(&self) -> bool {
+        *self.count != 0
+    }
+
+    /// Return true if no more transfers can be queued.
+    pub fn is_full(&self) -> bool {
+        *self.count == self.completions.len()
+    }
+
+    /// Cycle at which the transfer in progress will complete, if any.
+    pub fn next_completion(&self) -> Option<i64> {
+        if self.is_busy() {
+            Some(self.completions[0])
+        } else {
+            None
+        }
+    }
+
+    /// Queue a transfer of `len` bytes, requested at cycle `now`. Returns
+    /// the cycle at which the transfer will complete, or None if the
+    /// channel is full.
+    pub fn start(&mut self, now: i64, len: usize) -> Option<i64> {
+        if self.is_full() {
+            return None;
+        }
+        let count = *self.count;
+        let begin = if count > 0 {
+            self.completions[count - 1].max(now)
+        } else {
+            now
+        };
+        let end = begin + self.timing.duration(len);
+        self.completions[count] = end;
+        *self.count += 1;
+        Some(end)
+    }
+
+    /// Advance the channel to cycle `now`, retiring all the transfers that
+    /// are completed by then. Returns the number of retired transfers, so
+    /// that the caller can raise the completion interrupt.
+    pub fn advance(&mut self, now: i64) -> usize {
+        let count = *self.count;
+        let done = self.completions[..count]
+            .iter()
+            .take_while(|&&end| end <= now)
+            .count();
+        for i in done..count {
+            self.completions[i - done] = self.completions[i];
+        }
+        *self.count -= done;
+        done
+    }
+
+    /// Abort all queued transfers.
+    pub fn reset(&mut self) {
+        *self.count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing() -> DmaTiming {
+        DmaTiming {
+            latency: 10,
+            burst_size: 16,
+            word_size: 4,
+            cycles_per_word: 2,
+        }
+    }
+
+    #[test]
+    fn duration() {
+        let t = timing();
+        assert_eq!(t.duration(4), 10 + 2);
+        assert_eq!(t.duration(16), 10 + 4 * 2);
+        assert_eq!(t.duration(18), 2 * 10 + 5 * 2);
+    }
+
+    #[test]
+    fn queue() {
+        let mut ch = DmaChannel::new("test_dma", timing(), 2);
+        assert_eq!(ch.is_busy(), false);
+        assert_eq!(ch.start(100, 16), Some(118));
+        assert_eq!(ch.start(105, 4), Some(130));
+        assert_eq!(ch.is_full(), true);
+        assert_eq!(ch.start(110, 4), None);
+
+        assert_eq!(ch.advance(117), 0);
+        assert_eq!(ch.advance(118), 1);
+        assert_eq!(ch.pending(), 1);
+        assert_eq!(ch.next_completion(), Some(130));
+
+        // A transfer started on an idle channel begins immediately
+        assert_eq!(ch.advance(200), 1);
+        assert_eq!(ch.is_busy(), false);
+        assert_eq!(ch.start(200, 4), Some(212));
+        ch.reset();
+        assert_eq!(ch.next_completion(), None);
+    }
+}
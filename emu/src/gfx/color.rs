@@ -275,6 +275,9 @@ impl<CF: ColorFormat> fmt::Debug for Color<CF> {
 
 pub type I4 = cf<u8, U4, U4, U0, U0, U0, U0, U0, U0, U0>;
 pub type I8 = cf<u8, U8, U8, U0, U0, U0, U0, U0, U0, U0>;
+pub type Ia31 = cf<u8, U4, U3, U1, U0, U0, U0, U0, U1, U0>;
+pub type Ia44 = cf<u8, U8, U4, U4, U0, U0, U0, U0, U4, U0>;
+pub type Ia88 = cf<u16, U16, U8, U8, U0, U0, U0, U0, U8, U0>;
 pub type Rgb555 = cf<u16, U16, U5, U0, U5, U5, U5, U10, U0, U0>;
 pub type Rgb565 = cf<u16, U16, U5, U0, U6, U5, U5, U11, U0, U0>;
 pub type Rgb888 = cf<u32, U32, U8, U0, U8, U8, U8, U16, U0, U0>;
@@ -283,6 +286,7 @@ pub type Xbgr8888 = cf<u32, U32, U8, U24, U8, U16, U8, U8, U0, U0>;
 pub type Bgr565 = cf<u16, U16, U5, U11, U6, U5, U5, U0, U0, U0>;
 pub type Rgba5551 = cf<u16, U16, U5, U0, U5, U5, U5, U10, U1, U15>;
 pub type Rgba8888 = cf<u32, U32, U8, U0, U8, U8, U8, U16, U8, U24>;
+pub type Abgr1555 = cf<u16, U16, U5, U11, U5, U6, U5, U1, U1, U0>;
 pub type Abgr8888 = cf<u32, U32, U8, U24, U8, U16, U8, U8, U8, U0>;
 
 pub trait ColorConverter<CF2: ColorFormat>: Sized {
@@ -330,6 +334,37 @@ macro_rules! define_greyscale_conversions {
 define_greyscale_conversions!(I4, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
 define_greyscale_conversions!(I8, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
 
+// Expand a n-bit component to 8 bits by bit replication (eg: 3 bits
+// abc become abcabcab).
+fn replicate8(v: i32, n: usize) -> i32 {
+    let mut res = 0;
+    let mut shift = 8 - n as i32;
+    while shift > -(n as i32) {
+        res |= if shift >= 0 { v << shift } else { v >> -shift };
+        shift -= n as i32;
+    }
+    res & 0xFF
+}
+
+macro_rules! define_greyscale_alpha_conversions {
+    ($cfgrey:ident, $($cf2:ident),+) => {
+        $(
+            impl ColorConverter<$cf2> for Color<$cfgrey> {
+                #[inline(always)]
+                fn cconv(self) -> Color<$cf2> {
+                    let i = replicate8(self.r.val, <$cfgrey as ColorFormat>::RN::to_usize());
+                    let a = replicate8(self.a.val, <$cfgrey as ColorFormat>::AN::to_usize());
+                    Color::<Rgba8888>::new_clamped(i, i, i, a).cconv()
+                }
+            }
+        )+
+    };
+}
+
+define_greyscale_alpha_conversions!(Ia31, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
+define_greyscale_alpha_conversions!(Ia44, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
+define_greyscale_alpha_conversions!(Ia88, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -368,6 +403,17 @@ mod tests {
         assert_eq!(Color::<Rgba8888>::new_clamped(0x44, 0x44, 0x44, 0xff), c2);
     }
 
+    #[test]
+    fn greyscale_alpha() {
+        let c = Color::<Ia31>::new_clamped(0x5, 0, 0, 1);
+        let c2: Color<Rgba8888> = c.cconv();
+        assert_eq!(Color::<Rgba8888>::new_clamped(0xB6, 0xB6, 0xB6, 0xff), c2);
+
+        let c = Color::<Ia44>::from_bits(0x7C);
+        let c2: Color<Rgba8888> = c.cconv();
+        assert_eq!(Color::<Rgba8888>::new_clamped(0x77, 0x77, 0x77, 0xCC), c2);
+    }
+
     #[test]
     fn alpha() {
         assert_eq!(Color::<Rgb555>::new(0x10, 0x10, 0x10, 0).is_some(), true);
@@ -8,6 +8,7 @@
 
 pub mod bus;
 pub mod dbg;
+pub mod dma;
 pub mod fp;
 pub mod gfx;
 pub mod hw;
@@ -2,7 +2,7 @@ extern crate bit_field;
 extern crate byteorder;
 extern crate emu;
 extern crate slog;
-use super::mi::{IrqMask, Mi};
+use super::errors::*;
 use super::r4300::R4300;
 use super::rdp::Rdp;
 use super::sp::RSPCPU;
@@ -10,6 +10,7 @@ use emu::bus::be::{Device, MemIoR, Reg32, RegDeref, RegRef};
 use emu::dbg;
 use emu::int::Numerics;
 use emu::sync;
+use std::path::Path;
 
 bitflags! {
     struct StatusFlags: u32 {
@@ -37,12 +38,15 @@ impl RegDeref for StatusFlags {
     }
 }
 
+/// Mask of command addresses in XBUS mode (DMEM is 4KB).
+const DMEM_MASK: u32 = 0xFFF;
+
 #[derive(DeviceBE)]
 pub struct Dp {
-    #[reg(bank = 0, offset = 0x0, rwmask = 0x00FFFFFF, wcb)]
+    #[reg(bank = 0, offset = 0x0, rwmask = 0x00FFFFF8, wcb)]
     cmd_start: Reg32,
 
-    #[reg(bank = 0, offset = 0x4, rwmask = 0x00FFFFFF, wcb)]
+    #[reg(bank = 0, offset = 0x4, rwmask = 0x00FFFFF8, wcb)]
     cmd_end: Reg32,
 
     #[reg(bank = 0, offset = 0x8, readonly)]
@@ -51,13 +55,28 @@ pub struct Dp {
     #[reg(bank = 0, offset = 0xC, wcb)]
     cmd_status: Reg32,
 
+    // Counters (24-bit), all cleared through writes to the status register.
+    #[reg(bank = 0, offset = 0x10, readonly)]
+    cmd_clock: Reg32,
+
+    #[reg(bank = 0, offset = 0x14, readonly)]
+    cmd_bufbusy: Reg32,
+
+    #[reg(bank = 0, offset = 0x18, readonly)]
+    cmd_pipebusy: Reg32,
+
+    #[reg(bank = 0, offset = 0x1C, readonly)]
+    cmd_tmem: Reg32,
+
     logger: slog::Logger,
 
     fetched_mem: MemIoR<u64>,
     fetched_start_addr: u32,
     fetched_end_addr: u32,
+    fetched_xbus: bool,
     cycles: i64,
     running: bool,
+    noise_seed: u32,
 
     gfx: Box<Rdp>,
 }
@@ -70,16 +89,55 @@ impl Dp {
             cmd_end: Reg32::default(),
             cmd_current: Reg32::default(),
             cmd_status: Reg32::default(),
+            cmd_clock: Reg32::default(),
+            cmd_bufbusy: Reg32::default(),
+            cmd_pipebusy: Reg32::default(),
+            cmd_tmem: Reg32::default(),
             logger,
             cycles: 0,
             running: false,
+            noise_seed: 1,
             fetched_mem: MemIoR::default(),
             fetched_start_addr: 0,
             fetched_end_addr: 0,
+            fetched_xbus: false,
             gfx: Box::new(Rdp::new(gfx_logger)),
         })
     }
 
+    /// Start recording the RDP command stream to a capture file.
+    pub fn start_rdp_capture(&mut self, path: &Path) -> Result<()> {
+        self.gfx.start_capture(path)
+    }
+
+    pub fn stop_rdp_capture(&mut self) {
+        self.gfx.stop_capture()
+    }
+
+    /// Replay a capture file through the RDP, dumping the color image of
+    /// each frame into `out_dir`. Returns the number of frames replayed.
+    pub fn replay_rdp_capture(&mut self, path: &Path, out_dir: &Path) -> Result<usize> {
+        self.gfx.replay_capture(path, out_dir)
+    }
+
+    /// Seed the RDP noise generator (used by the combiner and by dithering),
+    /// so that emulation runs can be reproduced exactly.
+    pub fn set_rdp_noise_seed(&mut self, seed: u32) {
+        self.noise_seed = seed;
+        self.gfx.set_noise_seed(seed);
+    }
+
+    /// Restart the RDP noise sequence from the current seed.
+    pub fn reset_rdp_noise(&mut self) {
+        self.gfx.set_noise_seed(self.noise_seed);
+    }
+
+    /// Return the software RDP, for debuggers to inspect its state (tiles,
+    /// TMEM contents and image formats).
+    pub fn rdp(&self) -> &Rdp {
+        &self.gfx
+    }
+
     fn cmd_status_ref(&self) -> RegRef<StatusFlags> {
         self.cmd_status.as_ref::<StatusFlags>()
     }
@@ -110,6 +168,46 @@ impl Dp {
         if new & (1<<1) != 0 {
             status.insert(StatusFlags::XBUS_DMA);
         }
+        if new & (1<<2) != 0 {
+            status.remove(StatusFlags::FREEZE);
+        }
+        if new & (1<<3) != 0 {
+            status.insert(StatusFlags::FREEZE);
+        }
+        if new & (1<<4) != 0 {
+            status.remove(StatusFlags::FLUSH);
+        }
+        if new & (1<<5) != 0 {
+            status.insert(StatusFlags::FLUSH);
+        }
+        if new & (1<<6) != 0 {
+            self.cmd_tmem.set(0);
+        }
+        if new & (1<<7) != 0 {
+            self.cmd_pipebusy.set(0);
+        }
+        if new & (1<<8) != 0 {
+            self.cmd_bufbusy.set(0);
+        }
+        if new & (1<<9) != 0 {
+            self.cmd_clock.set(0);
+        }
+    }
+
+    /// Advance a 24-bit DP counter register.
+    fn add_counter(reg: &mut Reg32, cycles: i64) {
+        reg.set((reg.get() + cycles as u32) & 0x00FF_FFFF);
+    }
+
+    fn set_running(&mut self, running: bool) {
+        self.running = running;
+        let mut status = self.cmd_status_ref();
+        let busy = StatusFlags::CMD_BUSY | StatusFlags::PIPE_BUSY | StatusFlags::START_GLK;
+        if running {
+            status.insert(busy);
+        } else {
+            status.remove(busy);
+        }
     }
 
     fn check_start(&mut self) {
@@ -122,23 +220,41 @@ impl Dp {
         // See if the start ptr changed, if so we need to refetch it.
         // Otherwise, continue from current pointer.
         if status.contains(StatusFlags::START_VALID) {
-            let start = self.cmd_start.get();
-            *self.cmd_current_ref() = start;
-            self.fetched_start_addr = start;
-            if status.contains(StatusFlags::XBUS_DMA) {
+            // A new buffer is only started once the current one is
+            // finished: the RSP (in XBUS mode) usually double-buffers
+            // its output in DMEM and queues the next buffer while the
+            // previous one is still being processed.
+            if self.running {
+                return;
+            }
+            let mut start = self.cmd_start.get();
+            self.fetched_xbus = status.contains(StatusFlags::XBUS_DMA);
+            if self.fetched_xbus {
+                // Commands are read from DMEM, through the RSP bus.
+                start &= DMEM_MASK;
                 self.fetched_mem = RSPCPU::get().bus.fetch_read::<u64>(start);
             } else {
                 self.fetched_mem = R4300::get().bus.fetch_read::<u64>(start);
             }
-            if self.fetched_mem.iter().is_none() {
-                error!(self.logger, "cmd buffer pointing to non-linear memory"; o!("ptr" => start.hex()));
-            }
+            *self.cmd_current_ref() = start;
+            self.fetched_start_addr = start;
             status.remove(StatusFlags::START_VALID);
         }
 
         self.fetched_end_addr = self.cmd_end.get();
+        if self.fetched_xbus {
+            // Don't run past the end of DMEM.
+            self.fetched_end_addr = self
+                .fetched_end_addr
+                .min(DMEM_MASK + 1)
+                .max(self.fetched_start_addr);
+        }
         status.remove(StatusFlags::END_VALID);
-        self.running = true;
+        if self.fetched_mem.iter().is_none() {
+            error!(self.logger, "cmd buffer pointing to non-linear memory"; o!("ptr" => self.fetched_start_addr.hex()));
+            return;
+        }
+        self.set_running(true);
         warn!(
             self.logger,
             "DP start";
@@ -153,9 +269,43 @@ impl sync::Subsystem for Dp {
     }
 
     fn run(&mut self, until: i64, _: &dbg::Tracer) -> dbg::Result<()> {
-        if !self.running {
-            self.cycles = until;
-            return Ok(());
+        let start = self.cycles;
+        let was_running = self.running;
+        self.run_commands(until);
+        if was_running || self.running {
+            // The clock counter runs as long as the RDP is processing
+            // commands.
+            Dp::add_counter(&mut self.cmd_clock, self.cycles - start);
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, t: &dbg::Tracer) -> dbg::Result<()> {
+        self.run(self.cycles + 1, t)
+    }
+
+    fn cycles(&self) -> i64 {
+        self.cycles
+    }
+
+    fn pc(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl Dp {
+    /// Process commands until the specified cycle. Each command takes
+    /// an approximate number of cycles depending on the work done by the
+    /// RDP, so that the busy bits and the counters change over time. The
+    /// busy bits are cleared only once the last command has completed.
+    fn run_commands(&mut self, until: i64) {
+        if !self.running || self.cmd_status_ref().contains(StatusFlags::FREEZE) {
+            self.cycles = self.cycles.max(until);
+            return;
+        }
+        if self.cycles >= until {
+            // Still busy with the previous command.
+            return;
         }
         loop {
             let mut curr_addr = self.cmd_current_ref();
@@ -164,37 +314,30 @@ impl sync::Subsystem for Dp {
                 .iter()
                 .unwrap()
                 .skip((*curr_addr - self.fetched_start_addr) as usize / 8)
-                .take((self.fetched_end_addr - *curr_addr) as usize / 8)
+                .take(self.fetched_end_addr.saturating_sub(*curr_addr) as usize / 8)
             {
                 self.gfx.op(cmd);
                 *curr_addr += 8;
-                self.cycles += 1;
+
+                let (pipe, tmem) = self.gfx.take_busy_cycles();
+                let cost = 1 + pipe + tmem;
+                Dp::add_counter(&mut self.cmd_bufbusy, cost);
+                Dp::add_counter(&mut self.cmd_pipebusy, pipe);
+                Dp::add_counter(&mut self.cmd_tmem, tmem);
+                self.cycles += cost;
                 if self.cycles >= until {
-                    return Ok(());
+                    return;
                 }
             }
 
             // Finished the current buffer: stop iteration, but
             // check if there's a new buffer pending
-            self.running = false;
+            self.set_running(false);
             self.check_start();
             if !self.running {
                 self.cycles = until;
-                Mi::get_mut().set_irq_line(IrqMask::DP, true);
-                return Ok(());
+                return;
             }
         }
     }
-
-    fn step(&mut self, t: &dbg::Tracer) -> dbg::Result<()> {
-        self.run(self.cycles + 1, t)
-    }
-
-    fn cycles(&self) -> i64 {
-        self.cycles
-    }
-
-    fn pc(&self) -> Option<u64> {
-        None
-    }
 }
@@ -1,14 +1,17 @@
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
+extern crate slog;
 
+use emu::bus::be::Device;
 use emu::dbg;
 use emu::hw;
 use emu::log;
+use r64emu::dp::Dp;
 use r64emu::errors::*;
+use r64emu::r4300::R4300;
 use r64emu::N64;
 
-use std::path::Path;
-
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -27,6 +30,42 @@ struct Cli {
     )]
     bios: std::path::PathBuf,
 
+    /// Record the RDP command stream to the specified file
+    #[structopt(long = "rdp-capture", parse(from_os_str))]
+    rdp_capture: Option<std::path::PathBuf>,
+
+    /// Replay an RDP command stream capture without running the ROM, and exit
+    #[structopt(long = "rdp-replay", parse(from_os_str))]
+    rdp_replay: Option<std::path::PathBuf>,
+
+    /// Directory where the frames drawn by --rdp-replay are written
+    #[structopt(long = "rdp-replay-out", parse(from_os_str), default_value = ".")]
+    rdp_replay_out: std::path::PathBuf,
+
+    /// Seed of the RDP noise generator
+    #[structopt(long = "rdp-seed", default_value = "1")]
+    rdp_seed: u32,
+
+    /// Emulate the Expansion Pak (8MB of RDRAM)
+    #[structopt(long = "expansion-pak")]
+    expansion_pak: bool,
+
+    /// Write an execution trace of the main CPU to the specified file
+    #[structopt(long = "cpu-trace", parse(from_os_str))]
+    cpu_trace: Option<std::path::PathBuf>,
+
+    /// Only trace instructions within this range of addresses (eg: 80000400-80001000)
+    #[structopt(long = "cpu-trace-range", parse(try_from_str = "parse_pc_range"))]
+    cpu_trace_range: Option<(u32, u32)>,
+
+    /// Only trace instructions of these classes (alu, branch, load, store, cop0, cop1, cop2)
+    #[structopt(long = "cpu-trace-class")]
+    cpu_trace_class: Vec<mips64::InsnClass>,
+
+    /// Log all writes to this range of physical addresses, by the CPU or by DMA, but not by the RDP (eg: 00000300-0000031F)
+    #[structopt(long = "bus-watch", parse(try_from_str = "parse_pc_range"))]
+    bus_watch: Vec<(u32, u32)>,
+
     /// Path to the ROM file
     #[structopt(parse(from_os_str))]
     rom: std::path::PathBuf,
@@ -34,15 +73,56 @@ struct Cli {
 
 quick_main!(run);
 
-fn create_n64(romfn: &Path, biosfn: &Path, logger: slog::Logger) -> Result<N64> {
-    let mut n64 = N64::new(logger, romfn, biosfn).unwrap();
+fn parse_pc_range(s: &str) -> std::result::Result<(u32, u32), String> {
+    let parse = |v: &str| u32::from_str_radix(v.trim_start_matches("0x"), 16);
+    let mut parts = s.splitn(2, '-');
+    match (parts.next().map(parse), parts.next().map(parse)) {
+        (Some(Ok(start)), Some(Ok(end))) if start <= end => Ok((start, end)),
+        _ => Err(format!("invalid address range: {}", s)),
+    }
+}
+
+fn create_n64(args: &Cli, logger: slog::Logger) -> Result<N64> {
+    let mut n64 = N64::with_expansion_pak(logger, &args.rom, &args.bios, args.expansion_pak)?;
     n64.setup_cic(true)?;
+    Dp::get_mut().set_rdp_noise_seed(args.rdp_seed);
+    if let Some(ref path) = args.rdp_capture {
+        Dp::get_mut().start_rdp_capture(path)?;
+    }
+    if let Some(ref path) = args.cpu_trace {
+        let filter = mips64::TraceFilter {
+            pc_range: args.cpu_trace_range,
+            classes: args.cpu_trace_class.clone(),
+        };
+        R4300::get_mut()
+            .start_trace(path, filter)
+            .chain_err(|| "cannot create CPU trace file")?;
+    }
+    for &(begin, end) in args.bus_watch.iter() {
+        n64.watch_bus_writes(begin, end);
+    }
     Ok(n64)
 }
 
+/// Replay an RDP capture on a N64 that is never booted, so that nothing but
+/// the captured commands can touch RDRAM.
+fn replay_rdp(args: &Cli, path: &std::path::Path) -> Result<()> {
+    let logger = log::new_console_logger();
+    let _n64 =
+        N64::with_expansion_pak(logger.new(o!()), &args.rom, &args.bios, args.expansion_pak)?;
+    Dp::get_mut().set_rdp_noise_seed(args.rdp_seed);
+    let frames = Dp::get_mut().replay_rdp_capture(path, &args.rdp_replay_out)?;
+    info!(logger, "RDP capture replayed"; "path" => ?path, "frames" => frames);
+    Ok(())
+}
+
 fn run() -> Result<()> {
     let args = Cli::from_args();
 
+    if let Some(ref path) = args.rdp_replay {
+        return replay_rdp(&args, path);
+    }
+
     let mut out = hw::Output::new(
         hw::VideoConfig {
             window_title: "R64EMU - Nintendo 64 Emulator".into(),
@@ -59,14 +139,14 @@ fn run() -> Result<()> {
 
     if args.debugger {
         let (logger, logpool) = log::new_pool_logger();
-        let mut n64 = create_n64(&args.rom, &args.bios, logger).unwrap();
+        let mut n64 = create_n64(&args, logger).unwrap();
         let mut dbgconfig = args.rom.clone();
         dbgconfig.set_extension("dbg");
         out.run_and_debug(&mut n64, &dbgconfig, logpool);
     } else {
         out.run_threaded(move || {
             let logger = log::new_console_logger();
-            let n64 = create_n64(&args.rom, &args.bios, logger).unwrap();
+            let n64 = create_n64(&args, logger).unwrap();
             Ok(Box::new(n64))
         });
     }
@@ -120,9 +120,16 @@ impl Mi {
         self.update_cpu_irq();
     }
 
+    // All peripheral interrupts are OR'ed together into the CPU IP2 line.
     fn update_cpu_irq(&self) {
-        R4300::get_mut()
-            .cop0
-            .set_hwint_line(0, (self.irq_ack.get() & self.irq_mask.get()) != 0);
+        let cpu = R4300::get_mut();
+        let pending = (self.irq_ack.get() & self.irq_mask.get()) != 0;
+        cpu.cop0.set_hwint_line(0, pending);
+        if pending {
+            // The line can be raised by a CPU access in the middle of a
+            // tight loop (eg: writing MI_MASK or SP_STATUS): exit it so that
+            // the interrupt is taken right after the access.
+            cpu.ctx_mut().tight_exit = true;
+        }
     }
 }
@@ -1,9 +1,10 @@
-use emu::bus::be::{Bus, Device};
+use emu::bus::be::{Bus, BusWatch, Device};
 use emu::dbg;
 use emu::dbg::{DebuggerModel, DebuggerRenderer};
 use emu::gfx::{GfxBufferMutLE, Rgb888};
 use emu::hw;
 use emu::input::*;
+use emu::int::Numerics;
 use emu::snd::{SampleFormat, SndBufferMut, S16_STEREO};
 use emu::state::{CurrentState, State};
 use emu::sync;
@@ -22,7 +23,7 @@ use super::mi::Mi;
 use super::mips64;
 use super::pi::Pi;
 use super::r4300::R4300;
-use super::ri::Ri;
+use super::ri::{Ri, RDRAM_SIZE, RDRAM_SIZE_EXPANDED};
 use super::si::Si;
 use super::sp::{Sp, RSPCPU};
 use super::vi::Vi;
@@ -65,7 +66,7 @@ impl sync::SyncEmu for SyncEmu {
     fn subsystem(&self, idx: usize) -> Option<(&mut dyn sync::Subsystem, i64)> {
         match idx {
             0 => Some((R4300::get_mut().deref_mut(), MAIN_CLOCK + MAIN_CLOCK / 2)), // FIXME: uses DIVMOD),
-            1 => Some((RSPCPU::get_mut().deref_mut(), MAIN_CLOCK)),
+            1 => Some((RSPCPU::get_mut(), MAIN_CLOCK)),
             2 => Some((Dp::get_mut(), MAIN_CLOCK)),
             3 => Some((Ai::get_mut(), VCLK)),
             4 => Some((Pi::get_mut(), MAIN_CLOCK)),
@@ -117,6 +118,22 @@ impl N64 {
     pub const AUDIO_OUTPUT_FREQUENCY: i64 = Ai::OUTPUT_FREQUENCY;
 
     pub fn new(logger: slog::Logger, romfn: &Path, biosfn: &Path) -> Result<N64> {
+        N64::with_expansion_pak(logger, romfn, biosfn, false)
+    }
+
+    /// Create a N64, optionally with the Expansion Pak installed (8MB of
+    /// RDRAM instead of 4MB).
+    pub fn with_expansion_pak(
+        logger: slog::Logger,
+        romfn: &Path,
+        biosfn: &Path,
+        expansion_pak: bool,
+    ) -> Result<N64> {
+        let rdram_size = if expansion_pak {
+            RDRAM_SIZE_EXPANDED
+        } else {
+            RDRAM_SIZE
+        };
         let sync = sync::Sync::new(logger.new(o!()), SyncEmu);
 
         R4300::new(sync::Sync::new_logger(&sync)).register();
@@ -137,7 +154,7 @@ impl N64 {
         Si::new(sync::Sync::new_logger(&sync)).register();
         Vi::new(sync::Sync::new_logger(&sync)).register();
         Ai::new(sync::Sync::new_logger(&sync)).register();
-        Ri::new(sync::Sync::new_logger(&sync)).register();
+        Ri::new(sync::Sync::new_logger(&sync), rdram_size).register();
 
         // Now that all devices have been created, map the CPU buses.
         R4300::get_mut().map_bus()?;
@@ -167,7 +184,8 @@ impl N64 {
 
         // Setup the encryption seed, given the CIC model that we detect
         // by checksumming the ROM header.
-        let mut seed: u32 = match Cartridge::get().detect_cic_model()? {
+        let cic = Cartridge::get().detect_cic_model()?;
+        let mut seed: u32 = match cic {
             CicModel::Cic6101 => 0x3F, // starfox
             CicModel::Cic6102 => 0x3F, // mario
             CicModel::Cic6103 => 0x78, // banjo
@@ -185,11 +203,33 @@ impl N64 {
         // FIXME: fix RDRAM initialization emulation. IPL3 does initialize RDRAM (starting at 0x0400_0040),
         // and is supposed to end up writing the RAM size at 0x8000_0318, but it does not currently work.
         // This is relied upon by libdragon at least. So fix it by setting the RDRAM as already initialized
-        // and copying the RAM size.
+        // and copying the RAM size (IPL3 of CIC-6105 stores it at 0x8000_03F0 instead).
+        let rdram_size = Ri::get().rdram_size() as u32;
+        let size_addr = match cic {
+            CicModel::Cic6105 => 0x0000_03F0,
+            _ => 0x0000_0318,
+        };
         R4300::get_mut().bus.write::<u32>(0x0470_000C, 0x14);
-        R4300::get_mut().bus.write::<u32>(0x0000_0318, 4*1024*1024);
+        R4300::get_mut().bus.write::<u32>(size_addr, rdram_size);
         Ok(())
     }
+
+    /// Log all writes to the specified (inclusive) range of physical
+    /// addresses, either by the CPU or by DMA, together with the address of
+    /// the instruction that the main CPU is executing. Pixels drawn by the RDP
+    /// are written straight into RDRAM, and are not logged.
+    pub fn watch_bus_writes(&mut self, begin: u32, end: u32) {
+        let logger = self.logger.new(o!());
+        R4300::get_mut()
+            .bus
+            .add_watchpoint(begin, end, BusWatch::Write, move |acc| {
+                warn!(logger, "bus write"; o!(
+                    "addr" => acc.addr.hex(),
+                    "val" => acc.val.hex(),
+                    "size" => ?acc.size,
+                    "cpu_pc" => R4300::get().insn_pc().hex()));
+            });
+    }
 }
 
 impl hw::OutputProducer for N64 {
@@ -283,6 +323,7 @@ impl DebuggerModel for N64 {
             self.initial_state.clone().make_current();
             self.setup_cic(true).unwrap();
             self.sync.reset();
+            Dp::get_mut().reset_rdp_noise();
         } else {
             // Soft reset: just trigger a reset on CPUs and hope for the best
             R4300::get_mut().reset();
@@ -7,6 +7,7 @@ use bitfield::Bit;
 use byteorder::{BigEndian, ByteOrder};
 use emu::bus::be::{Device, Mem, MemFlags, Reg32};
 use emu::dbg;
+use emu::dma::{DmaChannel, DmaTiming};
 use emu::input::{InputManager, InputValue};
 use emu::int::Numerics;
 use emu::state::Field;
@@ -49,11 +50,11 @@ pub struct Pi {
     dma_status: Reg32,
 
     // [7:0] domain 1 device latency
-    #[reg(bank = 0, offset = 0x0014, rwmask = 0)]
+    #[reg(bank = 0, offset = 0x0014, rwmask = 0xFF)]
     dom1_latency: Reg32,
 
     // [7:0] domain 1 device R/W strobe pulse width
-    #[reg(bank = 0, offset = 0x0018, rwmask = 0)]
+    #[reg(bank = 0, offset = 0x0018, rwmask = 0xFF)]
     dom1_pulse_width: Reg32,
 
     // [3:0] domain 1 device page size
@@ -82,9 +83,22 @@ pub struct Pi {
 
     logger: slog::Logger,
     cycles: Field<i64>,
+    dma: DmaChannel,
     pub(crate) input: InputManager,
 }
 
+// Timing of PI transfers with a device, given its domain configuration. The
+// PI transfers 16 bits at a time, each one taking a strobe pulse and a
+// release period, and waits for the device latency at each page boundary.
+fn domain_timing(latency: u32, pulse_width: u32, page_size: u32, release: u32) -> DmaTiming {
+    DmaTiming {
+        latency: latency as i64 + 1,
+        burst_size: 1 << (page_size + 2),
+        word_size: 2,
+        cycles_per_word: (pulse_width + 1 + release + 1) as i64,
+    }
+}
+
 impl Pi {
     pub fn new(logger: slog::Logger, pifrom: &Path, input: InputManager) -> Result<Box<Pi>> {
         let mut contents = vec![];
@@ -95,6 +109,7 @@ impl Pi {
             rom: Mem::from_buffer("pif_rom", contents, MemFlags::READACCESS),
             ram: Mem::default(),
             cycles: Field::new("Pi::cycles", 0),
+            dma: DmaChannel::new("Pi::dma", domain_timing(0, 0, 0, 0), 1),
             input: input,
             dma_ram_addr: Reg32::default(),
             dma_rom_addr: Reg32::default(),
@@ -115,9 +130,55 @@ impl Pi {
     fn cb_write_dma_status(&mut self, old: u32, new: u32) {
         self.dma_status.set(old); // write bits are not related to read bits
         info!(self.logger, "write dma status"; o!("val" => format!("{:x}", new)));
+        if new & 1 != 0 {
+            // Reset the controller, aborting the current transfer
+            self.dma.reset();
+            self.dma_status.set(0);
+        }
         Mi::get_mut().set_irq_line(IrqMask::PI, false);
     }
 
+    // Timing of transfers with the device at the specified PI bus address:
+    // domain 2 covers 0x0500_0000-0x0FFF_FFFF (64DD registers and SRAM),
+    // domain 1 everything else (cartridge ROM and 64DD IPL).
+    fn dma_timing(&self, addr: u32) -> DmaTiming {
+        if addr >= 0x0500_0000 && addr < 0x1000_0000 {
+            domain_timing(
+                self.dom2_latency.get(),
+                self.dom2_pulse_width.get(),
+                self.dom2_page_size.get(),
+                self.dom2_release.get(),
+            )
+        } else {
+            domain_timing(
+                self.dom1_latency.get(),
+                self.dom1_pulse_width.get(),
+                self.dom1_page_size.get(),
+                self.dom1_release.get(),
+            )
+        }
+    }
+
+    // Schedule the completion of a transfer of len bytes. Data is moved
+    // immediately, but the DMA busy bit and the interrupt are only updated
+    // when the transfer is completed (see run()). Returns false if a
+    // transfer is already in progress, in which case the new one is ignored.
+    fn start_dma(&mut self, addr: u32, len: usize) -> bool {
+        if self.dma.is_busy() {
+            // Starting a transfer while another one is in progress sets the
+            // error bit, and is otherwise ignored.
+            error!(self.logger, "DMA started while busy");
+            self.dma_status.set(self.dma_status.get() | 4);
+            return false;
+        }
+        self.dma.timing = self.dma_timing(addr);
+        if let Some(end) = self.dma.start(*self.cycles, len) {
+            info!(self.logger, "DMA scheduled"; o!("end" => end));
+        }
+        self.dma_status.set(self.dma_status.get() | 1);
+        true
+    }
+
     fn cb_write_dma_wr_len(&mut self, _old: u32, len: u32) {
         let mut raddr = self.dma_rom_addr.get();
         let mut waddr = self.dma_ram_addr.get();
@@ -126,6 +187,10 @@ impl Pi {
             "dst(ram)" => waddr.hex(),
             "len" => len+1));
 
+        if !self.start_dma(raddr, len as usize + 1) {
+            return;
+        }
+
         let bus = &mut R4300::get_mut().bus;
         let mut i = 0;
         while i < len + 1 {
@@ -137,7 +202,6 @@ impl Pi {
         }
         self.dma_rom_addr.set(raddr);
         self.dma_ram_addr.set(waddr);
-        Mi::get_mut().set_irq_line(IrqMask::PI, true);
     }
 
     fn cb_write_dma_rd_len(&mut self, _old: u32, val: u32) {
@@ -148,19 +212,21 @@ impl Pi {
             "dst(rom)" => waddr.hex(),
             "len" => val+1));
 
+        if !self.start_dma(waddr, val as usize + 1) {
+            return;
+        }
+
         let bus = &mut R4300::get_mut().bus;
         let mut i = 0;
         while i < val + 1 {
-            let v = bus.read::<u32>(raddr);
-            info!(self.logger, "DMA DATA"; "data" => v.hex());
-
+            let data = bus.read::<u32>(raddr);
+            bus.write::<u32>(waddr, data);
             raddr = raddr + 4;
             waddr = waddr + 4;
             i += 4;
         }
-        Mi::get_mut().set_irq_line(IrqMask::PI, true);
-
-        unimplemented!();
+        self.dma_ram_addr.set(raddr);
+        self.dma_rom_addr.set(waddr);
     }
 
     pub fn begin_frame(&mut self) {
@@ -251,6 +317,44 @@ impl Pi {
         }
         return Err("joybus: no PIFRAM marker found");
     }
+
+    // Answer a CIC challenge (used at runtime by games with a CIC-NUS-6105).
+    // The challenge is stored as 30 nibbles in PIFRAM 0x30-0x3E, and the
+    // response overwrites it.
+    fn cic_challenge(&mut self) {
+        const LUT0: [u8; 16] = [
+            0x4, 0x7, 0xA, 0x7, 0xE, 0x5, 0xE, 0x1, 0xC, 0xF, 0x8, 0xF, 0x6, 0x3, 0x6, 0x9,
+        ];
+        const LUT1: [u8; 16] = [
+            0x4, 0x1, 0xA, 0x7, 0xE, 0x5, 0xE, 0x1, 0xC, 0x9, 0x8, 0x5, 0x6, 0x3, 0xC, 0x9,
+        ];
+
+        let mut key = 0xB;
+        let mut lut1 = false;
+        for i in 0..30 {
+            let byte = &mut self.ram[0x30 + i / 2];
+            let shift = if i & 1 == 0 { 4 } else { 0 };
+            let chl = (*byte >> shift) & 0xF;
+
+            let rsp = key.wrapping_add(5 * chl) & 0xF;
+            *byte = (*byte & !(0xF << shift)) | (rsp << shift);
+
+            key = if lut1 { LUT1 } else { LUT0 }[rsp as usize];
+            let sgn = (rsp >> 3) & 1;
+            let mag = if sgn == 1 { !rsp & 7 } else { rsp & 7 };
+            let mut m = if mag % 3 == 1 { sgn } else { 1 - sgn };
+            if lut1 {
+                match rsp {
+                    0x1 | 0x9 => m = 1,
+                    0xB | 0xE => m = 0,
+                    _ => {}
+                }
+            }
+            lut1 = m == 1;
+        }
+        self.ram[0x2E] = 0;
+        self.ram[0x2F] = 0;
+    }
 }
 
 impl sync::Subsystem for Pi {
@@ -270,6 +374,12 @@ impl sync::Subsystem for Pi {
             self.ram[0x3F] &= !0x20;
         }
 
+        if status & 0x02 != 0 {
+            info!(self.logger, "CIC challenge");
+            self.cic_challenge();
+            self.ram[0x3F] = 0;
+        }
+
         if status & 0x01 != 0 {
             info!(self.logger, "joybus triggered");
             self.joybus_exec();
@@ -294,6 +404,15 @@ impl sync::Subsystem for Pi {
             Si::get_mut().set_busy(false);
         }
 
+        if self.dma.advance(*self.cycles) > 0 {
+            self.dma_status.set(self.dma_status.get() & !1);
+            Mi::get_mut().set_irq_line(IrqMask::PI, true);
+        }
+
+        // SI transfers are between RDRAM and PIF RAM, so complete them after
+        // PIF has processed the commands.
+        Si::get_mut().advance_dma(*self.cycles);
+
         Ok(())
     }
 
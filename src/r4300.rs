@@ -1,6 +1,6 @@
 use std::ops::{Deref, DerefMut};
 use mips64;
-use emu::bus::be::{Bus, Device};
+use emu::bus::be::{Bus, Device, OpenBus};
 
 use super::n64::MAINCPU_NAME;
 use super::ai::Ai;
@@ -22,6 +22,27 @@ impl mips64::Config for R4300Config {
     type Cop1 = mips64::Fpu;
     type Cop2 = mips64::CopNull;
     type Cop3 = mips64::CopNull;
+
+    // VR4300 latencies of multiplications and divisions
+    fn op_stall(op: &'static str) -> i64 {
+        match op {
+            "mult" | "multu" => 4,
+            "dmult" | "dmultu" => 7,
+            "div" | "divu" => 36,
+            "ddiv" | "ddivu" => 68,
+            _ => 0,
+        }
+    }
+
+    // Caches are not emulated, so cached accesses are assumed to always hit.
+    // Uncached accesses (KSEG1) go through the SysAD bus; writes are buffered.
+    fn mem_stall(vaddr: u32, write: bool) -> i64 {
+        if vaddr >> 29 == 0b101 && !write {
+            20
+        } else {
+            0
+        }
+    }
 }
 
 #[derive(DeviceBE)]
@@ -75,6 +96,14 @@ impl R4300 {
         self.bus.map_device(0x1000_0000, Cartridge::get(), 0)?;
         self.bus.map_device(0x1800_0000, Cartridge::get(), 1)?;
         self.bus.map_device(0x1FC0_0000, Pi::get(), 1)?;
+
+        // The PI multiplexes address and data over the same lines, so reads
+        // of unmapped PI areas return the lower half of the address. Games
+        // probe these areas (eg: for the 64DD), so don't log them.
+        self.bus
+            .map_open_bus(0x0500_0000, 0x1FBF_FFFF, OpenBus::AddressLow, false)?;
+        self.bus
+            .map_open_bus(0x1FD0_0000, 0x7FFF_FFFF, OpenBus::AddressLow, false)?;
         Ok(())
     }
 }
\ No newline at end of file
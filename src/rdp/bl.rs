@@ -1,8 +1,5 @@
 // Blender
 
-// TODO:
-//   * alpha compare
-
 extern crate bit_field;
 extern crate emu;
 
@@ -39,7 +36,7 @@ impl Default for BlenderCycle {
 pub(crate) struct Blender {
     combined: MultiColor,
     shade: MultiColor,
-    inv_combined: MultiColor,
+    inv_alpha: MultiColor,
     partial_blended: MultiColor,
     framebuffer: MultiColor,
     reg_blend: MultiColor,
@@ -49,32 +46,198 @@ pub(crate) struct Blender {
     ff: MultiColor,   // 0xFF
 
     cycles: [BlenderCycle; 2],
+
+    force_blend: bool,
+    image_read: bool,
+    antialias: bool,
+    color_on_cvg: bool,
+    cvg_dest: u32,
+    alpha_cvg_select: bool,
+    cvg_times_alpha: bool,
+
+    // Coverage of the current pixel as computed by the rasterizer (number
+    // of covered samples, 0-8), the same after cvg_times_alpha, and the
+    // coverage stored in memory (3 bits, 0-7, meaning 1-8 samples).
+    coverage: u16,
+    pixel_coverage: u16,
+    mem_coverage: u16,
+    alpha_dither: u16,
 }
 
 impl Blender {
     pub(crate) fn new() -> Blender {
         Blender {
             ff: MultiColor::splat(0xff),
+            coverage: 8,
             ..Default::default()
         }
     }
 
+    /// Set the coverage of the next pixel, and its alpha dither value
+    /// (used to round the alpha-scaled coverage).
+    pub(crate) fn set_coverage(&mut self, cvg: u8, alpha_dither: u16) {
+        self.coverage = cvg as u16;
+        self.alpha_dither = alpha_dither;
+    }
+
+    /// Coverage stored in memory for the framebuffer pixel `fb`. It is
+    /// stored in the top bits of the framebuffer alpha (for 16-bit
+    /// framebuffers, only the alpha bit is available, as the RDRAM hidden
+    /// bits are not emulated); without image read, memory is considered
+    /// fully covered.
+    #[inline(always)]
+    fn memory_coverage(&self, fb: MultiColor) -> u16 {
+        if self.image_read {
+            fb.extract(3) >> 5
+        } else {
+            7
+        }
+    }
+
+    /// Whether a pixel with coverage `cvg` (0-8) drawn over `fb` overflows
+    /// the pixel coverage (see `cvg_overflow`), as seen by the depth test.
+    #[inline(always)]
+    pub(crate) fn coverage_overflow(&self, cvg: u8, fb: MultiColor) -> bool {
+        cvg as u16 + self.memory_coverage(fb) >= 8
+    }
+
+    fn set_inputs(&mut self, combined: MultiColor, shade: MultiColor, fb: MultiColor) {
+        self.mem_coverage = self.memory_coverage(fb);
+
+        // With cvg_times_alpha, coverage is scaled by the combined alpha.
+        // With alpha_cvg_select, the blender sees the coverage in place of
+        // alpha.
+        let alpha = combined.extract(3);
+        let cvg_alpha = (alpha * self.coverage + self.alpha_dither) >> 3;
+        self.pixel_coverage = if self.cvg_times_alpha {
+            cvg_alpha >> 5
+        } else {
+            self.coverage
+        };
+        self.combined = if self.alpha_cvg_select {
+            let a = if self.cvg_times_alpha {
+                cvg_alpha
+            } else {
+                (self.coverage << 5).min(0xFF)
+            };
+            combined.map_alpha(|_| a)
+        } else {
+            combined
+        };
+        self.shade = shade;
+
+        // Without image read, the framebuffer is not read at all: the
+        // blender sees a black memory color, with full coverage.
+        self.framebuffer = if self.image_read {
+            fb
+        } else {
+            self.zero.map_alpha(|_| 7 << 5)
+        };
+    }
+
+    /// Whether the coverage of the current pixel plus the memory coverage
+    /// exceeds a full pixel. This is the case for internal pixels, and for
+    /// edges drawn over a fully covered background.
+    #[inline(always)]
+    fn cvg_overflow(&self) -> bool {
+        self.pixel_coverage + self.mem_coverage >= 8
+    }
+
+    /// Whether the blend equation is applied. Otherwise, the blender just
+    /// outputs its P input. Blending happens when forced, or when
+    /// antialiasing an edge pixel over a partially covered one.
+    #[inline(always)]
+    fn blend_enabled(&self) -> bool {
+        self.force_blend || (self.antialias && !self.cvg_overflow())
+    }
+
+    /// Compute the coverage to store into memory, according to the coverage
+    /// destination mode (clamp, wrap, zap, save).
+    #[inline(always)]
+    fn final_coverage(&self, blend: bool) -> u16 {
+        let (cvg, mem) = (self.pixel_coverage, self.mem_coverage);
+        match self.cvg_dest {
+            0 => {
+                if !blend {
+                    cvg.max(1) - 1
+                } else {
+                    (cvg + mem).min(7)
+                }
+            }
+            1 => (cvg + mem) & 7,
+            2 => 7,
+            3 => mem,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Final step of the blender: with color_on_cvg, the color is only
+    /// updated if coverage overflows; the stored alpha is the new coverage.
+    #[inline(always)]
+    fn finalize(&self, color: MultiColor, blend: bool) -> MultiColor {
+        let color = if self.color_on_cvg && !self.cvg_overflow() {
+            self.framebuffer
+        } else {
+            color
+        };
+        let cvg = self.final_coverage(blend) << 5;
+        color.map_alpha(|_| cvg)
+    }
+
+    /// Compute the blend equation (P*A + M*B) / (A + B) for a cycle. A and B
+    /// are 5-bit (with B incremented by one); when `divide` is false, A+B is
+    /// assumed to be 1.0 as the hardware does for forced blending.
+    #[inline(always)]
+    fn blend_cycle(&mut self, cyc: usize, divide: bool) -> MultiColor {
+        self.inv_alpha = unsafe { *self.cycles[cyc].a }.map_alpha(|a| 0xFF - a);
+
+        let (p, m, a, b) = self.cycles[cyc].fetch();
+        let a = a.replicate_alpha() >> 3;
+        let b = (b.replicate_alpha() >> 3) + MultiColor::splat(1);
+
+        if divide {
+            (p * a + m * b) / (a + b)
+        } else {
+            ((p * a + m * b) >> 5).min(MultiColor::splat(0xFF))
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn blend_1cycle(
         &mut self,
         combined: MultiColor,
-        _shade: MultiColor,
+        shade: MultiColor,
         fb: MultiColor,
     ) -> MultiColor {
-        self.combined = combined;
-        self.inv_combined = combined.map_alpha(|a| 0xFF - a);
-        self.framebuffer = fb;
-
-        let (p, m, a, b) = self.cycles[0].fetch();
-        let a = a.replicate_alpha() >> 3;
-        let b = (b.replicate_alpha() >> 3) + MultiColor::splat(1);
+        self.set_inputs(combined, shade, fb);
+        let blend = self.blend_enabled();
+        let color = if blend {
+            self.blend_cycle(0, !self.force_blend)
+        } else {
+            unsafe { *self.cycles[0].p }
+        };
+        self.finalize(color, blend)
+    }
 
-        (p * a + m * b) / (a + b)
+    /// In two-cycle mode, the first cycle always computes the blend equation
+    /// (without division) into an intermediate color, that the second cycle
+    /// can select as its P or M input.
+    #[inline(always)]
+    pub(crate) fn blend_2cycle(
+        &mut self,
+        combined: MultiColor,
+        shade: MultiColor,
+        fb: MultiColor,
+    ) -> MultiColor {
+        self.set_inputs(combined, shade, fb);
+        self.partial_blended = self.blend_cycle(0, false);
+        let blend = self.blend_enabled();
+        let color = if blend {
+            self.blend_cycle(1, !self.force_blend)
+        } else {
+            unsafe { *self.cycles[1].p }
+        };
+        self.finalize(color, blend)
     }
 
     pub(crate) unsafe fn setup_cycle_pm(&self, cyc: usize, p_or_m: u32) -> *const MultiColor {
@@ -105,7 +268,7 @@ impl Blender {
                 _ => unreachable!(),
             },
             b: match b {
-                0 => &self.inv_combined,
+                0 => &self.inv_alpha,
                 1 => &self.framebuffer,
                 2 => &self.ff,
                 3 => &self.zero,
@@ -116,16 +279,24 @@ impl Blender {
 
     pub(crate) fn set_other_modes(&mut self, modes: u64) {
         let p = modes.get_bits(30..32) as u32;
-        let m = modes.get_bits(26..28) as u32;
-        let a = modes.get_bits(22..24) as u32;
+        let a = modes.get_bits(26..28) as u32;
+        let m = modes.get_bits(22..24) as u32;
         let b = modes.get_bits(18..20) as u32;
         self.cycles[0] = unsafe { self.setup_cycle(0, (p, m, a, b)) };
 
         let p = modes.get_bits(28..30) as u32;
-        let m = modes.get_bits(24..26) as u32;
-        let a = modes.get_bits(20..22) as u32;
+        let a = modes.get_bits(24..26) as u32;
+        let m = modes.get_bits(20..22) as u32;
         let b = modes.get_bits(16..18) as u32;
         self.cycles[1] = unsafe { self.setup_cycle(1, (p, m, a, b)) };
+
+        self.antialias = modes.get_bit(3);
+        self.image_read = modes.get_bit(6);
+        self.color_on_cvg = modes.get_bit(7);
+        self.cvg_dest = modes.get_bits(8..10) as u32;
+        self.cvg_times_alpha = modes.get_bit(12);
+        self.alpha_cvg_select = modes.get_bit(13);
+        self.force_blend = modes.get_bit(14);
     }
 
     pub(crate) fn set_fog_color(&mut self, c: Color<Rgba8888>) {
@@ -134,12 +305,17 @@ impl Blender {
     pub(crate) fn set_blend_color(&mut self, c: Color<Rgba8888>) {
         self.reg_blend = MultiColor::from_color(c);
     }
+    pub(crate) fn blend_alpha(&self) -> u16 {
+        self.reg_blend.extract(3)
+    }
 
     pub(crate) fn repr_comb_ptr(&self, ptr: *const MultiColor, alpha: bool) -> String {
         if ptr == &self.combined {
             (if alpha { "input.a" } else { "input" }).into()
-        } else if ptr == &self.inv_combined {
-            "(1.0 - input.a)".into()
+        } else if ptr == &self.inv_alpha {
+            "(1.0 - a)".into()
+        } else if ptr == &self.partial_blended {
+            "blended".into()
         } else if ptr == &self.reg_fog {
             (if alpha { "reg_fog.a" } else { "reg_fog" }).into()
         } else if ptr == &self.framebuffer {
@@ -147,7 +323,7 @@ impl Blender {
         } else if ptr == &self.reg_blend {
             "reg_blend".into()
         } else if ptr == &self.shade {
-            "shade".into()
+            (if alpha { "shade.a" } else { "shade" }).into()
         } else if ptr == &self.zero {
             "0.0".into()
         } else if ptr == &self.ff {
@@ -157,17 +333,34 @@ impl Blender {
         }
     }
 
-    pub(crate) fn fmt_1cycle(&self) -> String {
-        let a = self.repr_comb_ptr(self.cycles[0].a, true);
-        let b = self.repr_comb_ptr(self.cycles[0].b, true);
+    fn fmt_cycle(&self, cyc: usize) -> String {
+        let a = self.repr_comb_ptr(self.cycles[cyc].a, true);
+        let b = self.repr_comb_ptr(self.cycles[cyc].b, true);
         format!(
-            "Blender {{ ({}*{} + {}*{}) / ({}+{}) }}",
-            self.repr_comb_ptr(self.cycles[0].p, false),
+            "({}*{} + {}*{}) / ({}+{})",
+            self.repr_comb_ptr(self.cycles[cyc].p, false),
             a,
-            self.repr_comb_ptr(self.cycles[0].m, false),
+            self.repr_comb_ptr(self.cycles[cyc].m, false),
             b,
             a,
             b,
         )
     }
+
+    pub(crate) fn fmt_1cycle(&self) -> String {
+        format!(
+            "Blender {{ {}, force_blend: {} }}",
+            self.fmt_cycle(0),
+            self.force_blend
+        )
+    }
+
+    pub(crate) fn fmt_2cycle(&self) -> String {
+        format!(
+            "Blender {{ {}; {}, force_blend: {} }}",
+            self.fmt_cycle(0),
+            self.fmt_cycle(1),
+            self.force_blend
+        )
+    }
 }
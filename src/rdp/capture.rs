@@ -0,0 +1,109 @@
+// Capture and replay of the RDP command stream
+
+extern crate byteorder;
+
+use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crate::errors::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"R64RDPC1";
+
+/// Record tags. A capture file is made of the magic string followed by a
+/// sequence of records, each one starting with a tag byte.
+const TAG_RDRAM: u8 = b'M'; // RDRAM snapshot: u32 length + contents
+const TAG_CMD: u8 = b'C'; // Command word: u64
+const TAG_FRAME: u8 = b'F'; // End of frame (Sync Full)
+
+/// A single record read back from a capture file.
+pub(crate) enum Record {
+    Rdram(Vec<u8>),
+    Cmd(u64),
+    Frame,
+}
+
+/// Writer for a capture file. RDRAM is snapshotted at the beginning of each
+/// frame, so that a replay sees the same textures and framebuffers that the
+/// commands of that frame referenced.
+pub(crate) struct CaptureWriter {
+    out: BufWriter<File>,
+    frame_started: bool,
+}
+
+impl CaptureWriter {
+    pub(crate) fn create(path: &Path) -> Result<CaptureWriter> {
+        let mut out =
+            BufWriter::new(File::create(path).chain_err(|| "cannot create RDP capture file")?);
+        out.write_all(MAGIC)?;
+        Ok(CaptureWriter {
+            out,
+            frame_started: false,
+        })
+    }
+
+    /// Return true if the next command is the first of a frame (so that
+    /// RDRAM must be snapshotted before it).
+    pub(crate) fn need_rdram(&self) -> bool {
+        !self.frame_started
+    }
+
+    pub(crate) fn write_rdram(&mut self, rdram: &[u8]) -> Result<()> {
+        self.out.write_u8(TAG_RDRAM)?;
+        self.out.write_u32::<BigEndian>(rdram.len() as u32)?;
+        self.out.write_all(rdram)?;
+        self.frame_started = true;
+        Ok(())
+    }
+
+    pub(crate) fn write_cmd(&mut self, cmd: u64) -> Result<()> {
+        self.out.write_u8(TAG_CMD)?;
+        self.out.write_u64::<BigEndian>(cmd)?;
+        Ok(())
+    }
+
+    pub(crate) fn write_frame(&mut self) -> Result<()> {
+        self.out.write_u8(TAG_FRAME)?;
+        self.out.flush()?;
+        self.frame_started = false;
+        Ok(())
+    }
+}
+
+/// Reader for a capture file.
+pub(crate) struct CaptureReader {
+    input: BufReader<File>,
+}
+
+impl CaptureReader {
+    pub(crate) fn open(path: &Path) -> Result<CaptureReader> {
+        let mut input =
+            BufReader::new(File::open(path).chain_err(|| "cannot open RDP capture file")?);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("invalid RDP capture file");
+        }
+        Ok(CaptureReader { input })
+    }
+
+    /// Read the next record, or None at the end of the capture.
+    pub(crate) fn next_record(&mut self) -> Result<Option<Record>> {
+        let tag = match self.input.read_u8() {
+            Ok(tag) => tag,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(match tag {
+            TAG_RDRAM => {
+                let len = self.input.read_u32::<BigEndian>()? as usize;
+                let mut rdram = vec![0u8; len];
+                self.input.read_exact(&mut rdram)?;
+                Record::Rdram(rdram)
+            }
+            TAG_CMD => Record::Cmd(self.input.read_u64::<BigEndian>()?),
+            TAG_FRAME => Record::Frame,
+            _ => bail!("invalid record in RDP capture file: {:x}", tag),
+        }))
+    }
+}
@@ -1,8 +1,6 @@
 // Color combiner
 
 // TODO:
-//   * 2-cycle mode
-//   * chroma key
 //   * coverage alpha
 //   * alpha dithering
 
@@ -43,12 +41,23 @@ pub(crate) struct Combiner {
     env: MultiColor,
     key_center: MultiColor,
     key_scale: MultiColor,
+    key_width: [i32; 3],
     lod_fraction: MultiColor,
     prim_lod_fraction: MultiColor,
     noise: MultiColor,
     conv_k4: MultiColor,
     conv_k5: MultiColor,
     one: MultiColor,
+
+    // Alpha of the basic inputs, replicated over the RGB channels, for the
+    // RGB multiplier.
+    combined_alpha: MultiColor,
+    texel0_alpha: MultiColor,
+    texel1_alpha: MultiColor,
+    prim_alpha: MultiColor,
+    shade_alpha: MultiColor,
+    env_alpha: MultiColor,
+
     zero: MultiColor,
 
     cycle_rgb: [CombinerCycle; 2],
@@ -133,15 +142,44 @@ impl Combiner {
 
     #[inline(always)]
     pub(crate) fn combine_1cycle(&mut self, shade: MultiColor) -> MultiColor {
-        self.shade = shade;
+        self.set_shade_multi(shade);
         let c = self.combine_cycle(1);
 
         // Save as combined color (FIXME: this is not correct with parallel pixels)
         self.combined = c;
+        self.combined_alpha = c.replicate_alpha();
 
         return c;
     }
 
+    /// Run both combiner cycles. The second cycle gets the output of the
+    /// first one as combined color; also, its TEXEL0 input is the texel of
+    /// the next tile (and its TEXEL1 would be the texel of the next pixel,
+    /// which we approximate with the current one).
+    #[inline(always)]
+    pub(crate) fn combine_2cycle(&mut self, shade: MultiColor) -> MultiColor {
+        self.set_shade_multi(shade);
+        self.combined = self.combine_cycle(0);
+        self.combined_alpha = self.combined.replicate_alpha();
+
+        self.swap_texels();
+        let c = self.combine_cycle(1);
+        self.swap_texels();
+
+        self.combined = c;
+        c
+    }
+
+    fn swap_texels(&mut self) {
+        std::mem::swap(&mut self.texel0, &mut self.texel1);
+        std::mem::swap(&mut self.texel0_alpha, &mut self.texel1_alpha);
+    }
+
+    fn set_shade_multi(&mut self, shade: MultiColor) {
+        self.shade = shade;
+        self.shade_alpha = shade.replicate_alpha();
+    }
+
     unsafe fn setup_cycle_basic(&self, v: u32) -> *const MultiColor {
         match v {
             0 => &self.combined,
@@ -176,12 +214,12 @@ impl Combiner {
             mul: match mul {
                 0...5 => self.setup_cycle_basic(mul),
                 6 => &self.key_scale,
-                7 => &self.combined,
-                8 => &self.texel0,
-                9 => &self.texel1,
-                10 => &self.prim,
-                11 => &self.shade,
-                12 => &self.env,
+                7 => &self.combined_alpha,
+                8 => &self.texel0_alpha,
+                9 => &self.texel1_alpha,
+                10 => &self.prim_alpha,
+                11 => &self.shade_alpha,
+                12 => &self.env_alpha,
                 13 => &self.lod_fraction,
                 14 => &self.prim_lod_fraction,
                 15 => &self.conv_k5,
@@ -240,18 +278,103 @@ impl Combiner {
 
     pub(crate) fn set_tex0(&mut self, c: MultiColor) {
         self.texel0 = c;
+        self.texel0_alpha = c.replicate_alpha();
     }
     pub(crate) fn set_tex1(&mut self, c: MultiColor) {
         self.texel1 = c;
+        self.texel1_alpha = c.replicate_alpha();
     }
     pub(crate) fn set_prim(&mut self, c: Color<Rgba8888>) {
         self.prim = MultiColor::from_color(c);
+        self.prim_alpha = self.prim.replicate_alpha();
     }
     pub(crate) fn set_shade(&mut self, c: Color<Rgba8888>) {
-        self.shade = MultiColor::from_color(c);
+        self.set_shade_multi(MultiColor::from_color(c));
     }
     pub(crate) fn set_env(&mut self, c: Color<Rgba8888>) {
         self.env = MultiColor::from_color(c);
+        self.env_alpha = self.env.replicate_alpha();
+    }
+
+    /// Return true if the noise input is selected by the current mode.
+    pub(crate) fn uses_noise(&self) -> bool {
+        let noise: *const MultiColor = &self.noise;
+        self.cycle_rgb.iter().any(|cyc| cyc.suba == noise)
+    }
+
+    /// Set the value of the noise input: only the top 3 bits of each channel
+    /// are random.
+    pub(crate) fn set_noise(&mut self, rnd: u16) {
+        self.noise = MultiColor::splat(((rnd & 7) << 6) | 0x20);
+    }
+    pub(crate) fn set_key_r(&mut self, width: u16, center: u8, scale: u8) {
+        self.key_width[0] = width as i32;
+        self.key_center = self
+            .key_center
+            .replace(0, center as u16)
+            .replace(4, center as u16);
+        self.key_scale = self
+            .key_scale
+            .replace(0, scale as u16)
+            .replace(4, scale as u16);
+    }
+    pub(crate) fn set_key_gb(
+        &mut self,
+        (width_g, center_g, scale_g): (u16, u8, u8),
+        (width_b, center_b, scale_b): (u16, u8, u8),
+    ) {
+        self.key_width[1] = width_g as i32;
+        self.key_width[2] = width_b as i32;
+        self.key_center = self
+            .key_center
+            .replace(1, center_g as u16)
+            .replace(5, center_g as u16)
+            .replace(2, center_b as u16)
+            .replace(6, center_b as u16);
+        self.key_scale = self
+            .key_scale
+            .replace(1, scale_g as u16)
+            .replace(5, scale_g as u16)
+            .replace(2, scale_b as u16)
+            .replace(6, scale_b as u16);
+    }
+
+    /// Compute the chroma key alpha of the combined color. For each RGB
+    /// channel, the distance from the key center (multiplied by the key
+    /// scale) is subtracted from the key width (4.8 fixed point, where 1.0
+    /// covers the whole color range); the resulting alpha is the minimum
+    /// over the three channels, clamped to 0-255. Texels close to the key
+    /// center thus get a high alpha.
+    pub(crate) fn key_alpha(&self, c: MultiColor) -> MultiColor {
+        let mut alpha = MultiColor::splat(0);
+        for px in 0..2 {
+            let mut ka = 0xFF;
+            for ch in 0..3 {
+                let idx = px * 4 + ch;
+                let v = c.extract(idx) as i32;
+                let center = self.key_center.extract(idx) as i32;
+                let scale = self.key_scale.extract(idx) as i32;
+                let dist = ((v - center).abs() * scale) >> 8;
+                ka = ka.min(self.key_width[ch] - dist);
+            }
+            alpha = alpha.replace(px * 4 + 3, ka.max(0).min(0xFF) as u16);
+        }
+        alpha
+    }
+
+    /// Set the K4 and K5 coefficients (signed 9-bit), stored in two's
+    /// complement in the lanes.
+    pub(crate) fn set_convert_k45(&mut self, k4: i32, k5: i32) {
+        self.conv_k4 = MultiColor::splat(k4 as i16 as u16);
+        self.conv_k5 = MultiColor::splat(k5 as i16 as u16);
+    }
+
+    pub(crate) fn set_lod_frac(&mut self, frac: u16) {
+        self.lod_fraction = MultiColor::splat(frac);
+    }
+
+    pub(crate) fn set_prim_lod_frac(&mut self, frac: u8) {
+        self.prim_lod_fraction = MultiColor::splat(frac as u16);
     }
 
     fn repr_comb_ptr(&self, ptr: *const MultiColor) -> String {
@@ -275,6 +398,18 @@ impl Combiner {
             "lod_fraction".into()
         } else if ptr == &self.prim_lod_fraction {
             "prim_lod_fraction".into()
+        } else if ptr == &self.combined_alpha {
+            "combined_alpha".into()
+        } else if ptr == &self.texel0_alpha {
+            "tex0_alpha".into()
+        } else if ptr == &self.texel1_alpha {
+            "tex1_alpha".into()
+        } else if ptr == &self.prim_alpha {
+            "prim_alpha".into()
+        } else if ptr == &self.shade_alpha {
+            "shade_alpha".into()
+        } else if ptr == &self.env_alpha {
+            "env_alpha".into()
         } else if ptr == &self.noise {
             "noise".into()
         } else if ptr == &self.conv_k4 {
@@ -290,17 +425,29 @@ impl Combiner {
         }
     }
 
+    fn fmt_cycle(&self, cyc: usize) -> String {
+        format!(
+            "rgb: ({}-{})*{}+{}, alpha: ({}-{})*{}+{}",
+            self.repr_comb_ptr(self.cycle_rgb[cyc].suba),
+            self.repr_comb_ptr(self.cycle_rgb[cyc].subb),
+            self.repr_comb_ptr(self.cycle_rgb[cyc].mul),
+            self.repr_comb_ptr(self.cycle_rgb[cyc].add),
+            self.repr_comb_ptr(self.cycle_alpha[cyc].suba),
+            self.repr_comb_ptr(self.cycle_alpha[cyc].subb),
+            self.repr_comb_ptr(self.cycle_alpha[cyc].mul),
+            self.repr_comb_ptr(self.cycle_alpha[cyc].add),
+        )
+    }
+
     pub(crate) fn fmt_1cycle(&self) -> String {
+        format!("Combiner {{ {} }}", self.fmt_cycle(1))
+    }
+
+    pub(crate) fn fmt_2cycle(&self) -> String {
         format!(
-            "Combiner {{ rgb: ({}-{})*{}+{}, alpha: ({}-{})*{}+{} }}",
-            self.repr_comb_ptr(self.cycle_rgb[1].suba),
-            self.repr_comb_ptr(self.cycle_rgb[1].subb),
-            self.repr_comb_ptr(self.cycle_rgb[1].mul),
-            self.repr_comb_ptr(self.cycle_rgb[1].add),
-            self.repr_comb_ptr(self.cycle_alpha[1].suba),
-            self.repr_comb_ptr(self.cycle_alpha[1].subb),
-            self.repr_comb_ptr(self.cycle_alpha[1].mul),
-            self.repr_comb_ptr(self.cycle_alpha[1].add),
+            "Combiner {{ {}; {} }}",
+            self.fmt_cycle(0),
+            self.fmt_cycle(1)
         )
     }
 }
@@ -0,0 +1,103 @@
+// Dithering
+
+extern crate bit_field;
+
+use self::bit_field::BitField;
+use super::MultiColor;
+
+const MAGIC_MATRIX: [u16; 16] = [0, 6, 1, 7, 4, 2, 5, 3, 3, 5, 2, 4, 7, 1, 6, 0];
+const BAYER_MATRIX: [u16; 16] = [0, 4, 1, 5, 4, 0, 5, 1, 3, 7, 2, 6, 7, 3, 6, 2];
+
+/// Dither stage. The RDP dithers colors when reducing them to 5 bits per
+/// component for 16-bit framebuffers: a component is rounded up if its 3 low
+/// bits are above the dither value of the pixel. The dither value comes from
+/// a 4x4 matrix (magic square or Bayer) or from the noise generator, as
+/// selected in Other Modes; a separate dither value is used for alpha.
+#[derive(Default)]
+pub(crate) struct Dither {
+    rgb_sel: u32,
+    alpha_sel: u32,
+    noise: u32,
+
+    // Dither values of the current pixel (one per component for RGB, as
+    // noise dithering uses different values).
+    rgb: [u16; 3],
+    alpha: u16,
+}
+
+/// Default seed of the noise generator.
+pub(crate) const NOISE_SEED: u32 = 1;
+
+impl Dither {
+    pub(crate) fn new() -> Dither {
+        Dither {
+            noise: NOISE_SEED,
+            ..Default::default()
+        }
+    }
+
+    /// Reseed the noise generator. The noise sequence only depends on the
+    /// seed and on the pixels drawn since, so replays are deterministic.
+    pub(crate) fn set_noise_seed(&mut self, seed: u32) {
+        self.noise = seed;
+    }
+
+    pub(crate) fn set_other_modes(&mut self, modes: u64) {
+        self.rgb_sel = modes.get_bits(38..40) as u32;
+        self.alpha_sel = modes.get_bits(36..38) as u32;
+    }
+
+    /// Return the next value of the noise generator (15 bits). This is the
+    /// linear congruential generator used by the reference software RDP
+    /// implementations, which is close enough to the hardware noise.
+    pub(crate) fn noise(&mut self) -> u16 {
+        self.noise = self.noise.wrapping_mul(214013).wrapping_add(2531011);
+        ((self.noise >> 16) & 0x7FFF) as u16
+    }
+
+    /// Compute the dither values for the pixel at the specified coordinates.
+    pub(crate) fn set_pixel(&mut self, x: i32, y: i32) {
+        let idx = (((y & 3) << 2) | (x & 3)) as usize;
+        let pattern = match self.rgb_sel {
+            0 => MAGIC_MATRIX[idx],
+            _ => BAYER_MATRIX[idx],
+        };
+
+        self.rgb = match self.rgb_sel {
+            0 | 1 => [pattern; 3],
+            2 => {
+                let n = self.noise();
+                [n & 7, (n >> 3) & 7, (n >> 6) & 7]
+            }
+            // No dithering: a component is never rounded up
+            _ => [7; 3],
+        };
+        self.alpha = match self.alpha_sel {
+            0 => pattern,
+            1 => !pattern & 7,
+            2 => self.noise() & 7,
+            _ => 0,
+        };
+    }
+
+    /// Alpha dither value of the current pixel (0-7).
+    pub(crate) fn alpha(&self) -> u16 {
+        self.alpha
+    }
+
+    /// Dither the RGB components of a color in preparation for their
+    /// truncation to 5 bits.
+    pub(crate) fn dither_rgb(&self, c: MultiColor) -> MultiColor {
+        let mut c = c;
+        for px in 0..2 {
+            for ch in 0..3 {
+                let idx = px * 4 + ch;
+                let v = c.extract(idx);
+                if v & 7 > self.rgb[ch] {
+                    c = c.replace(idx, (v + 8).min(0xFF));
+                }
+            }
+        }
+        c
+    }
+}
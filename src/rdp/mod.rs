@@ -10,7 +10,7 @@ type MultiColor = u16x8;
 pub(crate) trait MColor: Sized + Copy {
     fn from_color<CF: ColorFormat>(c: Color<CF>) -> Self;
     fn get_color<CF: ColorFormat>(&self, idx: usize) -> Color<CF>;
-    fn map_alpha(self, f: fn(u16) -> u16) -> Self;
+    fn map_alpha<F: Fn(u16) -> u16>(self, f: F) -> Self;
     fn replace_alpha(self, alpha: Self) -> Self;
     fn replicate_alpha(self) -> Self;
     fn overflown(self) -> bool;
@@ -48,7 +48,7 @@ impl MColor for MultiColor {
         }
     }
 
-    fn map_alpha(self, f: fn(u16) -> u16) -> Self {
+    fn map_alpha<F: Fn(u16) -> u16>(self, f: F) -> Self {
         let a1 = self.extract(3);
         let a2 = self.extract(7);
         self.replace(3, f(a1)).replace(7, f(a2))
@@ -78,7 +78,7 @@ pub(crate) enum CycleMode {
 }
 
 #[derive(Copy, Clone, Debug)]
-pub(crate) enum DpColorFormat {
+pub enum DpColorFormat {
     Rgba,
     Yuv,
     ColorIndex,
@@ -106,10 +106,15 @@ impl DpColorFormat {
 }
 
 mod bl;
+mod capture;
 mod cc;
+mod dither;
 mod pipeline;
 mod raster;
 mod rdp;
+mod tex;
+mod zbuf;
 
 pub use self::pipeline::PixelPipeline;
-pub use self::rdp::Rdp;
+pub use self::rdp::{ImageFormat, Rdp};
+pub use self::tex::TileDescriptor;
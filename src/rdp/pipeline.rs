@@ -1,12 +1,21 @@
+extern crate bit_field;
 extern crate emu;
+use self::bit_field::BitField;
 use super::bl::Blender;
 use super::cc::Combiner;
-use super::MultiColor;
+use super::dither::Dither;
+use super::{MColor, MultiColor};
 use emu::gfx::{Color, Rgba8888};
 
 pub struct PixelPipeline {
     cc: Combiner,
     bl: Blender,
+    dither: Dither,
+    key_en: bool,
+    two_cycle: bool,
+    fb_16bpp: bool,
+    alpha_compare: bool,
+    dither_alpha: bool,
 }
 
 impl PixelPipeline {
@@ -14,15 +23,58 @@ impl PixelPipeline {
         PixelPipeline {
             cc: Combiner::new(),
             bl: Blender::new(),
+            dither: Dither::new(),
+            key_en: false,
+            two_cycle: false,
+            fb_16bpp: false,
+            alpha_compare: false,
+            dither_alpha: false,
         }
     }
 
     #[inline(always)]
-    pub fn calc_pixels(&mut self, shade: MultiColor, fb: MultiColor) -> MultiColor {
-        self.cc.set_tex0(shade);
-        let combined = self.cc.combine_1cycle(shade);
-        let blended = self.bl.blend_1cycle(combined, shade, fb);
-        return blended;
+    pub fn calc_pixels(
+        &mut self,
+        shade: MultiColor,
+        tex0: MultiColor,
+        tex1: MultiColor,
+        fb: MultiColor,
+    ) -> Option<MultiColor> {
+        self.cc.set_tex0(tex0);
+        self.cc.set_tex1(tex1);
+        if self.cc.uses_noise() {
+            let rnd = self.dither.noise();
+            self.cc.set_noise(rnd);
+        }
+        let mut combined = if self.two_cycle {
+            self.cc.combine_2cycle(shade)
+        } else {
+            self.cc.combine_1cycle(shade)
+        };
+        if self.key_en {
+            combined = combined.replace_alpha(self.cc.key_alpha(combined));
+        }
+        if self.alpha_compare {
+            // Discard pixels whose alpha is below the blend color alpha, or
+            // below a random threshold.
+            let threshold = if self.dither_alpha {
+                self.dither.noise() & 0xFF
+            } else {
+                self.bl.blend_alpha()
+            };
+            if combined.extract(3) < threshold {
+                return None;
+            }
+        }
+        let blended = if self.two_cycle {
+            self.bl.blend_2cycle(combined, shade, fb)
+        } else {
+            self.bl.blend_1cycle(combined, shade, fb)
+        };
+        if self.fb_16bpp {
+            return Some(self.dither.dither_rgb(blended));
+        }
+        Some(blended)
     }
 
     pub fn set_combine_mode(&mut self, mode: u64) {
@@ -31,20 +83,68 @@ impl PixelPipeline {
     pub fn set_prim_color(&mut self, c: Color<Rgba8888>) {
         self.cc.set_prim(c);
     }
+    pub fn set_prim_lod_frac(&mut self, frac: u8) {
+        self.cc.set_prim_lod_frac(frac);
+    }
+    /// Prepare the pipeline for the pixel at the specified coordinates,
+    /// with the specified coverage (0-8).
+    pub fn set_pixel(&mut self, x: i32, y: i32, cvg: u8) {
+        self.dither.set_pixel(x, y);
+        self.bl.set_coverage(cvg, self.dither.alpha());
+    }
+    pub fn set_noise_seed(&mut self, seed: u32) {
+        self.dither.set_noise_seed(seed);
+    }
+    /// Whether a pixel with coverage `cvg` drawn over the framebuffer color
+    /// `fb` overflows the pixel coverage (used by the depth test).
+    pub fn cvg_overflow(&self, cvg: u8, fb: MultiColor) -> bool {
+        self.bl.coverage_overflow(cvg, fb)
+    }
+    pub fn set_color_image_bpp(&mut self, bpp: usize) {
+        self.fb_16bpp = bpp == 16;
+    }
+    pub fn set_lod_frac(&mut self, frac: u16) {
+        self.cc.set_lod_frac(frac);
+    }
     pub fn set_env_color(&mut self, c: Color<Rgba8888>) {
         self.cc.set_env(c);
     }
     pub fn set_blend_color(&mut self, c: Color<Rgba8888>) {
         self.bl.set_blend_color(c);
     }
+    pub fn set_fog_color(&mut self, c: Color<Rgba8888>) {
+        self.bl.set_fog_color(c);
+    }
+    pub fn set_key_r(&mut self, width: u16, center: u8, scale: u8) {
+        self.cc.set_key_r(width, center, scale);
+    }
+    pub fn set_key_gb(&mut self, g: (u16, u8, u8), b: (u16, u8, u8)) {
+        self.cc.set_key_gb(g, b);
+    }
+    pub fn set_convert_k45(&mut self, k4: i32, k5: i32) {
+        self.cc.set_convert_k45(k4, k5);
+    }
     pub fn set_other_modes(&mut self, modes: u64) {
+        self.key_en = modes.get_bit(40);
+        self.alpha_compare = modes.get_bit(0);
+        self.dither_alpha = modes.get_bit(1);
+        self.two_cycle = modes.get_bits(52..54) == 1;
         self.bl.set_other_modes(modes);
+        self.dither.set_other_modes(modes);
     }
 
     pub fn fmt_combiner(&self) -> String {
-        self.cc.fmt_1cycle()
+        if self.two_cycle {
+            self.cc.fmt_2cycle()
+        } else {
+            self.cc.fmt_1cycle()
+        }
     }
     pub fn fmt_blender(&self) -> String {
-        self.bl.fmt_1cycle()
+        if self.two_cycle {
+            self.bl.fmt_2cycle()
+        } else {
+            self.bl.fmt_1cycle()
+        }
     }
 }
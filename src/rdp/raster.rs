@@ -1,20 +1,91 @@
+extern crate bit_field;
 extern crate byteorder;
 extern crate emu;
 extern crate num;
-use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
+use self::bit_field::BitField;
+use self::byteorder::ByteOrder;
 use self::emu::fp::formats::*;
-use self::emu::fp::FixedPoint;
+use self::emu::fp::{FixedPoint, Q};
 use self::emu::gfx::*;
 use self::num::ToPrimitive;
 use super::pipeline::PixelPipeline;
-use super::{DpColorFormat, MColor, MultiColor};
-use std::marker::PhantomData;
+use super::tex::TexSampler;
+use super::zbuf::{z_compare, ZBuffer, ZMode, Z_MAX};
+use super::{MColor, MultiColor};
+use std::mem::size_of;
 
+/// Scissor rectangle, in integer pixels (end excluded). In interlaced field
+/// mode, either odd or even lines are skipped as well.
+#[derive(Copy, Clone, Default, Debug)]
+pub(crate) struct Scissor {
+    pub(crate) x0: i32,
+    pub(crate) y0: i32,
+    pub(crate) x1: i32,
+    pub(crate) y1: i32,
+    /// If set, only lines of this parity (true = odd) are drawn.
+    pub(crate) field: Option<bool>,
+}
+
+impl Scissor {
+    /// Return true if line `y` is within the scissor.
+    #[inline(always)]
+    pub(crate) fn line(&self, y: i32) -> bool {
+        if y < self.y0 || y >= self.y1 {
+            return false;
+        }
+        match self.field {
+            Some(odd) => (y & 1 != 0) == odd,
+            None => true,
+        }
+    }
+
+    /// Clip a range of pixels (end excluded) on a line.
+    #[inline(always)]
+    pub(crate) fn clip_x(&self, x0: i32, x1: i32) -> (i32, i32) {
+        (x0.max(self.x0), x1.min(self.x1))
+    }
+}
+
+/// Encode a fill pattern into the raw framebuffer format, so that spans can
+/// be filled with plain byte copies.
+fn fill_pattern<CF1, CF2, O1>(colors: &[Color<CF2>]) -> Vec<u8>
+where
+    CF1: ColorFormat,
+    CF2: ColorFormat,
+    O1: ByteOrder,
+{
+    let bpp = size_of::<CF1::U>();
+    let mut pattern = vec![0u8; colors.len() * bpp];
+    let pitch = pattern.len();
+    let mut buf = GfxBufferMut::<CF1, O1>::new(&mut pattern, colors.len(), 1, pitch).unwrap();
+    let mut line = buf.line(0);
+    for (x, c) in colors.iter().enumerate() {
+        line.set(x, c.cconv());
+    }
+    pattern
+}
+
+/// Fill pixels `x0..x1` of a framebuffer line (raw bytes, `bpp` bytes per
+/// pixel) with a repeating raw pattern, aligned so that pattern pixel `i`
+/// lands on the pixels with `x % len == i`.
+#[inline(always)]
+fn fill_span(line: &mut [u8], x0: usize, x1: usize, pattern: &[u8], bpp: usize) {
+    let (b0, b1) = (x0 * bpp, x1 * bpp);
+    let src = pattern.iter().cycle().skip(b0 % pattern.len());
+    for (d, s) in line[b0..b1].iter_mut().zip(src) {
+        *d = *s;
+    }
+}
+
+/// Fill a rectangle (inclusive of its lower-right corner) writing raw colors.
+/// `colors` is indexed by the X coordinate modulo its length (see
+/// `fill_triangle`).
 #[inline(always)]
 pub fn fill_rect<'a, 'b, CF1, CF2, FP1, O1>(
     dst: &mut GfxBufferMut<'a, CF1, O1>,
     dr: Rect<FP1>,
-    color: Color<CF2>,
+    colors: &[Color<CF2>],
+    scissor: &Scissor,
 ) where
     CF1: ColorFormat,
     CF2: ColorFormat,
@@ -22,14 +93,25 @@ pub fn fill_rect<'a, 'b, CF1, CF2, FP1, O1>(
     O1: ByteOrder,
 {
     let dr = dr.truncate();
+    let (w, h) = (dst.width() as i32, dst.height() as i32);
+    let (x0, x1) = scissor.clip_x(
+        dr.c0.x.floor().to_i32().unwrap(),
+        dr.c1.x.floor().to_i32().unwrap() + 1,
+    );
+    let (x0, x1) = (x0.max(0), x1.min(w));
+    if x0 >= x1 {
+        return;
+    }
+    let pattern = fill_pattern::<CF1, CF2, O1>(colors);
+    let bpp = size_of::<CF1::U>();
+    let (mem, pitch) = dst.raw();
 
-    for dy in dr.c0.y.floor()..=dr.c1.y.floor() {
-        let mut dst = dst.line(dy.to_usize().unwrap());
-
-        for dx in dr.c0.x.floor()..=dr.c1.x.floor() {
-            let didx = dx.to_usize().unwrap();
-            dst.set(didx, color.cconv());
+    for dy in dr.c0.y.floor().to_i32().unwrap()..=dr.c1.y.floor().to_i32().unwrap() {
+        if !scissor.line(dy) || dy < 0 || dy >= h {
+            continue;
         }
+        let line = &mut mem[dy as usize * pitch..];
+        fill_span(line, x0 as usize, x1 as usize, &pattern, bpp);
     }
 }
 
@@ -37,6 +119,7 @@ pub fn fill_rect_pp<'a, 'b, CF1, CF2, FP1, O1>(
     dst: &mut GfxBufferMut<'a, CF1, O1>,
     dr: Rect<FP1>,
     color: Color<CF2>,
+    scissor: &Scissor,
     pp: &mut PixelPipeline,
 ) where
     CF1: ColorFormat,
@@ -46,14 +129,28 @@ pub fn fill_rect_pp<'a, 'b, CF1, CF2, FP1, O1>(
 {
     let dr = dr.truncate();
     let color = MultiColor::from_color(color);
-    let black = MultiColor::from_color(Color::<Rgba8888>::new_clamped(0, 0, 0, 0xff));
+    let (w, h) = (dst.width() as i32, dst.height() as i32);
+    let (x0, x1) = scissor.clip_x(
+        dr.c0.x.floor().to_i32().unwrap(),
+        dr.c1.x.floor().to_i32().unwrap(),
+    );
 
-    for dy in dr.c0.y.floor()..dr.c1.y.floor() {
-        let mut dst = dst.line(dy.to_usize().unwrap());
+    for dy in dr.c0.y.floor().to_i32().unwrap()..dr.c1.y.floor().to_i32().unwrap() {
+        if !scissor.line(dy) || dy >= h {
+            continue;
+        }
+        let mut dst = dst.line(dy as usize);
 
-        for dx in dr.c0.x.floor()..dr.c1.x.floor() {
-            let didx = dx.to_usize().unwrap();
-            let cres = pp.calc_pixels(color, black);
+        for dx in x0.max(0)..x1.min(w) {
+            let didx = dx as usize;
+            // The current pixel (color and coverage) is fed to the blender,
+            // which uses it if image read is enabled.
+            let fb: Color<Rgba8888> = dst.get(didx).cconv();
+            pp.set_pixel(dx, dy, 8);
+            let cres = match pp.calc_pixels(color, color, color, MultiColor::from_color(fb)) {
+                Some(c) => c,
+                None => continue,
+            };
             if cres.overflown() {
                 panic!(format!(
                     "pixel pipeline returned overflown pixel: {:?}",
@@ -65,6 +162,585 @@ pub fn fill_rect_pp<'a, 'b, CF1, CF2, FP1, O1>(
     }
 }
 
+/// Edge coefficients of a triangle command. These are the first four words
+/// shared by all triangle opcodes, and describe the three edges (major "H",
+/// middle "M" and low "L") that the edge walker steps through one scanline
+/// at a time.
+#[derive(Copy, Clone, Default, Debug)]
+pub(crate) struct EdgeCoeffs {
+    pub(crate) lft: bool,
+    pub(crate) level: usize,
+    pub(crate) tile: usize,
+    pub(crate) yl: Q<I30F2>,
+    pub(crate) ym: Q<I30F2>,
+    pub(crate) yh: Q<I30F2>,
+    pub(crate) xl: Q<I16F16>,
+    pub(crate) dxldy: Q<I16F16>,
+    pub(crate) xh: Q<I16F16>,
+    pub(crate) dxhdy: Q<I16F16>,
+    pub(crate) xm: Q<I16F16>,
+    pub(crate) dxmdy: Q<I16F16>,
+}
+
+/// A horizontal span produced by the edge walker.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Span {
+    /// Scanline of the span.
+    pub(crate) y: i32,
+    /// First pixel of the span.
+    pub(crate) x0: i32,
+    /// First pixel after the end of the span.
+    pub(crate) x1: i32,
+    /// Position of the major edge on this scanline; attributes are
+    /// interpolated starting from here.
+    pub(crate) xmajor: Q<I16F16>,
+    /// Number of scanlines walked since the top of the triangle.
+    pub(crate) line: i32,
+    /// Left and right edge (16.16) on each of the 4 subscanlines, used to
+    /// compute coverage. Empty subscanlines have left >= right.
+    pub(crate) sub: [(i32, i32); 4],
+}
+
+impl Span {
+    /// Compute the coverage of pixel `x`, as the number of covered sample
+    /// points (0-8). There are two sample points on each subscanline,
+    /// staggered between even and odd subscanlines.
+    #[inline(always)]
+    pub(crate) fn coverage(&self, x: i32) -> u8 {
+        let mut cvg = 0;
+        for (k, &(l, r)) in self.sub.iter().enumerate() {
+            let offs = if k & 1 == 0 {
+                [0x4000, 0xC000]
+            } else {
+                [0x0000, 0x8000]
+            };
+            for o in offs.iter() {
+                let sx = (x << 16) + o;
+                if sx >= l && sx < r {
+                    cvg += 1;
+                }
+            }
+        }
+        cvg
+    }
+
+    /// Range of pixels (end excluded) whose sample points are all covered,
+    /// so that their coverage is known without testing each sample.
+    #[inline(always)]
+    pub(crate) fn full_range(&self) -> (i32, i32) {
+        if self.sub.iter().any(|&(l, r)| l >= r) {
+            return (0, 0);
+        }
+        let l = self.sub.iter().map(|&(l, _)| l).max().unwrap();
+        let r = self.sub.iter().map(|&(_, r)| r).min().unwrap();
+        // The rightmost sample point of a pixel is at +0.75.
+        ((l + 0xFFFF) >> 16, ((r - 0xC001) >> 16) + 1)
+    }
+
+    /// Range of pixels (end excluded) that might be partially covered.
+    pub(crate) fn covered_range(&self) -> (i32, i32) {
+        let mut x0 = self.x0;
+        let mut x1 = self.x1;
+        for &(l, r) in self.sub.iter().filter(|&&(l, r)| l < r) {
+            x0 = x0.min(l >> 16);
+            x1 = x1.max((r + 0xFFFF) >> 16);
+        }
+        (x0, x1)
+    }
+}
+
+#[inline(always)]
+fn sext(v: u64, bits: usize) -> i32 {
+    ((v as i32) << (32 - bits)) >> (32 - bits)
+}
+
+impl EdgeCoeffs {
+    pub(crate) fn decode(cmd: &[u64]) -> EdgeCoeffs {
+        EdgeCoeffs {
+            lft: cmd[0].get_bit(55),
+            level: cmd[0].get_bits(51..54) as usize,
+            tile: cmd[0].get_bits(48..51) as usize,
+            yl: Q::from_bits(sext(cmd[0].get_bits(32..46), 14)),
+            ym: Q::from_bits(sext(cmd[0].get_bits(16..30), 14)),
+            yh: Q::from_bits(sext(cmd[0].get_bits(0..14), 14)),
+            xl: Q::from_bits(cmd[1].get_bits(32..64) as i32),
+            dxldy: Q::from_bits(cmd[1].get_bits(0..32) as i32),
+            xh: Q::from_bits(cmd[2].get_bits(32..64) as i32),
+            dxhdy: Q::from_bits(cmd[2].get_bits(0..32) as i32),
+            xm: Q::from_bits(cmd[3].get_bits(32..64) as i32),
+            dxmdy: Q::from_bits(cmd[3].get_bits(0..32) as i32),
+        }
+    }
+
+    /// Count the spans and pixels that the triangle covers within the
+    /// scissor.
+    pub(crate) fn count_pixels(&self, scissor: &Scissor) -> (i64, i64) {
+        let (mut lines, mut pixels) = (0, 0);
+        self.for_each_span(scissor, |span| {
+            let (x0, x1) = span.covered_range();
+            lines += 1;
+            pixels += (x1 - x0).max(0) as i64;
+        });
+        (lines, pixels)
+    }
+
+    /// Walk the triangle edges and call `f` for each non-empty horizontal
+    /// span, clipped against the scissor.
+    ///
+    /// XH and XM are the edge positions at the scanline containing YH,
+    /// while XL is the position at the scanline containing YM.
+    pub(crate) fn for_each_span<F: FnMut(&Span)>(&self, scissor: &Scissor, mut f: F) {
+        let yh = self.yh.floor();
+        let ym = self.ym.floor();
+        let yl = self.yl.ceil();
+
+        let mut xh = self.xh;
+        let mut xm = self.xm;
+        let mut xl = self.xl;
+        let (sl, sr) = (scissor.x0 << 16, scissor.x1 << 16);
+        for y in yh..yl {
+            let xminor = if y < ym { xm } else { xl };
+            let (x0, x1) = if self.lft {
+                (xh.floor(), xminor.floor())
+            } else {
+                (xminor.floor(), xh.floor())
+            };
+            let (x0, x1) = scissor.clip_x(x0, x1);
+
+            // Edges on the 4 subscanlines (Y coordinates are 10.2, so
+            // they can start and end within a scanline).
+            let mut sub = [(0, 0); 4];
+            for k in 0..4 {
+                let sy = y * 4 + k as i32;
+                if sy < self.yh.bits() || sy >= self.yl.bits() {
+                    continue;
+                }
+                let major = xh.bits() + (self.dxhdy.bits() >> 2) * k as i32;
+                let minor = if sy < self.ym.bits() {
+                    xm.bits() + (self.dxmdy.bits() >> 2) * k as i32
+                } else {
+                    xl.bits() + (self.dxldy.bits() >> 2) * k as i32
+                };
+                let (l, r) = if self.lft {
+                    (major, minor)
+                } else {
+                    (minor, major)
+                };
+                sub[k] = (l.max(sl), r.min(sr));
+            }
+
+            let visible = scissor.line(y);
+            if visible && (x0 < x1 || sub.iter().any(|&(l, r)| l < r)) {
+                f(&Span {
+                    y,
+                    x0,
+                    x1,
+                    xmajor: xh,
+                    line: y - yh,
+                    sub,
+                });
+            }
+
+            xh = xh + self.dxhdy;
+            if y < ym {
+                xm = xm + self.dxmdy;
+            } else {
+                xl = xl + self.dxldy;
+            }
+        }
+    }
+}
+
+/// Coefficients of a block of four interpolated attributes, as found in
+/// triangle commands (shade RGBA, or texture S/T/W). Values are s15.16 and
+/// refer to the top of the major edge; each one comes with its slope along
+/// X, along the major edge, and along Y.
+#[derive(Copy, Clone, Default, Debug)]
+pub(crate) struct AttrCoeffs {
+    pub(crate) val: [i32; 4],
+    pub(crate) dx: [i32; 4],
+    pub(crate) de: [i32; 4],
+    pub(crate) dy: [i32; 4],
+}
+
+impl AttrCoeffs {
+    /// Decode an 8-word coefficient block. The integer and fractional parts
+    /// of each value are stored in separate words.
+    pub(crate) fn decode(cmd: &[u64]) -> AttrCoeffs {
+        let mut c = AttrCoeffs::default();
+        for i in 0..4 {
+            let bits = 48 - i * 16..64 - i * 16;
+            let fixed = |int: usize, frac: usize| {
+                ((cmd[int].get_bits(bits.clone()) << 16) | cmd[frac].get_bits(bits.clone())) as i32
+            };
+            c.val[i] = fixed(0, 2);
+            c.dx[i] = fixed(1, 3);
+            c.de[i] = fixed(4, 6);
+            c.dy[i] = fixed(5, 7);
+        }
+        c
+    }
+
+    /// Evaluate all four attributes at pixel `x` of the specified span.
+    #[inline(always)]
+    pub(crate) fn at(&self, span: &Span, x: i32) -> [i32; 4] {
+        let dx = ((x as i64) << 16) - span.xmajor.bits() as i64;
+        let mut v = [0i32; 4];
+        for i in 0..4 {
+            v[i] = self.val[i]
+                .wrapping_add(self.de[i].wrapping_mul(span.line))
+                .wrapping_add(((self.dx[i] as i64 * dx) >> 16) as i32);
+        }
+        v
+    }
+}
+
+/// Incremental evaluation of an attribute block along a span. Moving one
+/// pixel to the right adds exactly the X slope, so this gives the same
+/// results as `AttrCoeffs::at`, without the per-pixel multiplications.
+struct AttrStepper<'a> {
+    coeffs: &'a AttrCoeffs,
+    v: [i32; 4],
+}
+
+impl<'a> AttrStepper<'a> {
+    fn new(coeffs: &'a AttrCoeffs, span: &Span, x: i32) -> AttrStepper<'a> {
+        AttrStepper {
+            coeffs,
+            v: coeffs.at(span, x),
+        }
+    }
+
+    /// Return the attributes at the current pixel, and move to the next one.
+    #[inline(always)]
+    fn next(&mut self) -> [i32; 4] {
+        let v = self.v;
+        for i in 0..4 {
+            self.v[i] = self.v[i].wrapping_add(self.coeffs.dx[i]);
+        }
+        v
+    }
+}
+
+/// Depth coefficients of a triangle command (s15.16).
+#[derive(Copy, Clone, Default, Debug)]
+pub(crate) struct ZCoeffs {
+    pub(crate) z: i32,
+    pub(crate) dzdx: i32,
+    pub(crate) dzde: i32,
+    pub(crate) dzdy: i32,
+}
+
+impl ZCoeffs {
+    pub(crate) fn decode(cmd: &[u64]) -> ZCoeffs {
+        ZCoeffs {
+            z: cmd[0].get_bits(32..64) as i32,
+            dzdx: cmd[0].get_bits(0..32) as i32,
+            dzde: cmd[1].get_bits(32..64) as i32,
+            dzdy: cmd[1].get_bits(0..32) as i32,
+        }
+    }
+
+    /// Per-pixel delta Z (DzPix), in 16-bit integer units.
+    pub(crate) fn dzpix(&self) -> u32 {
+        ((self.dzdx.wrapping_abs() as u32 + self.dzdy.wrapping_abs() as u32) >> 16).max(1)
+    }
+
+    /// Evaluate the depth at pixel `x` of the specified span.
+    #[inline(always)]
+    pub(crate) fn at(&self, span: &Span, x: i32) -> i32 {
+        let dx = ((x as i64) << 16) - span.xmajor.bits() as i64;
+        self.z
+            .wrapping_add(self.dzde.wrapping_mul(span.line))
+            .wrapping_add(((self.dzdx as i64 * dx) >> 16) as i32)
+    }
+}
+
+/// Depth buffer state for the rasterizer: the Z buffer itself, plus the
+/// compare/update enables and the Z mode from Other Modes.
+pub(crate) struct DepthTest<'a> {
+    pub(crate) zb: ZBuffer<'a>,
+    pub(crate) compare: bool,
+    pub(crate) update: bool,
+    pub(crate) mode: ZMode,
+    /// Primitive depth (s15.16) and delta Z, used in place of the
+    /// per-pixel depth if the Z source select bit is set.
+    pub(crate) prim: Option<(i32, u32)>,
+}
+
+impl<'a> DepthTest<'a> {
+    /// Convert a depth value from s15.16 to 18-bit unsigned depth.
+    #[inline(always)]
+    fn depth(z: i32) -> u32 {
+        ((z.max(0) as u32) >> 13).min(Z_MAX)
+    }
+
+    /// Return the depth and delta Z of pixel `x` of the span, either from
+    /// the primitive depth or from the triangle depth coefficients (if any).
+    #[inline(always)]
+    fn z_at(&self, zc: Option<&ZCoeffs>, span: &Span, x: i32) -> Option<(i32, u32)> {
+        match (self.prim, zc) {
+            (Some(prim), _) => Some(prim),
+            (None, Some(zc)) => Some((zc.at(span, x), zc.dzpix())),
+            (None, None) => None,
+        }
+    }
+
+    /// Run the depth test for the specified pixel, with coverage `cvg`.
+    /// Returns the coverage to draw the pixel with, or None if it must be
+    /// discarded.
+    #[inline(always)]
+    fn test(&self, x: usize, y: usize, z: i32, dz: u32, cvg: u8, overflow: bool) -> Option<u8> {
+        if !self.compare {
+            return Some(cvg);
+        }
+        let old = self.zb.get(x, y);
+        z_compare(old, Self::depth(z), dz, cvg, overflow, self.mode)
+    }
+
+    /// Update the depth buffer for a pixel that was drawn (if enabled).
+    #[inline(always)]
+    fn write(&mut self, x: usize, y: usize, z: i32, dz: u32) {
+        if self.update {
+            self.zb.set(x, y, Self::depth(z), dz);
+        }
+    }
+}
+
+/// A decoded triangle command. All triangle opcodes share the same edge
+/// coefficients, optionally followed by the shade, texture and depth
+/// coefficient blocks (in this order).
+#[derive(Copy, Clone, Default, Debug)]
+pub(crate) struct Triangle {
+    pub(crate) edges: EdgeCoeffs,
+    pub(crate) shade: Option<AttrCoeffs>,
+    pub(crate) tex: Option<AttrCoeffs>,
+    pub(crate) z: Option<ZCoeffs>,
+}
+
+impl Triangle {
+    /// Number of command words of a triangle with the specified
+    /// coefficient blocks.
+    pub(crate) fn num_words(shade: bool, tex: bool, z: bool) -> usize {
+        4 + if shade { 8 } else { 0 } + if tex { 8 } else { 0 } + if z { 2 } else { 0 }
+    }
+
+    pub(crate) fn decode(cmd: &[u64], shade: bool, tex: bool, z: bool) -> Triangle {
+        let mut tri = Triangle {
+            edges: EdgeCoeffs::decode(&cmd[0..4]),
+            ..Default::default()
+        };
+        let mut off = 4;
+        if shade {
+            tri.shade = Some(AttrCoeffs::decode(&cmd[off..off + 8]));
+            off += 8;
+        }
+        if tex {
+            tri.tex = Some(AttrCoeffs::decode(&cmd[off..off + 8]));
+            off += 8;
+        }
+        if z {
+            tri.z = Some(ZCoeffs::decode(&cmd[off..off + 2]));
+        }
+        tri
+    }
+
+    /// Build the triangle equivalent to a texture rectangle, as the RDP
+    /// draws them through the same edge walker: both edges are vertical, and
+    /// the texture coordinates (s10.5) step by DsDx along X and DtDy along Y
+    /// (both s5.10), or the opposite if `flip` is set (Texture Rectangle
+    /// Flip). The bottom and right edges are excluded.
+    pub(crate) fn from_tex_rect(
+        rect: Rect<U30F2>,
+        tile: usize,
+        s: i32,
+        t: i32,
+        dsdx: i32,
+        dtdy: i32,
+        flip: bool,
+    ) -> Triangle {
+        let x0 = Q::from_bits((rect.c0.x.bits() as i32) << 14);
+        let x1 = Q::from_bits((rect.c1.x.bits() as i32) << 14);
+        let y1 = Q::from_bits(rect.c1.y.bits() as i32);
+        let mut tex = AttrCoeffs::default();
+        tex.val[0] = s << 16;
+        tex.val[1] = t << 16;
+        if flip {
+            tex.dx[1] = dtdy << 11;
+            tex.de[0] = dsdx << 11;
+            tex.dy[0] = dsdx << 11;
+        } else {
+            tex.dx[0] = dsdx << 11;
+            tex.de[1] = dtdy << 11;
+            tex.dy[1] = dtdy << 11;
+        }
+        Triangle {
+            edges: EdgeCoeffs {
+                lft: true,
+                level: 0,
+                tile,
+                yl: y1,
+                ym: y1,
+                yh: Q::from_bits(rect.c0.y.bits() as i32),
+                xl: x1,
+                dxldy: Q::from_bits(0),
+                xh: x0,
+                dxhdy: Q::from_bits(0),
+                xm: x1,
+                dxmdy: Q::from_bits(0),
+            },
+            shade: None,
+            tex: Some(tex),
+            z: None,
+        }
+    }
+}
+
+/// Rasterize a flat triangle writing raw colors. `colors` is indexed by the
+/// X coordinate modulo its length, so that 16-bit and 8-bit framebuffers can
+/// be filled with the pixels packed in the fill color register.
+pub fn fill_triangle<'a, CF1, CF2, O1>(
+    dst: &mut GfxBufferMut<'a, CF1, O1>,
+    tri: &Triangle,
+    colors: &[Color<CF2>],
+    scissor: &Scissor,
+) where
+    CF1: ColorFormat,
+    CF2: ColorFormat,
+    O1: ByteOrder,
+{
+    let (w, h) = (dst.width() as i32, dst.height() as i32);
+    let pattern = fill_pattern::<CF1, CF2, O1>(colors);
+    let bpp = size_of::<CF1::U>();
+    let (mem, pitch) = dst.raw();
+    tri.edges.for_each_span(scissor, |span| {
+        let (x0, x1) = (span.x0.max(0), span.x1.min(w));
+        if span.y < 0 || span.y >= h || x0 >= x1 {
+            return;
+        }
+        let line = &mut mem[span.y as usize * pitch..];
+        fill_span(line, x0 as usize, x1 as usize, &pattern, bpp);
+    });
+}
+
+/// Rasterize a triangle through the pixel pipeline. If the triangle has
+/// shade coefficients, the shade color is Gouraud-interpolated across the
+/// triangle; otherwise, `color` is used as a flat shade. If it has texture
+/// coefficients, texels are sampled through `sampler` and fed into the
+/// combiner (selecting the tile per pixel if LOD is enabled). If it has depth
+/// coefficients and `depth` is present, each pixel goes through the depth
+/// test; the depth buffer is updated only for pixels that are not discarded
+/// by the pipeline (eg: by alpha compare).
+pub fn draw_triangle_pp<'a, CF1, CF2, O1>(
+    dst: &mut GfxBufferMut<'a, CF1, O1>,
+    tri: &Triangle,
+    sampler: &TexSampler,
+    mut depth: Option<DepthTest>,
+    color: Color<CF2>,
+    scissor: &Scissor,
+    pp: &mut PixelPipeline,
+) where
+    CF1: ColorFormat,
+    CF2: ColorFormat,
+    O1: ByteOrder,
+{
+    let (w, h) = (dst.width() as i32, dst.height() as i32);
+    let flat = MultiColor::from_color(color);
+
+    tri.edges.for_each_span(scissor, |span| {
+        if span.y < 0 || span.y >= h {
+            return;
+        }
+        let mut dst = dst.line(span.y as usize);
+
+        // Same span, one scanline below: used to compute the texture
+        // coordinate step along Y.
+        let next = Span {
+            line: span.line + 1,
+            xmajor: span.xmajor + tri.edges.dxhdy,
+            ..*span
+        };
+
+        let (x0, x1) = span.covered_range();
+        let (x0, x1) = (x0.max(0), x1.min(w));
+        if x0 >= x1 {
+            return;
+        }
+        let (full0, full1) = span.full_range();
+        let mut shade_it = tri.shade.as_ref().map(|c| AttrStepper::new(c, span, x0));
+        let mut tex_it = tri.tex.as_ref().map(|c| AttrStepper::new(c, span, x0));
+
+        for x in x0..x1 {
+            let shade_v = shade_it.as_mut().map(|it| it.next());
+            let tex_v = tex_it.as_mut().map(|it| it.next());
+            let cvg = if x >= full0 && x < full1 {
+                8
+            } else {
+                span.coverage(x)
+            };
+            if cvg == 0 {
+                continue;
+            }
+            let fb: Color<Rgba8888> = dst.get(x as usize).cconv();
+            let fb = MultiColor::from_color(fb);
+            let z = depth.as_ref().and_then(|d| d.z_at(tri.z.as_ref(), span, x));
+            let cvg = match (z, depth.as_ref()) {
+                (Some((z, dz)), Some(depth)) => {
+                    let overflow = pp.cvg_overflow(cvg, fb);
+                    match depth.test(x as usize, span.y as usize, z, dz, cvg, overflow) {
+                        Some(cvg) => cvg,
+                        None => continue,
+                    }
+                }
+                _ => cvg,
+            };
+            let shade = match shade_v {
+                Some(v) => MultiColor::from_color(Color::<Rgba8888>::new_clamped(
+                    v[0] >> 16,
+                    v[1] >> 16,
+                    v[2] >> 16,
+                    v[3] >> 16,
+                )),
+                None => flat,
+            };
+            let (texel0, texel1) = match (tri.tex.as_ref(), tex_v) {
+                (Some(tex), Some(v)) => {
+                    let st = sampler.project(v[0], v[1], v[2]);
+                    let mut tile = tri.edges.tile;
+                    if sampler.lod_enabled() {
+                        // Project the right and lower neighbours to compute
+                        // the level of detail.
+                        let vx = tex.at(span, x + 1);
+                        let vy = tex.at(&next, x);
+                        let st_dx = sampler.project(vx[0], vx[1], vx[2]);
+                        let st_dy = sampler.project(vy[0], vy[1], vy[2]);
+                        if let Some((lt, frac)) =
+                            sampler.lod(tile, tri.edges.level, st, st_dx, st_dy)
+                        {
+                            tile = lt;
+                            pp.set_lod_frac(frac);
+                        }
+                    }
+                    (
+                        sampler.sample(tile, st.0, st.1),
+                        sampler.sample((tile + 1) & 7, st.0, st.1),
+                    )
+                }
+                _ => (shade, shade),
+            };
+            pp.set_pixel(x, span.y, cvg);
+            let cres = match pp.calc_pixels(shade, texel0, texel1, fb) {
+                Some(c) => c,
+                None => continue,
+            };
+            if let (Some((z, dz)), Some(depth)) = (z, depth.as_mut()) {
+                depth.write(x as usize, span.y as usize, z, dz);
+            }
+            dst.set(x as usize, cres.get_color(0));
+        }
+    });
+}
+
 #[inline(always)]
 fn int_draw_rect<'a, 'b, CF1, CF2, FP1, FP2, O1, O2>(
     dst: &mut GfxBufferMut<'a, CF1, O1>,
@@ -156,90 +832,123 @@ pub fn draw_rect_scaled<'a, 'b, CF1, CF2, FP1, FP2, O1, O2>(
     int_draw_rect(dst, dr, src, sr.c0, dsdt);
 }
 
-fn draw_rect_slopes<'a, 'b, CF1, CF2, FP1, FP2, O1, O2>(
-    dst: &mut GfxBufferMut<'a, CF1, O1>,
+/// Copy-mode texture rectangle: texels are copied as-is into the framebuffer,
+/// without going through the pixel pipeline. The rectangle is inclusive of
+/// its lower-right corner. S steps along X and T along Y, unless `flip` is
+/// set (Texture Rectangle Flip), where S steps along Y and T along X. If
+/// `alpha_mask` is not zero, texels whose raw bits have no bit set in the
+/// mask are skipped (alpha threshold).
+pub fn copy_rect<'a, 'b, CF, FP1, FP2, O1, O2>(
+    dst: &mut GfxBufferMut<'a, CF, O1>,
     dr: Rect<FP1>,
-    src: &GfxBuffer<'b, CF2, O2>,
+    src: &GfxBuffer<'b, CF, O2>,
     st: Point<FP2>,
     dsdt: Point<FP2>,
+    flip: bool,
+    alpha_mask: u64,
+    scissor: &Scissor,
 ) where
-    CF1: ColorFormat,
-    CF2: ColorFormat,
+    CF: ColorFormat,
     FP1: FixedPoint,
     FP2: FixedPoint,
     O1: ByteOrder,
     O2: ByteOrder,
 {
-    int_draw_rect(dst, dr, src, st, dsdt);
-}
-
-pub(crate) struct RenderState<FPXY, FPST> {
-    pub(crate) dst_cf: DpColorFormat,
-    pub(crate) dst_bpp: usize,
-    pub(crate) src_cf: DpColorFormat,
-    pub(crate) src_bpp: usize,
-    pub(crate) phantom: PhantomData<(FPXY, FPST)>,
-}
-
-pub(crate) type DpRenderState = RenderState<U30F2, I22F10>;
-
-impl<FPXY: FixedPoint, FPST: FixedPoint> RenderState<FPXY, FPST> {
-    #[inline]
-    fn draw_rect_slopes2<CF1: ColorFormat, CF2: ColorFormat, O: ByteOrder>(
-        &self,
-        dst: (&mut [u8], usize, usize, usize),
-        dr: Rect<FPXY>,
-        src: (&[u8], usize, usize, usize),
-        st: Point<FPST>,
-        dsdt: Point<FPST>,
-    ) {
-        let mut dst = GfxBufferMut::<CF1, LittleEndian>::new(dst.0, dst.1, dst.2, dst.3).unwrap();
-        let src = GfxBuffer::<CF2, O>::new(src.0, src.1, src.2, src.3).unwrap();
-        draw_rect_slopes(&mut dst, dr, &src, st, dsdt);
-    }
-
-    #[inline]
-    fn draw_rect_slopes1<CF1: ColorFormat>(
-        &self,
-        dst: (&mut [u8], usize, usize, usize),
-        dr: Rect<FPXY>,
-        src: (&[u8], usize, usize, usize),
-        st: Point<FPST>,
-        dsdt: Point<FPST>,
-    ) {
-        match self.src_cf {
-            DpColorFormat::Intensity if self.src_bpp == 4 => {
-                self.draw_rect_slopes2::<CF1, I4, BigEndian>(dst, dr, src, st, dsdt)
+    let dr = dr.truncate();
+    let (dw, dh) = (dst.width() as i64, dst.height() as i64);
+    let (sw, sh) = (src.width() as i64, src.height() as i64);
+    let x0 = dr.c0.x.floor().to_i64().unwrap();
+    let x1 = dr.c1.x.floor().to_i64().unwrap().min(dw - 1);
+    let y0 = dr.c0.y.floor().to_i64().unwrap();
+    let y1 = dr.c1.y.floor().to_i64().unwrap().min(dh - 1);
+
+    // S and T at the beginning of the current line.
+    let mut line_st = st;
+    for dy in y0..=y1 {
+        let mut pst = line_st;
+        if flip {
+            line_st.x = line_st.x + dsdt.x;
+        } else {
+            line_st.y = line_st.y + dsdt.y;
+        }
+        if dy < 0 || !scissor.line(dy as i32) {
+            continue;
+        }
+        let mut dst = dst.line(dy as usize);
+
+        for dx in x0..=x1 {
+            let tx = pst.x.floor().to_i64().unwrap();
+            let ty = pst.y.floor().to_i64().unwrap();
+            if flip {
+                pst.y = pst.y + dsdt.y;
+            } else {
+                pst.x = pst.x + dsdt.x;
             }
-            DpColorFormat::Intensity if self.src_bpp == 8 => {
-                self.draw_rect_slopes2::<CF1, I8, BigEndian>(dst, dr, src, st, dsdt)
+            if dx < scissor.x0 as i64 || dx >= scissor.x1 as i64 {
+                continue;
             }
-            _ => panic!(
-                "unimplemented src color format: {:?}/{}",
-                self.src_cf, self.src_bpp
-            ),
-        }
-    }
-
-    pub fn draw_rect_slopes(
-        &self,
-        dst: (&mut [u8], usize, usize, usize),
-        dr: Rect<FPXY>,
-        src: (&[u8], usize, usize, usize),
-        st: Point<FPST>,
-        dsdt: Point<FPST>,
-    ) {
-        match self.dst_cf {
-            DpColorFormat::Rgba if self.dst_bpp == 32 => {
-                self.draw_rect_slopes1::<Rgb888>(dst, dr, src, st, dsdt)
+            if dx < 0 || tx < 0 || tx >= sw || ty < 0 || ty >= sh {
+                continue;
             }
-            DpColorFormat::Rgba if self.dst_bpp == 16 => {
-                self.draw_rect_slopes1::<Rgb555>(dst, dr, src, st, dsdt)
+            let c = src.line(ty as usize).get(tx as usize);
+            let bits: u64 = c.to_bits().into();
+            if alpha_mask != 0 && bits & alpha_mask == 0 {
+                continue;
             }
-            _ => panic!(
-                "unimplemented dst color format: {:?}/{}",
-                self.dst_cf, self.dst_bpp
-            ),
+            dst.set(dx as usize, c);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triangle_spans() {
+        // Right triangle with a vertical major edge at X=10 on the left, and
+        // a minor edge moving right by one pixel per scanline, for 4 lines.
+        let edges = EdgeCoeffs {
+            lft: true,
+            yh: Q::from_int(0),
+            ym: Q::from_int(4),
+            yl: Q::from_int(4),
+            xh: Q::from_int(10),
+            xm: Q::from_int(10),
+            dxmdy: Q::from_int(1),
+            ..Default::default()
+        };
+        let scissor = Scissor {
+            x0: 0,
+            y0: 0,
+            x1: 320,
+            y1: 240,
+            field: None,
+        };
+
+        let mut spans = Vec::new();
+        edges.for_each_span(&scissor, |span| {
+            spans.push((span.y, span.x0, span.x1, span.full_range()));
+        });
+        assert_eq!(
+            spans,
+            vec![
+                (0, 10, 10, (0, 0)),
+                (1, 10, 11, (10, 11)),
+                (2, 10, 12, (10, 12)),
+                (3, 10, 13, (10, 13)),
+            ]
+        );
+        assert_eq!(edges.count_pixels(&scissor), (4, 10));
+
+        // Scissoring clips the spans and skips lines.
+        let scissor = Scissor {
+            x0: 11,
+            y0: 2,
+            ..scissor
+        };
+        let mut spans = Vec::new();
+        edges.for_each_span(&scissor, |span| spans.push((span.y, span.x0, span.x1)));
+        assert_eq!(spans, vec![(2, 11, 12), (3, 11, 13)]);
+    }
+}
@@ -3,39 +3,37 @@ extern crate byteorder;
 extern crate emu;
 extern crate slog;
 use self::bit_field::BitField;
-use self::byteorder::{BigEndian, LittleEndian};
+use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
 use self::emu::bus::Device;
+use super::super::mi::{IrqMask, Mi};
 use super::super::r4300::R4300;
+use super::capture::{CaptureReader, CaptureWriter, Record};
 use super::pipeline::PixelPipeline;
-use super::raster::{draw_rect, fill_rect, fill_rect_pp, DpRenderState};
+use super::raster::{
+    copy_rect, draw_triangle_pp, fill_rect, fill_rect_pp, fill_triangle, DepthTest, Scissor,
+    Triangle,
+};
+use super::tex::{tmem_linear, tmem_store, TexSampler, TileDescriptor, TLUT_BASE};
+use super::zbuf::{ZBuffer, ZMode};
 use super::{CycleMode, DpColorFormat};
+use crate::errors::*;
 use emu::fp::formats::*;
 use emu::fp::Q;
 use emu::gfx::*;
 use emu::int::Numerics;
-use std::marker::PhantomData;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 
+/// Format and address of an image in RDRAM, as set by Set Color Image or
+/// Set Texture Image.
 #[derive(Copy, Clone, Default, Debug)]
-struct TileDescriptor {
-    color_format: DpColorFormat,
-    bpp: usize,
-    pitch: usize,
-    tmem_addr: u32,
-    palette: usize,
-    clamp: [bool; 2],
-    mirror: [bool; 2],
-    mask: [u32; 2],
-    shift: [u32; 2],
-
-    rect: Rect<U30F2>,
-}
-
-#[derive(Copy, Clone, Default, Debug)]
-struct ImageFormat {
-    color_format: DpColorFormat,
-    bpp: usize,
-    width: usize,
-    dram_addr: u32,
+pub struct ImageFormat {
+    pub color_format: DpColorFormat,
+    pub bpp: usize,
+    pub width: usize,
+    pub dram_addr: u32,
 }
 
 impl ImageFormat {
@@ -44,22 +42,68 @@ impl ImageFormat {
     }
 }
 
+/// Number of 64-bit words of the command with the specified opcode.
+fn cmd_words(op: u64) -> usize {
+    match op {
+        // Triangles: bits 2, 1 and 0 of the opcode select the optional
+        // shade, texture and depth coefficient blocks.
+        0x08...0x0F => Triangle::num_words(op & 4 != 0, op & 2 != 0, op & 1 != 0),
+        // Texture Rectangle / Texture Rectangle Flip
+        0x24 | 0x25 => 2,
+        _ => 1,
+    }
+}
+
+/// A primitive drawn in fill mode.
+enum FillShape<'a> {
+    Rect(Rect<U30F2>),
+    Triangle(&'a Triangle),
+}
+
 pub struct Rdp {
     logger: slog::Logger,
     tmem: Box<[u8]>,
-    clip: Rect<I30F2>,
+    scissor: Scissor,
     fb: ImageFormat,
     tex: ImageFormat,
+    zbuf_addr: u32, // DRAM address of the depth buffer
     tiles: [TileDescriptor; 8],
     fill_color: u32,
+    prim_min_level: u32,
+    prim_z: u32,
+    prim_dz: u32,
+    conv: [i32; 6],
     cycle_mode: CycleMode,
+    other_modes: u64,
 
     pipeline: PixelPipeline,
 
-    cmdbuf: [u64; 16],
+    // Approximate clocks spent drawing and loading TMEM, not yet
+    // accounted by the DP.
+    pipe_cycles: i64,
+    tmem_cycles: i64,
+
+    capture: Option<CaptureWriter>,
+
+    // Set while replaying a capture: the CPU is not running, so Sync Full
+    // must not raise interrupts.
+    replaying: bool,
+
+    // Number of errors (unsupported or malformed commands) in the current
+    // frame; reported at Sync Full.
+    frame_errors: usize,
+
+    cmdbuf: [u64; 22],
     cmdlen: usize,
+
+    // Last command words received, dumped along with the state when an
+    // error is reported.
+    history: VecDeque<u64>,
 }
 
+/// Number of command words kept in the history.
+const CMD_HISTORY: usize = 32;
+
 impl Rdp {
     pub fn new(logger: slog::Logger) -> Rdp {
         let mut tmem = Vec::new();
@@ -67,15 +111,27 @@ impl Rdp {
         Rdp {
             logger: logger,
             tmem: tmem.into_boxed_slice(),
-            clip: Rect::default(),
+            scissor: Scissor::default(),
             fb: ImageFormat::default(),
             tex: ImageFormat::default(),
+            zbuf_addr: 0,
             tiles: [TileDescriptor::default(); 8],
             fill_color: 0,
+            prim_min_level: 0,
+            prim_z: 0,
+            prim_dz: 0,
+            conv: [0; 6],
             cycle_mode: CycleMode::One,
+            other_modes: 0,
             pipeline: PixelPipeline::new(),
-            cmdbuf: [0u64; 16],
+            pipe_cycles: 0,
+            tmem_cycles: 0,
+            capture: None,
+            replaying: false,
+            frame_errors: 0,
+            cmdbuf: [0u64; 22],
             cmdlen: 0,
+            history: VecDeque::with_capacity(CMD_HISTORY),
         }
     }
 
@@ -88,32 +144,523 @@ impl Rdp {
             .unwrap()
     }
 
-    fn framebuffer<'s, 'r: 's>(&'s self) -> (&'r mut [u8], usize, usize, usize) {
-        let fb_mem = R4300::get_mut()
-            .bus
-            .fetch_write::<u8>(self.fb.dram_addr)
-            .mem()
-            .unwrap();
-        (fb_mem, 320, 240, self.fb.pitch())
+    /// Report a command that cannot be executed as requested. The command is
+    /// then handled with a fallback (or ignored) rather than aborting the
+    /// emulation.
+    fn report_error(&mut self, err: &str) {
+        error!(self.logger, "DP: unsupported command"; "cmd" => self.cmdbuf[0].hex(), "err" => err);
+        if self.frame_errors == 0 {
+            // Dump the state only for the first error of each frame, as
+            // errors tend to repeat for every primitive.
+            self.dump_state();
+        }
+        self.frame_errors += 1;
+    }
+
+    /// Dump the decoded RDP state and the last received commands to the log,
+    /// so that errors can be investigated (and reported) without a debugger.
+    fn dump_state(&self) {
+        error!(self.logger, "DP: state dump";
+            "other_modes" => self.other_modes.hex(),
+            "cycle_mode" => ?self.cycle_mode,
+            "color_image" => ?self.fb,
+            "texture_image" => ?self.tex,
+            "zbuf_addr" => self.zbuf_addr.hex(),
+            "scissor" => ?self.scissor,
+            "fill_color" => self.fill_color.hex(),
+            "combiner" => self.pipeline.fmt_combiner(),
+            "blender" => self.pipeline.fmt_blender());
+        for (idx, tile) in self.tiles.iter().enumerate() {
+            error!(self.logger, "DP: state dump"; "tile" => idx, "desc" => ?tile);
+        }
+        let history: Vec<String> = self.history.iter().map(|c| c.hex()).collect();
+        error!(self.logger, "DP: state dump"; "last_cmds" => history.join(" "));
+    }
+
+    /// Wrap the color image for drawing, reporting an error if it does not
+    /// fit the requested format.
+    fn fb_buffer<'a, CF: ColorFormat, O: ByteOrder>(
+        &mut self,
+        fb: (&'a mut [u8], usize, usize, usize),
+    ) -> Option<GfxBufferMut<'a, CF, O>> {
+        match GfxBufferMut::new(fb.0, fb.1, fb.2, fb.3) {
+            Ok(buf) => Some(buf),
+            Err(err) => {
+                self.report_error(&err);
+                None
+            }
+        }
+    }
+
+    /// Return the color image as (mem, width, height, pitch). The width is the
+    /// one programmed with Set Color Image; the RDP has no notion of the
+    /// framebuffer height, so it is derived from the bottom of the scissor
+    /// (nothing can be drawn below it), bounded by the available memory.
+    /// Reports an error and returns None if the color image is not in RDRAM.
+    fn framebuffer<'r>(&mut self) -> Option<(&'r mut [u8], usize, usize, usize)> {
+        let addr = self.fb.dram_addr;
+        let fb_mem = match R4300::get_mut().bus.fetch_write::<u8>(addr).mem() {
+            Some(mem) => mem,
+            None => {
+                self.report_error(&format!("color image outside of RDRAM: {:x}", addr));
+                return None;
+            }
+        };
+        let pitch = self.fb.pitch();
+        let height = if pitch == 0 {
+            0
+        } else {
+            (self.scissor.y1.max(0) as usize).min(fb_mem.len() / pitch)
+        };
+        Some((fb_mem, self.fb.width, height, pitch))
+    }
+
+    /// Return the memory of the Z image, reporting an error if it is not in
+    /// RDRAM.
+    fn zbuffer<'r>(&mut self) -> Option<&'r mut [u8]> {
+        let addr = self.zbuf_addr;
+        match R4300::get_mut().bus.fetch_write::<u8>(addr).mem() {
+            Some(mem) => Some(mem),
+            None => {
+                self.report_error(&format!("Z image outside of RDRAM: {:x}", addr));
+                None
+            }
+        }
+    }
+
+    /// Return the memory of the texture image starting at the specified byte
+    /// offset, reporting an error if it is not in RDRAM.
+    fn texture_mem<'r>(&mut self, off: usize) -> Option<&'r [u8]> {
+        let addr = self.tex.dram_addr + off as u32;
+        match R4300::get().bus.fetch_read::<u8>(addr).mem() {
+            Some(mem) => Some(mem),
+            None => {
+                self.report_error(&format!("texture image outside of RDRAM: {:x}", addr));
+                None
+            }
+        }
+    }
+
+    /// Return the approximate number of clocks spent in the pixel pipeline
+    /// and loading TMEM since the last call.
+    pub fn take_busy_cycles(&mut self) -> (i64, i64) {
+        let cycles = (self.pipe_cycles, self.tmem_cycles);
+        self.pipe_cycles = 0;
+        self.tmem_cycles = 0;
+        cycles
+    }
+
+    /// Account the time needed to draw `pixels` pixels over `lines` spans,
+    /// depending on the cycle mode: fill mode writes 64 bits per clock,
+    /// copy mode 4 pixels per clock, and two-cycle mode takes 2 clocks per
+    /// pixel.
+    fn add_draw_cycles(&mut self, lines: i64, pixels: i64) {
+        const SPAN_SETUP_CYCLES: i64 = 8;
+        let pixel_cycles = match self.cycle_mode {
+            CycleMode::Fill => pixels * self.fb.bpp as i64 / 64,
+            CycleMode::Copy => pixels / 4,
+            CycleMode::One => pixels,
+            CycleMode::Two => pixels * 2,
+        };
+        self.pipe_cycles += lines * SPAN_SETUP_CYCLES + pixel_cycles;
+    }
+
+    fn add_rect_cycles(&mut self, rect: Rect<U30F2>) {
+        let sc = self.scissor;
+        let x0 = (rect.c0.x.floor() as i32).max(sc.x0);
+        let x1 = (rect.c1.x.floor() as i32 + 1).min(sc.x1);
+        let y0 = (rect.c0.y.floor() as i32).max(sc.y0);
+        let y1 = (rect.c1.y.floor() as i32 + 1).min(sc.y1);
+        let (w, h) = ((x1 - x0).max(0) as i64, (y1 - y0).max(0) as i64);
+        self.add_draw_cycles(h, w * h);
+    }
+
+    fn draw_triangle(&mut self, tri: &Triangle) {
+        match self.cycle_mode {
+            CycleMode::Fill => self.fill_mode(FillShape::Triangle(tri)),
+            CycleMode::One | CycleMode::Two => match self.fb.bpp {
+                8 => self.shade_triangle::<I8>(tri),
+                16 => self.shade_triangle::<Abgr1555>(tri),
+                32 => self.shade_triangle::<Abgr8888>(tri),
+                bpp => self.report_error(&format!("triangle with {}bpp color image", bpp)),
+            },
+            CycleMode::Copy => self.report_error("triangle in copy mode"),
+        }
+    }
+
+    /// Shade color of primitives without shade coefficients. The fill color
+    /// register is only used in fill mode: in one/two-cycle mode, flat
+    /// primitives have a zero shade color.
+    fn flat_shade() -> Color<Rgba8888> {
+        Color::new_clamped(0, 0, 0, 0)
+    }
+
+    fn shade_triangle<CF: ColorFormat>(&mut self, tri: &Triangle) {
+        let fb = match self.framebuffer() {
+            Some(fb) => fb,
+            None => return,
+        };
+        let mut dst = match self.fb_buffer::<CF, BigEndian>(fb) {
+            Some(dst) => dst,
+            None => return,
+        };
+        // With Z source select, the primitive depth is used for every pixel
+        // (even for texture rectangles, which have no depth coefficients).
+        let z_source_sel = self.other_modes.get_bit(2);
+        let zb_mem = if tri.z.is_some() || z_source_sel {
+            match self.zbuffer() {
+                Some(mem) => Some(mem),
+                None => return,
+            }
+        } else {
+            None
+        };
+        let color = Rdp::flat_shade();
+        let sampler = TexSampler::new(
+            &self.tmem,
+            &self.tiles,
+            self.other_modes,
+            self.prim_min_level as usize,
+            self.conv,
+        );
+        let depth = if let Some(zb_mem) = zb_mem {
+            Some(DepthTest {
+                zb: ZBuffer::new(zb_mem, self.fb.width),
+                compare: self.other_modes.get_bit(4),
+                update: self.other_modes.get_bit(5),
+                mode: ZMode::from_bits(self.other_modes.get_bits(10..12)),
+                prim: if z_source_sel {
+                    Some(((self.prim_z as i32) << 16, self.prim_dz))
+                } else {
+                    None
+                },
+            })
+        } else {
+            None
+        };
+        draw_triangle_pp(
+            &mut dst,
+            tri,
+            &sampler,
+            depth,
+            color,
+            &self.scissor,
+            &mut self.pipeline,
+        );
+    }
+
+    fn fill_rectangle(&mut self, mut rect: Rect<U30F2>) {
+        if rect.truncate().cast::<U30F2>() != rect {
+            self.report_error("coordinates in fill rectangle were not pixel aligned");
+            rect = rect.truncate().cast();
+        }
+        match self.cycle_mode {
+            CycleMode::Fill => self.fill_mode(FillShape::Rect(rect)),
+            CycleMode::One | CycleMode::Two => match self.fb.bpp {
+                8 => self.shade_rect::<I8>(rect),
+                16 => self.shade_rect::<Abgr1555>(rect),
+                32 => self.shade_rect::<Abgr8888>(rect),
+                bpp => self.report_error(&format!("fill rectangle with {}bpp color image", bpp)),
+            },
+            CycleMode::Copy => self.report_error("fill rectangle in copy mode"),
+        }
+    }
+
+    fn shade_rect<CF: ColorFormat>(&mut self, rect: Rect<U30F2>) {
+        let fb = match self.framebuffer() {
+            Some(fb) => fb,
+            None => return,
+        };
+        let mut dst = match self.fb_buffer::<CF, BigEndian>(fb) {
+            Some(dst) => dst,
+            None => return,
+        };
+        let color = Rdp::flat_shade();
+        fill_rect_pp(&mut dst, rect, color, &self.scissor, &mut self.pipeline);
+    }
+
+    /// Draw a primitive in fill mode. The fill color register is written
+    /// as-is, so each pixel gets the bits matching its position within the
+    /// 32-bit register (two 16-bit pixels, or four 8-bit pixels).
+    fn fill_mode(&mut self, shape: FillShape) {
+        let c = self.fill_color;
+        match self.fb.bpp {
+            8 => {
+                let c8 = |i: u32| Color::<I8>::from_bits((c >> (24 - i * 8)) as u8);
+                self.fill_mode_colors(shape, &[c8(0), c8(1), c8(2), c8(3)]);
+            }
+            16 => {
+                let c0 = Color::<Abgr1555>::from_bits((c >> 16) as u16);
+                let c1 = Color::<Abgr1555>::from_bits(c as u16);
+                self.fill_mode_colors(shape, &[c0, c1]);
+            }
+            32 => self.fill_mode_colors(shape, &[Color::<Abgr8888>::from_bits(c)]),
+            bpp => self.report_error(&format!("fill with {}bpp color image", bpp)),
+        }
+    }
+
+    fn fill_mode_colors<CF: ColorFormat>(&mut self, shape: FillShape, colors: &[Color<CF>]) {
+        let fb = match self.framebuffer() {
+            Some(fb) => fb,
+            None => return,
+        };
+        let mut dst = match self.fb_buffer::<CF, BigEndian>(fb) {
+            Some(dst) => dst,
+            None => return,
+        };
+        match shape {
+            FillShape::Rect(rect) => fill_rect(&mut dst, rect, colors, &self.scissor),
+            FillShape::Triangle(tri) => fill_triangle(&mut dst, tri, colors, &self.scissor),
+        }
+    }
+
+    fn draw_tex_rect_copy(
+        &mut self,
+        tile: usize,
+        rect: Rect<U30F2>,
+        st: Point<I22F10>,
+        dsdt: Point<I22F10>,
+        flip: bool,
+    ) {
+        let tile = self.tiles[tile];
+        if tile.pitch == 0 {
+            return;
+        }
+        let fb = match self.framebuffer() {
+            Some(fb) => fb,
+            None => return,
+        };
+        let src_h = (tile.rect.height().floor() as usize + 1).min(4096 / tile.pitch);
+        let (tmem, pitch) = tmem_linear(
+            &self.tmem,
+            tile.tmem_addr as usize,
+            tile.pitch,
+            src_h,
+            tile.bpp,
+        );
+        let tmem = &tmem[..];
+        let src_w = pitch * 8 / tile.bpp;
+
+        // Copy mode processes 4 texels per clock, so DsDx is programmed as
+        // 4.0 for a 1:1 copy.
+        let dsdt = Point::new(Q::from_bits(dsdt.x.bits() >> 2), dsdt.y);
+        let alpha_test = self.other_modes.get_bit(0);
+
+        match (self.fb.bpp, tile.bpp) {
+            (16, 16) => {
+                let mut dst = match self.fb_buffer::<Rgba5551, BigEndian>(fb) {
+                    Some(dst) => dst,
+                    None => return,
+                };
+                let src = GfxBuffer::<Rgba5551, BigEndian>::new(tmem, src_w, src_h, pitch).unwrap();
+                copy_rect(
+                    &mut dst,
+                    rect,
+                    &src,
+                    st,
+                    dsdt,
+                    flip,
+                    if alpha_test { 1 } else { 0 },
+                    &self.scissor,
+                );
+            }
+            (32, 32) => {
+                let mut dst = match self.fb_buffer::<Rgba8888, BigEndian>(fb) {
+                    Some(dst) => dst,
+                    None => return,
+                };
+                let src = GfxBuffer::<Rgba8888, BigEndian>::new(tmem, src_w, src_h, pitch).unwrap();
+                copy_rect(
+                    &mut dst,
+                    rect,
+                    &src,
+                    st,
+                    dsdt,
+                    flip,
+                    if alpha_test { 0xFF } else { 0 },
+                    &self.scissor,
+                );
+            }
+            (8, 8) => {
+                let mut dst = match self.fb_buffer::<I8, BigEndian>(fb) {
+                    Some(dst) => dst,
+                    None => return,
+                };
+                let src = GfxBuffer::<I8, BigEndian>::new(tmem, src_w, src_h, pitch).unwrap();
+                copy_rect(&mut dst, rect, &src, st, dsdt, flip, 0, &self.scissor);
+            }
+            (dst_bpp, src_bpp) => {
+                self.report_error(&format!(
+                    "unsupported bpp combination in copy mode: dst={} src={}",
+                    dst_bpp, src_bpp
+                ));
+            }
+        }
+    }
+
+    /// Start recording every incoming command word to the specified file,
+    /// with frame boundaries at Sync Full.
+    pub fn start_capture(&mut self, path: &Path) -> Result<()> {
+        self.capture = Some(CaptureWriter::create(path)?);
+        info!(self.logger, "DP: capture started"; "path" => ?path);
+        Ok(())
+    }
+
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
+    }
+
+    /// Reseed the noise generator of the pixel pipeline.
+    pub fn set_noise_seed(&mut self, seed: u32) {
+        self.pipeline.set_noise_seed(seed);
+    }
+
+    /// Feed a capture back through the rasterizer, restoring RDRAM from the
+    /// snapshot taken at the beginning of each frame. At the end of each
+    /// frame, the color image is written to `out_dir` as a PPM file.
+    /// Returns the number of frames replayed.
+    pub fn replay_capture(&mut self, path: &Path, out_dir: &Path) -> Result<usize> {
+        if self.capture.is_some() {
+            bail!("cannot replay an RDP capture while capturing");
+        }
+        self.replaying = true;
+        let res = self.replay_records(path, out_dir);
+        self.replaying = false;
+        res
+    }
+
+    fn replay_records(&mut self, path: &Path, out_dir: &Path) -> Result<usize> {
+        let mut reader = CaptureReader::open(path)?;
+        let mut frames = 0;
+        while let Some(record) = reader.next_record()? {
+            match record {
+                Record::Rdram(data) => {
+                    let rdram = match R4300::get_mut().bus.fetch_write::<u8>(0).mem() {
+                        Some(mem) => mem,
+                        None => bail!("RDRAM is not mapped at address 0"),
+                    };
+                    let n = data.len().min(rdram.len());
+                    rdram[..n].copy_from_slice(&data[..n]);
+                }
+                Record::Cmd(cmd) => self.op(cmd),
+                Record::Frame => {
+                    let out = out_dir.join(format!("frame{:04}.ppm", frames));
+                    self.dump_color_image(&out)?;
+                    frames += 1;
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    /// Write the color image to the specified file, in binary PPM format.
+    fn dump_color_image(&mut self, path: &Path) -> Result<()> {
+        let (mem, width, height, pitch) = match self.framebuffer() {
+            Some(fb) => fb,
+            None => return Ok(()),
+        };
+        let fb = (&*mem, width, height, pitch);
+        let rgb = match self.fb.bpp {
+            8 => Rdp::color_image_rgb::<I8>(fb)?,
+            16 => Rdp::color_image_rgb::<Abgr1555>(fb)?,
+            32 => Rdp::color_image_rgb::<Abgr8888>(fb)?,
+            bpp => bail!("cannot dump {}bpp color image", bpp),
+        };
+        let mut f = File::create(path).chain_err(|| "cannot create frame dump file")?;
+        write!(f, "P6\n{} {}\n255\n", width, height)?;
+        f.write_all(&rgb)?;
+        info!(self.logger, "DP: frame dumped"; "path" => ?path);
+        Ok(())
+    }
+
+    fn color_image_rgb<CF: ColorFormat>(fb: (&[u8], usize, usize, usize)) -> Result<Vec<u8>> {
+        let (width, height) = (fb.1, fb.2);
+        let buf = GfxBuffer::<CF, BigEndian>::new(fb.0, width, height, fb.3)?;
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            let line = buf.line(y);
+            for x in 0..width {
+                let c: Color<Rgb888> = line.get(x).cconv();
+                let (r, g, b, _) = c.components();
+                rgb.extend_from_slice(&[r as u8, g as u8, b as u8]);
+            }
+        }
+        Ok(rgb)
+    }
+
+    fn capture_cmd(&mut self, cmd: u64) {
+        let cap = match self.capture.as_mut() {
+            Some(cap) => cap,
+            None => return,
+        };
+        let mut res = Ok(());
+        if cap.need_rdram() {
+            res = match R4300::get().bus.fetch_read::<u8>(0).mem() {
+                Some(rdram) => cap.write_rdram(rdram),
+                None => Err("RDRAM is not mapped at address 0".into()),
+            };
+        }
+        if let Err(e) = res.and_then(|_| cap.write_cmd(cmd)) {
+            error!(self.logger, "DP: error writing capture, stopped"; "err" => %e);
+            self.capture = None;
+        }
     }
 
     pub fn op(&mut self, cmd: u64) {
         info!(self.logger, "DP command"; "cmd" => cmd.hex());
+        self.capture_cmd(cmd);
+        if self.history.len() == CMD_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(cmd);
         self.cmdbuf[self.cmdlen] = cmd;
         self.cmdlen += 1;
 
+        // Accumulate words until the command is complete, then dispatch it.
         let op = self.cmdbuf[0].get_bits(56..62);
+        let nwords = cmd_words(op);
+        if self.cmdlen < nwords {
+            return;
+        }
+        self.cmdlen = 0;
+        let cmd = self.cmdbuf[0];
+
         match op {
+            0x08...0x0F => {
+                // Triangle commands: bit 2 of the opcode selects shade
+                // coefficients, bit 1 selects texture coefficients, and
+                // bit 0 selects depth coefficients.
+                let shade = op & 4 != 0;
+                let tex = op & 2 != 0;
+                let z = op & 1 != 0;
+                let tri = Triangle::decode(&self.cmdbuf[..nwords], shade, tex, z);
+                info!(self.logger, "DP: Triangle"; "op" => op, "tri" => ?tri);
+                let (lines, pixels) = tri.edges.count_pixels(&self.scissor);
+                self.add_draw_cycles(lines, pixels);
+                self.draw_triangle(&tri);
+            }
             0x2D => {
-                // Set Scissor
-                self.clip = Rect::from_bits(
+                // Set Scissor: coordinates are 10.2; bit 25 enables field
+                // mode (interlaced), where bit 24 selects whether odd or even
+                // lines are kept.
+                let clip = Rect::<I30F2>::from_bits(
                     cmd.get_bits(44..56) as i32,
                     cmd.get_bits(32..44) as i32,
                     cmd.get_bits(12..24) as i32,
                     cmd.get_bits(0..12) as i32,
                 );
-                info!(self.logger, "DP: Set Scissor"; "clip" => ?self.clip);
-                self.cmdlen = 0;
+                self.scissor = Scissor {
+                    x0: clip.c0.x.floor(),
+                    y0: clip.c0.y.floor(),
+                    x1: clip.c1.x.floor(),
+                    y1: clip.c1.y.floor(),
+                    field: if cmd.get_bit(25) {
+                        Some(cmd.get_bit(24))
+                    } else {
+                        None
+                    },
+                };
+                info!(self.logger, "DP: Set Scissor"; "scissor" => ?self.scissor);
             }
             0x3D | 0x3F => {
                 // Set Color/Texture Image
@@ -126,17 +673,42 @@ impl Rdp {
 
                 if op == 0x3F {
                     self.fb = format;
+                    self.pipeline.set_color_image_bpp(self.fb.bpp);
                     info!(self.logger, "DP: Set Color Image"; "format" => ?self.fb);
                 } else {
                     self.tex = format;
                     info!(self.logger, "DP: Set Texture Image"; "format" => ?self.tex);
                 }
-                self.cmdlen = 0;
+            }
+            0x3E => {
+                // Set Z Image
+                self.zbuf_addr = cmd.get_bits(0..26) as u32;
+                info!(self.logger, "DP: Set Z Image"; "addr" => self.zbuf_addr.hex());
+            }
+            0x29 => {
+                // Sync Full: all previous commands are complete, notify the CPU
+                info!(self.logger, "DP: Sync Full");
+                if !self.replaying {
+                    Mi::get_mut().set_irq_line(IrqMask::DP, true);
+                }
+                if self.frame_errors != 0 {
+                    warn!(self.logger, "DP: errors in frame"; "count" => self.frame_errors);
+                    self.frame_errors = 0;
+                }
+                if let Some(cap) = self.capture.as_mut() {
+                    if let Err(e) = cap.write_frame() {
+                        error!(self.logger, "DP: error writing capture, stopped"; "err" => %e);
+                        self.capture = None;
+                    }
+                }
             }
             0x28 => {
                 // Sync Tile
                 info!(self.logger, "DP: Sync Tile");
-                self.cmdlen = 0;
+            }
+            0x00 | 0x26 | 0x27 => {
+                // No Op, Sync Load, Sync Pipe: commands are executed in
+                // order, so there is nothing to wait for.
             }
             0x2F => {
                 // Set Other Modes
@@ -147,22 +719,22 @@ impl Rdp {
                     3 => CycleMode::Fill,
                     _ => unreachable!(),
                 };
+                self.other_modes = cmd;
                 self.pipeline.set_other_modes(cmd);
                 warn!(self.logger, "DP: Set Other Modes"; "blender" => self.pipeline.fmt_blender());
-                self.cmdlen = 0;
             }
-            0x24 => {
-                // Texture rectangle (2 words)
-                if self.cmdlen != 2 {
-                    return;
-                }
-
+            0x24 | 0x25 => {
+                // Texture Rectangle / Texture Rectangle Flip (2 words). The
+                // flipped variant swaps the texture axes: S steps along Y and
+                // T along X.
+                let flip = op == 0x25;
                 let tile = self.cmdbuf[0].get_bits(24..27) as usize;
                 let x1 = self.cmdbuf[0].get_bits(44..56) as u32;
                 let y1 = self.cmdbuf[0].get_bits(32..44) as u32;
                 let x0 = self.cmdbuf[0].get_bits(12..24) as u32;
                 let y0 = self.cmdbuf[0].get_bits(0..12) as u32;
-                let mut rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
+                let rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
+                self.add_rect_cycles(rect);
 
                 let s = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(48..64) as i16);
                 let t = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(32..48) as i16);
@@ -171,38 +743,70 @@ impl Rdp {
 
                 let ptex = Point::new(s, t);
                 let slope = Point::new(dsdx, dtdy);
-                info!(self.logger, "DP: Textured Rectangle"; "idx" => tile, "tile" => ?self.tiles[tile], "screen" => ?rect, "ptex" => ?ptex, "slope" => ?slope);
+                info!(self.logger, "DP: Textured Rectangle"; "flip" => flip, "idx" => tile, "tile" => ?self.tiles[tile], "screen" => ?rect, "ptex" => ?ptex, "slope" => ?slope);
 
-                let tmem_addr = self.tiles[tile].tmem_addr as usize;
-                let tmem_pitch = self.tiles[tile].pitch;
-                let tex_rect = self.tiles[tile].rect;
-                let src = (
-                    &self.tmem[tmem_addr..],
-                    tex_rect.width().floor() as usize + 1,
-                    tex_rect.height().floor() as usize + 1,
-                    tmem_pitch,
-                );
+                match self.cycle_mode {
+                    CycleMode::Copy => {
+                        self.draw_tex_rect_copy(tile, rect, ptex.cast(), slope.cast(), flip);
+                    }
+                    CycleMode::One | CycleMode::Two => {
+                        // Go through the triangle rasterizer so that texels
+                        // are filtered and fed to the pixel pipeline.
+                        let tri = Triangle::from_tex_rect(
+                            rect,
+                            tile,
+                            s.bits() as i32,
+                            t.bits() as i32,
+                            dsdx.bits() as i32,
+                            dtdy.bits() as i32,
+                            flip,
+                        );
+                        self.draw_triangle(&tri);
+                    }
+                    CycleMode::Fill => {
+                        self.report_error("Texture Rectangle in fill mode");
+                    }
+                }
+            }
+            0x33 => {
+                // Load Block
+                let tile = cmd.get_bits(24..27) as usize;
+                let sl = cmd.get_bits(44..56) as usize;
+                let tl = cmd.get_bits(32..44) as usize;
+                let sh = cmd.get_bits(12..24) as usize;
+                let dxt = cmd.get_bits(0..12) as u32;
+                info!(self.logger, "DP: Load Block"; "idx" => tile, "sl" => sl, "tl" => tl, "sh" => sh, "dxt" => dxt.hex());
 
-                let mut fb_writer = R4300::get_mut().bus.fetch_write::<u8>(self.fb.dram_addr);
-                let fb_mem = fb_writer.mem().unwrap();
-                let dst = (fb_mem, 320, 240, self.fb.pitch());
-
-                // FIXME: draw_rect_slopes() use inclusive rectangles... maybe we need clipping?
-                let w = rect.width() - 1;
-                let h = rect.height() - 1;
-                rect.set_width(w);
-                rect.set_height(h);
-
-                let state = DpRenderState {
-                    dst_cf: self.fb.color_format,
-                    dst_bpp: self.fb.bpp,
-                    src_cf: self.tiles[tile].color_format,
-                    src_bpp: self.tiles[tile].bpp,
-                    phantom: PhantomData,
+                // Load_Block also updates the internal tile rect
+                self.tiles[tile].rect =
+                    Rect::from_bits(sl as u32 * 4, tl as u32 * 4, sh as u32 * 4, tl as u32 * 4);
+
+                // Load Block copies a linear run of texels, one 64-bit word at
+                // a time. DXT (1.11 fixed point) is the increment of the line
+                // counter for each word: the RDP uses it to know when a new
+                // texture line begins, as words in odd lines are stored with
+                // their 32-bit halves swapped.
+                let bpp = self.tex.bpp;
+                let src_off = (tl * self.tex.width + sl) * bpp / 8;
+                let nbytes = ((sh - sl.min(sh) + 1) * bpp / 8 + 7) & !7;
+
+                let tex_mem = match self.texture_mem(src_off) {
+                    Some(mem) => mem,
+                    None => return,
                 };
-                state.draw_rect_slopes(dst, rect, src, ptex.cast(), slope.cast());
+                let nbytes = nbytes.min(tex_mem.len() & !7);
 
-                self.cmdlen = 0;
+                // 32-bit texels are split across the two TMEM halves, so each
+                // word only takes 4 bytes in each half.
+                let tmem_addr = self.tiles[tile].tmem_addr as usize;
+                let word_size = if bpp == 32 { 4 } else { 8 };
+                let mut t: u32 = 0;
+                for (i, word) in tex_mem[..nbytes].chunks(8).enumerate() {
+                    let line = (t >> 11) as usize;
+                    tmem_store(&mut self.tmem, tmem_addr + i * word_size, line, word, bpp);
+                    t = t.wrapping_add(dxt);
+                }
+                self.tmem_cycles += nbytes as i64 / 8;
             }
             0x34 => {
                 // Load Tile
@@ -216,68 +820,82 @@ impl Rdp {
 
                 // Load_Tile also updates the internal tile rect
                 self.tiles[tile].rect = rect;
+                if s1 < s0 || t1 < t0 {
+                    self.report_error(&format!("Load Tile: inverted rect: {:?}", rect));
+                    return;
+                }
 
                 let tmem_addr = self.tiles[tile].tmem_addr as usize;
                 let tmem_pitch = self.tiles[tile].pitch;
-                let tex_reader = R4300::get().bus.fetch_read::<u8>(self.tex.dram_addr);
-                let tex_mem = tex_reader.mem().unwrap();
+                let tex_mem = match self.texture_mem(0) {
+                    Some(mem) => mem,
+                    None => return,
+                };
                 let width = rect.width().floor() as usize + 1;
                 let height = rect.height().floor() as usize + 1;
 
                 let copy_width = width.min(self.tex.width); // FIXME: is this correct? See RDPI4Decode
+                if copy_width == 0 {
+                    return;
+                }
                 rect.set_width(Q::from_int(copy_width as u32 - 1));
 
-                info!(self.logger, "DP: Load Tile: draw_rect"; "rect" => ?rect, "copy_width" => copy_width);
-                if self.tiles[tile].bpp == 16 && self.tex.bpp == 16 {
-                    let mut tmem = GfxBufferMutLE::<Rgba5551>::new(
-                        &mut self.tmem[tmem_addr..],
-                        copy_width,
-                        height,
-                        tmem_pitch,
-                    )
-                    .unwrap();
-
-                    let tex = GfxBufferLE::<Rgba5551>::new(
-                        &tex_mem,
-                        copy_width,
-                        height,
-                        self.tex.pitch(),
-                    )
-                    .unwrap();
-
-                    draw_rect(
-                        &mut tmem,
-                        Point::<U30F2>::from_int(0, 0),
-                        &tex,
-                        rect.cast::<U27F5>(),
-                    );
-                } else if self.tiles[tile].bpp == 8 && self.tex.bpp == 8 {
-                    let mut tmem = GfxBufferMutLE::<I8>::new(
-                        &mut self.tmem[tmem_addr..],
-                        copy_width,
-                        height,
-                        tmem_pitch,
-                    )
-                    .unwrap();
-
-                    let tex =
-                        GfxBufferLE::<I8>::new(&tex_mem, copy_width, height, self.tex.pitch())
-                            .unwrap();
-
-                    draw_rect(
-                        &mut tmem,
-                        Point::<U30F2>::from_int(0, 0),
-                        &tex,
-                        rect.cast::<U27F5>(),
-                    );
-                } else {
-                    panic!(
-                        "unknown src/dst bpp combination in load tile: dst={} src={}",
-                        self.tiles[tile].bpp, self.tex.bpp,
-                    );
+                info!(self.logger, "DP: Load Tile: copy"; "rect" => ?rect, "copy_width" => copy_width);
+                // The transfer always follows the texel size of the texture
+                // image: the tile size only matters when sampling, so
+                // textures can be loaded with a different size than the one
+                // used to draw them (eg: 4-bit textures loaded as 8-bit).
+                let bpp = self.tex.bpp;
+                if self.tiles[tile].bpp != bpp {
+                    info!(self.logger, "DP: Load Tile: size mismatch"; "tile_bpp" => self.tiles[tile].bpp, "tex_bpp" => bpp);
                 }
 
-                self.cmdlen = 0;
+                // Copy line by line, storing odd lines with swapped words (and
+                // 32-bit texels split across the TMEM halves). 4-bit texels are
+                // copied packed, starting from the byte containing the first
+                // texel.
+                let s0 = rect.c0.x.floor() as usize;
+                let t0 = rect.c0.y.floor() as usize;
+                let line_bytes = ((s0 + copy_width) * bpp + 7) / 8 - s0 * bpp / 8;
+                for t in 0..height {
+                    let src = ((t0 + t) * self.tex.width + s0) * bpp / 8;
+                    let dst = tmem_addr + t * tmem_pitch;
+                    if src + line_bytes > tex_mem.len() {
+                        break;
+                    }
+                    tmem_store(&mut self.tmem, dst, t, &tex_mem[src..src + line_bytes], bpp);
+                    self.tmem_cycles += (line_bytes as i64 + 7) / 8;
+                }
+            }
+            0x30 => {
+                // Load TLUT
+                let tile = cmd.get_bits(24..27) as usize;
+                let sl = cmd.get_bits(44..56) as usize >> 2;
+                let tl = cmd.get_bits(32..44) as usize >> 2;
+                let sh = cmd.get_bits(12..24) as usize >> 2;
+                info!(self.logger, "DP: Load TLUT"; "idx" => tile, "sl" => sl, "tl" => tl, "sh" => sh);
+
+                // Palette entries are 16-bit, and each one is stored four
+                // times in the high half of TMEM (once per bank).
+                let src_off = (tl * self.tex.width + sl) * 2;
+                let tex_mem = match self.texture_mem(src_off) {
+                    Some(mem) => mem,
+                    None => return,
+                };
+                let count = (sh - sl.min(sh) + 1).min(tex_mem.len() / 2);
+
+                let tmem_addr = (self.tiles[tile].tmem_addr as usize) | TLUT_BASE;
+                for (i, entry) in tex_mem[..count * 2].chunks(2).enumerate() {
+                    for j in 0..4 {
+                        let addr = (tmem_addr + i * 8 + j * 2) & 0xFFF;
+                        if addr < TLUT_BASE {
+                            break;
+                        }
+                        self.tmem[addr] = entry[0];
+                        self.tmem[addr + 1] = entry[1];
+                    }
+                }
+                self.tmem_cycles += count as i64;
             }
             0x35 => {
                 // Set Tile
@@ -298,84 +916,151 @@ impl Rdp {
                 tile.shift[0] = cmd.get_bits(0..4) as u32;
                 tile.shift[1] = cmd.get_bits(10..14) as u32;
                 info!(self.logger, "DP: Set Tile"; "idx" => idx, "format" => ?tile);
-                self.cmdlen = 0;
             }
             0x36 => {
                 let x1 = cmd.get_bits(44..56) as u32;
                 let y1 = cmd.get_bits(32..44) as u32;
                 let x0 = cmd.get_bits(12..24) as u32;
                 let y0 = cmd.get_bits(0..12) as u32;
-                let mut rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
+                let rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
                 info!(self.logger, "DP: Fill Rectangle"; "rect" => ?rect);
+                self.add_rect_cycles(rect);
 
-                match self.cycle_mode {
-                    CycleMode::Fill => {
-                        // Fill rectangle works with 32-bit packed words. Thus, we treat everything
-                        // as RGBA8888, but we need to convert the rect coordinates to adjust them
-                        // to a fake 32-bit resolution.
-                        let bppconv = 32 / self.fb.bpp as u32;
-
-                        rect.c0.x /= bppconv;
-                        rect.c0.y /= bppconv;
-                        rect.c1.x = ((rect.c1.x + 1) / bppconv) - 1;
-                        rect.c1.y = ((rect.c1.y + 1) / bppconv) - 1;
-
-                        if rect.truncate().cast::<U30F2>() != rect {
-                            panic!("Coordinates in DP Fill Rectangle were not 32-bit aligned");
-                        }
-
-                        let fb = self.framebuffer();
-                        let mut dst = GfxBufferMut::<Rgba8888, BigEndian>::new(
-                            fb.0,
-                            fb.1 / bppconv as usize,
-                            fb.2,
-                            fb.3,
-                        )
-                        .unwrap();
-                        let color = Color::<Rgba8888>::from_bits(self.fill_color);
-                        fill_rect(&mut dst, rect, color);
-                    }
-                    CycleMode::One => {
-                        let fb = self.framebuffer();
-                        let mut dst =
-                            GfxBufferMut::<Rgba8888, LittleEndian>::new(fb.0, fb.1, fb.2, fb.3)
-                                .unwrap();
-
-                        if rect.truncate().cast::<U30F2>() != rect {
-                            panic!("Coordinates in DP Fill Rectangle were not 32-bit aligned");
-                        }
-
-                        let color = Color::<Abgr8888>::from_bits(self.fill_color); // FIXME: this is probably not correct
-                        fill_rect_pp(&mut dst, rect, color, &mut self.pipeline);
-                    }
-                    _ => unimplemented!(),
-                }
-                self.cmdlen = 0;
+                self.fill_rectangle(rect);
+            }
+            0x2E => {
+                // Set Prim Depth
+                self.prim_z = cmd.get_bits(16..32) as u32 & 0x7FFF;
+                self.prim_dz = cmd.get_bits(0..16) as u32;
+                info!(self.logger, "DP: Set Prim Depth"; "z" => self.prim_z.hex(), "dz" => self.prim_dz.hex());
             }
             0x37 => {
                 let color = cmd.get_bits(0..32) as u32;
                 info!(self.logger, "DP: Set Fill Color"; "color" => color.hex());
                 self.fill_color = color;
-                self.cmdlen = 0;
             }
             0x3C => {
                 // Set Combine Mode
                 self.pipeline.set_combine_mode(cmd);
                 info!(self.logger, "DP: Set Combine Mode"; "cmd" => cmd.hex(), "cc" => self.pipeline.fmt_combiner());
-                self.cmdlen = 0;
+            }
+            0x3A => {
+                // Set Prim Color
+                let c = Color::<Abgr8888>::from_bits(cmd as u32);
+                let lod_frac = cmd.get_bits(32..40) as u8;
+                self.prim_min_level = cmd.get_bits(40..45) as u32;
+                self.pipeline.set_prim_color(c.cconv());
+                self.pipeline.set_prim_lod_frac(lod_frac);
+                info!(self.logger, "DP: Set Prim Color"; "c" => ?c, "min_level" => self.prim_min_level, "lod_frac" => lod_frac);
+            }
+            0x3B => {
+                // Set Env Color
+                let c = Color::<Abgr8888>::from_bits(cmd as u32);
+                self.pipeline.set_env_color(c.cconv());
+                info!(self.logger, "DP: Set Env Color"; "c" => ?c);
+            }
+            0x2A => {
+                // Set Key R
+                let width = cmd.get_bits(16..28) as u16;
+                let center = cmd.get_bits(8..16) as u8;
+                let scale = cmd.get_bits(0..8) as u8;
+                self.pipeline.set_key_r(width, center, scale);
+                info!(self.logger, "DP: Set Key R"; "width" => width, "center" => center, "scale" => scale);
+            }
+            0x2B => {
+                // Set Key GB
+                let g = (
+                    cmd.get_bits(44..56) as u16,
+                    cmd.get_bits(24..32) as u8,
+                    cmd.get_bits(16..24) as u8,
+                );
+                let b = (
+                    cmd.get_bits(32..44) as u16,
+                    cmd.get_bits(8..16) as u8,
+                    cmd.get_bits(0..8) as u8,
+                );
+                self.pipeline.set_key_gb(g, b);
+                info!(self.logger, "DP: Set Key GB"; "g" => ?g, "b" => ?b);
+            }
+            0x2C => {
+                // Set Convert: six 9-bit signed coefficients
+                for (i, k) in self.conv.iter_mut().enumerate() {
+                    let lsb = 45 - i * 9;
+                    *k = ((cmd.get_bits(lsb..lsb + 9) as i32) << 23) >> 23;
+                }
+                self.pipeline.set_convert_k45(self.conv[4], self.conv[5]);
+                info!(self.logger, "DP: Set Convert"; "k" => ?self.conv);
+            }
+            0x38 => {
+                // Set Fog Color
+                let c = Color::<Abgr8888>::from_bits(cmd as u32);
+                self.pipeline.set_fog_color(c.cconv());
+                info!(self.logger, "DP: Set Fog Color"; "c" => ?c);
             }
             0x39 => {
                 // Set Blend Color
                 let c = Color::<Abgr8888>::from_bits(cmd as u32);
                 self.pipeline.set_blend_color(c.cconv());
                 info!(self.logger, "DP: Set Blend Color"; "c" => ?c);
-                self.cmdlen = 0;
             }
 
             _ => {
-                warn!(self.logger, "unimplemented command"; "cmd" => (((cmd>>56)&0x3F) as u8).hex());
-                self.cmdlen = 0;
+                self.report_error(&format!("unimplemented command: {:x}", op));
             }
         };
     }
 }
+
+/// Debugger access to the RDP state, used to display a texture viewer.
+impl Rdp {
+    /// Return the 8 tile descriptors.
+    pub fn tiles(&self) -> &[TileDescriptor; 8] {
+        &self.tiles
+    }
+
+    /// Return the current color image format.
+    pub fn color_image(&self) -> ImageFormat {
+        self.fb
+    }
+
+    /// Return the current texture image format.
+    pub fn texture_image(&self) -> ImageFormat {
+        self.tex
+    }
+
+    /// Return a decoded copy of the texture described by the specified tile,
+    /// as currently stored in TMEM. Palettes are looked up according to the
+    /// current TLUT mode. An inverted tile rect yields an empty texture.
+    pub fn tile_texels(&self, tile: usize) -> OwnedGfxBuffer<Rgba8888, LittleEndian> {
+        let desc = &self.tiles[tile & 7];
+        let rect = desc.rect;
+        let (width, height) =
+            if rect.c1.x.bits() < rect.c0.x.bits() || rect.c1.y.bits() < rect.c0.y.bits() {
+                (0, 0)
+            } else {
+                (
+                    rect.width().floor() as usize + 1,
+                    rect.height().floor() as usize + 1,
+                )
+            };
+        let sampler = TexSampler::new(
+            &self.tmem,
+            &self.tiles,
+            self.other_modes,
+            self.prim_min_level as usize,
+            self.conv,
+        );
+
+        let mut texels = OwnedGfxBuffer::new(width, height);
+        {
+            let mut buf = texels.buf_mut();
+            for t in 0..height {
+                let mut line = buf.line(t);
+                for s in 0..width {
+                    line.set(s, sampler.fetch(desc, s as i32, t as i32));
+                }
+            }
+        }
+        texels
+    }
+}
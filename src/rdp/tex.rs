@@ -0,0 +1,546 @@
+// Texture sampling from TMEM
+
+extern crate emu;
+
+use super::{DpColorFormat, MColor, MultiColor};
+use bit_field::BitField;
+use emu::fp::formats::*;
+use emu::gfx::{Color, Rect, Rgba8888};
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct TileDescriptor {
+    pub color_format: DpColorFormat,
+    pub bpp: usize,
+    pub pitch: usize,
+    pub tmem_addr: u32,
+    pub palette: usize,
+    pub clamp: [bool; 2],
+    pub mirror: [bool; 2],
+    pub mask: [u32; 2],
+    pub shift: [u32; 2],
+
+    pub rect: Rect<U30F2>,
+}
+
+/// TMEM stores odd texture lines with their 32-bit words swapped (within
+/// each 64-bit word), so that the texture unit can fetch texels of two
+/// adjacent lines in parallel. Return the physical address of the byte at
+/// `addr` in texture line `line`.
+#[inline(always)]
+pub(crate) fn tmem_swizzle(addr: usize, line: usize) -> usize {
+    if line & 1 != 0 {
+        addr ^ 4
+    } else {
+        addr
+    }
+}
+
+/// Store a run of texels into TMEM at `addr`, as part of texture line
+/// `line`. 32-bit texels are split across the two TMEM halves: red and green
+/// are stored in the low 2KB, blue and alpha at the same offset in the high
+/// 2KB (so `addr` is an offset within a half).
+pub(crate) fn tmem_store(tmem: &mut [u8], addr: usize, line: usize, data: &[u8], bpp: usize) {
+    if bpp == 32 {
+        for (k, texel) in data.chunks(4).enumerate() {
+            for (j, b) in texel.iter().enumerate() {
+                let half = if j >= 2 { 0x800 } else { 0 };
+                let a = ((addr + k * 2 + (j & 1)) & 0x7FF) | half;
+                tmem[tmem_swizzle(a, line)] = *b;
+            }
+        }
+    } else {
+        for (i, b) in data.iter().enumerate() {
+            tmem[tmem_swizzle(addr + i, line) & 0xFFF] = *b;
+        }
+    }
+}
+
+/// Return a linear copy of a TMEM region, undoing the odd-line swap and
+/// (for 32-bit textures) the split between the TMEM halves. Returns the
+/// buffer and its pitch.
+pub(crate) fn tmem_linear(
+    tmem: &[u8],
+    addr: usize,
+    pitch: usize,
+    height: usize,
+    bpp: usize,
+) -> (Vec<u8>, usize) {
+    if bpp == 32 {
+        let mut buf = vec![0u8; pitch * 2 * height];
+        for t in 0..height {
+            for k in 0..pitch / 2 {
+                let a = (addr + t * pitch + k * 2) & 0x7FF;
+                let dst = &mut buf[t * pitch * 2 + k * 4..];
+                dst[0] = tmem[tmem_swizzle(a, t)];
+                dst[1] = tmem[tmem_swizzle(a + 1, t)];
+                dst[2] = tmem[tmem_swizzle(a | 0x800, t)];
+                dst[3] = tmem[tmem_swizzle((a + 1) | 0x800, t)];
+            }
+        }
+        (buf, pitch * 2)
+    } else {
+        let mut buf = vec![0u8; pitch * height];
+        for t in 0..height {
+            for i in 0..pitch {
+                buf[t * pitch + i] = tmem[tmem_swizzle(addr + t * pitch + i, t) & 0xFFF];
+            }
+        }
+        (buf, pitch)
+    }
+}
+
+/// Base address of the palette in TMEM. Load TLUT stores each 16-bit entry
+/// quadrupled (one copy per TMEM bank), so entries are 8 bytes apart.
+pub(crate) const TLUT_BASE: usize = 0x800;
+
+#[inline(always)]
+fn expand5(v: u16) -> i32 {
+    let v = (v & 0x1F) as i32;
+    (v << 3) | (v >> 2)
+}
+
+#[inline(always)]
+fn rgba16(v: u16) -> Color<Rgba8888> {
+    Color::new_clamped(
+        expand5(v >> 11),
+        expand5(v >> 6),
+        expand5(v >> 1),
+        if v & 1 != 0 { 0xFF } else { 0 },
+    )
+}
+
+#[inline(always)]
+fn ia16(v: u16) -> Color<Rgba8888> {
+    let i = (v >> 8) as i32;
+    Color::new_clamped(i, i, i, (v & 0xFF) as i32)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum TlutType {
+    Rgba16,
+    Ia16,
+}
+
+/// Texture filter, as selected by the sample type bit in Other Modes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum TexFilter {
+    /// Nearest texel.
+    Point,
+    /// Bilinear filter. The hardware only interpolates among 3 of the 4
+    /// texels around the sample point (picking the triangle that contains
+    /// it); in mid-texel mode, a sample exactly in the middle of the 4
+    /// texels returns their average instead.
+    Bilinear { mid_texel: bool },
+}
+
+/// Level-of-detail computation mode, as selected in Other Modes. In sharpen
+/// mode, magnified textures are extrapolated away from the next (lower
+/// resolution) level; in detail mode, the base tile holds a detail texture
+/// that is blended in when magnifying, and the mipmap chain starts at the
+/// following tile.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct LodMode {
+    pub(crate) sharpen: bool,
+    pub(crate) detail: bool,
+}
+
+pub(crate) struct TexSampler<'a> {
+    tmem: &'a [u8],
+    tiles: &'a [TileDescriptor],
+    persp: bool,
+    tlut: Option<TlutType>,
+    filter: TexFilter,
+    lod: Option<LodMode>,
+    min_level: i32,
+    conv: [i32; 6],
+}
+
+impl<'a> TexSampler<'a> {
+    pub(crate) fn new(
+        tmem: &'a [u8],
+        tiles: &'a [TileDescriptor],
+        other_modes: u64,
+        min_level: usize,
+        conv: [i32; 6],
+    ) -> TexSampler<'a> {
+        let tlut = if other_modes.get_bit(47) {
+            Some(if other_modes.get_bit(46) {
+                TlutType::Ia16
+            } else {
+                TlutType::Rgba16
+            })
+        } else {
+            None
+        };
+        TexSampler {
+            tmem,
+            tiles,
+            persp: other_modes.get_bit(51),
+            tlut,
+            filter: if other_modes.get_bit(45) {
+                TexFilter::Bilinear {
+                    mid_texel: other_modes.get_bit(44),
+                }
+            } else {
+                TexFilter::Point
+            },
+            lod: if other_modes.get_bit(48) {
+                Some(LodMode {
+                    sharpen: other_modes.get_bit(49),
+                    detail: other_modes.get_bit(50),
+                })
+            } else {
+                None
+            },
+            min_level: min_level as i32,
+            conv,
+        }
+    }
+
+    /// Convert a YUV texel to RGB using the K0-K3 coefficients programmed
+    /// with Set Convert. U and V are signed (biased by 128).
+    fn yuv_to_rgb(&self, y: i32, u: i32, v: i32) -> Color<Rgba8888> {
+        let (u, v) = (u - 128, v - 128);
+        let k = &self.conv;
+        Color::new_clamped(
+            y + ((k[0] * v + 0x40) >> 7),
+            y + ((k[1] * u + k[2] * v + 0x40) >> 7),
+            y + ((k[3] * u + 0x40) >> 7),
+            0xFF,
+        )
+    }
+
+    #[inline(always)]
+    fn tmem_u8(&self, addr: usize, t: usize) -> u8 {
+        self.tmem[tmem_swizzle(addr, t) & 0xFFF]
+    }
+
+    #[inline(always)]
+    fn tmem_u16(&self, addr: usize, t: usize) -> u16 {
+        ((self.tmem_u8(addr, t) as u16) << 8) | self.tmem_u8(addr + 1, t) as u16
+    }
+
+    /// Look up a color index through the palette. Without TLUT enabled, the
+    /// index itself is returned as an intensity.
+    fn palette(&self, idx: usize) -> Color<Rgba8888> {
+        match self.tlut {
+            Some(tt) => {
+                let v = self.tmem_u16(TLUT_BASE + (idx & 0xFF) * 8, 0);
+                match tt {
+                    TlutType::Rgba16 => rgba16(v),
+                    TlutType::Ia16 => ia16(v),
+                }
+            }
+            None => {
+                let i = idx as i32;
+                Color::new_clamped(i, i, i, i)
+            }
+        }
+    }
+
+    /// Fetch a single texel, given its integer coordinates relative to the
+    /// top-left corner of the tile.
+    pub(crate) fn fetch(&self, tile: &TileDescriptor, s: i32, t: i32) -> Color<Rgba8888> {
+        let t = t as usize;
+        let line = tile.tmem_addr as usize + t * tile.pitch;
+        let s = s as usize;
+
+        match (tile.color_format, tile.bpp) {
+            (DpColorFormat::Rgba, 16) => rgba16(self.tmem_u16(line + s * 2, t)),
+            (DpColorFormat::Rgba, 32) => {
+                // Red/green in the low half of TMEM, blue/alpha in the high half.
+                let addr = (line + s * 2) & 0x7FF;
+                Color::new_clamped(
+                    self.tmem_u8(addr, t) as i32,
+                    self.tmem_u8(addr + 1, t) as i32,
+                    self.tmem_u8(addr | 0x800, t) as i32,
+                    self.tmem_u8((addr + 1) | 0x800, t) as i32,
+                )
+            }
+            (DpColorFormat::Yuv, 16) => {
+                // Texels are stored in pairs as U, Y0, V, Y1.
+                let addr = line + (s & !1) * 2;
+                let u = self.tmem_u8(addr, t) as i32;
+                let y = self.tmem_u8(addr + 1 + (s & 1) * 2, t) as i32;
+                let v = self.tmem_u8(addr + 2, t) as i32;
+                self.yuv_to_rgb(y, u, v)
+            }
+            (DpColorFormat::Intensity, 8) => {
+                let i = self.tmem_u8(line + s, t) as i32;
+                Color::new_clamped(i, i, i, i)
+            }
+            (DpColorFormat::Intensity, 4) => {
+                let v = self.tmem_u8(line + s / 2, t);
+                let i = (if s & 1 == 0 { v >> 4 } else { v & 0xF }) as i32;
+                let i = (i << 4) | i;
+                Color::new_clamped(i, i, i, i)
+            }
+            (DpColorFormat::IntensityAlpha, 16) => ia16(self.tmem_u16(line + s * 2, t)),
+            (DpColorFormat::IntensityAlpha, 8) => {
+                let v = self.tmem_u8(line + s, t) as i32;
+                let (i, a) = (v >> 4, v & 0xF);
+                Color::new_clamped((i << 4) | i, (i << 4) | i, (i << 4) | i, (a << 4) | a)
+            }
+            (DpColorFormat::IntensityAlpha, 4) => {
+                // 3-bit intensity, 1-bit alpha
+                let v = self.tmem_u8(line + s / 2, t);
+                let v = (if s & 1 == 0 { v >> 4 } else { v & 0xF }) as i32;
+                let i = v >> 1;
+                let i = (i << 5) | (i << 2) | (i >> 1);
+                Color::new_clamped(i, i, i, if v & 1 != 0 { 0xFF } else { 0 })
+            }
+            (DpColorFormat::ColorIndex, 8) => self.palette(self.tmem_u8(line + s, t) as usize),
+            (DpColorFormat::ColorIndex, 4) => {
+                // 4-bit indices select an entry within the 16-color palette
+                // specified in the tile descriptor.
+                let v = self.tmem_u8(line + s / 2, t);
+                let idx = (if s & 1 == 0 { v >> 4 } else { v & 0xF }) as usize;
+                self.palette((tile.palette << 4) | idx)
+            }
+            _ => Color::new_clamped(0, 0, 0, 0),
+        }
+    }
+
+    /// Project the S/T/W coordinates produced by the triangle texture
+    /// coefficients (s15.16, whose integer part is itself a s10.5 texel
+    /// coordinate) into s10.5 texel coordinates, applying the perspective
+    /// correction if enabled. W is normalized so that 0x7FFF.FFFF
+    /// corresponds to 1.0.
+    pub(crate) fn project(&self, s: i32, t: i32, w: i32) -> (i32, i32) {
+        let (s, t) = if self.persp && w > 0 {
+            (
+                ((s as i64) << 31) / (w as i64),
+                ((t as i64) << 31) / (w as i64),
+            )
+        } else {
+            (s as i64, t as i64)
+        };
+        ((s >> 16) as i32, (t >> 16) as i32)
+    }
+
+    pub(crate) fn lod_enabled(&self) -> bool {
+        self.lod.is_some()
+    }
+
+    /// Compute the level of detail of a pixel, given the projected texture
+    /// coordinates of the pixel itself and of its right and lower
+    /// neighbours. Returns the tile to sample (counting from `base`, with up
+    /// to `max_level` levels) and the LOD fraction for the combiner, or
+    /// `None` if LOD is disabled.
+    ///
+    /// In sharpen mode, the fraction of magnified pixels is negative; it is
+    /// returned sign-extended, which the combiner arithmetic handles through
+    /// wrapping.
+    pub(crate) fn lod(
+        &self,
+        base: usize,
+        max_level: usize,
+        st: (i32, i32),
+        st_dx: (i32, i32),
+        st_dy: (i32, i32),
+    ) -> Option<(usize, u16)> {
+        let mode = self.lod?;
+
+        // LOD is the largest texel step (10.5) to the neighbouring pixels.
+        let mut lod = (st_dx.0 - st.0)
+            .abs()
+            .max((st_dx.1 - st.1).abs())
+            .max((st_dy.0 - st.0).abs())
+            .max((st_dy.1 - st.1).abs());
+        if lod >= 0x4000 {
+            lod = 0x7FFF;
+        }
+        let lod = lod.max(self.min_level);
+
+        let magnify = lod < 0x20;
+        let l_tile = if magnify {
+            0
+        } else {
+            31 - ((lod >> 5) as u32).leading_zeros() as usize
+        };
+        let distant = lod & 0x6000 != 0 || l_tile >= max_level;
+        let l_tile = l_tile.min(max_level);
+
+        let mut frac = (((lod << 3) >> l_tile) & 0xFF) as u16;
+        if !mode.sharpen && !mode.detail {
+            if distant {
+                frac = 0xFF;
+            } else if magnify {
+                frac = 0;
+            }
+        }
+        if mode.sharpen && magnify {
+            frac |= 0xFF00;
+        }
+
+        let tile = if mode.detail {
+            if magnify {
+                base
+            } else {
+                base + (l_tile + 1).min(max_level)
+            }
+        } else {
+            base + l_tile
+        };
+        Some((tile & 7, frac))
+    }
+
+    /// Sample the texture of the specified tile at the specified s10.5
+    /// texel coordinates (see `project`).
+    pub(crate) fn sample(&self, tile: usize, s: i32, t: i32) -> MultiColor {
+        let tile = &self.tiles[tile];
+
+        // Apply the tile shift, then make coordinates relative to the tile
+        // origin (which is in 10.2).
+        let s = shift_coord(s, tile.shift[0]) - ((tile.rect.c0.x.bits() as i32) << 3);
+        let t = shift_coord(t, tile.shift[1]) - ((tile.rect.c0.y.bits() as i32) << 3);
+
+        let (si, ti) = (s >> 5, t >> 5);
+        let c = match self.filter {
+            TexFilter::Point => self.fetch_wrapped(tile, si, ti),
+            TexFilter::Bilinear { mid_texel } => {
+                let (fs, ft) = (s & 0x1F, t & 0x1F);
+                let t0 = self.fetch_wrapped(tile, si, ti).components();
+                let t1 = self.fetch_wrapped(tile, si + 1, ti).components();
+                let t2 = self.fetch_wrapped(tile, si, ti + 1).components();
+                let t3 = self.fetch_wrapped(tile, si + 1, ti + 1).components();
+                if mid_texel && fs == 0x10 && ft == 0x10 {
+                    Color::new_clamped(
+                        (t0.0 + t1.0 + t2.0 + t3.0 + 2) >> 2,
+                        (t0.1 + t1.1 + t2.1 + t3.1 + 2) >> 2,
+                        (t0.2 + t1.2 + t2.2 + t3.2 + 2) >> 2,
+                        (t0.3 + t1.3 + t2.3 + t3.3 + 2) >> 2,
+                    )
+                } else if fs + ft < 0x20 {
+                    // Upper-left triangle: interpolate from T0 towards T1 (along
+                    // S) and T2 (along T).
+                    tri_lerp(t0, t1, t2, fs, ft)
+                } else {
+                    // Lower-right triangle: interpolate from T3 backwards.
+                    tri_lerp(t3, t2, t1, 0x20 - fs, 0x20 - ft)
+                }
+            }
+        };
+
+        MultiColor::from_color(c)
+    }
+
+    /// Fetch a texel, applying the clamp, mirror and mask settings of the
+    /// tile to its coordinates.
+    #[inline(always)]
+    fn fetch_wrapped(&self, tile: &TileDescriptor, s: i32, t: i32) -> Color<Rgba8888> {
+        let width = tile.rect.width().floor() as i32;
+        let height = tile.rect.height().floor() as i32;
+        self.fetch(
+            tile,
+            wrap_coord(s, width, tile.clamp[0], tile.mirror[0], tile.mask[0]),
+            wrap_coord(t, height, tile.clamp[1], tile.mirror[1], tile.mask[1]),
+        )
+    }
+}
+
+/// Apply the tile shift to a s10.5 texel coordinate. Shifts from 1 to 10
+/// scale the coordinate down; shifts from 11 to 15 scale it up by
+/// `16 - shift`.
+#[inline(always)]
+fn shift_coord(v: i32, shift: u32) -> i32 {
+    if shift <= 10 {
+        v >> shift
+    } else {
+        v << (16 - shift)
+    }
+}
+
+/// Wrap an integer texel coordinate (relative to the tile origin) along one
+/// axis; `max` is the last texel of the tile. Coordinates are clamped when
+/// requested, or when there is no mask (as the hardware does); then the
+/// mask wraps them, mirroring every other repetition if enabled.
+#[inline(always)]
+fn wrap_coord(v: i32, max: i32, clamp: bool, mirror: bool, mask: u32) -> i32 {
+    let mut v = v;
+    if clamp || mask == 0 {
+        v = v.max(0).min(max);
+    }
+    if mask != 0 {
+        // The hardware supports at most 10 bits of mask.
+        let mask = mask.min(0x3FF) as i32;
+        if mirror && v & (mask + 1) != 0 {
+            v = !v;
+        }
+        v &= mask;
+    }
+    v
+}
+
+/// Three-point interpolation: `base + fs*(ds-base) + ft*(dt-base)`, where
+/// the weights are 5-bit fractions.
+#[inline(always)]
+fn tri_lerp(
+    base: (i32, i32, i32, i32),
+    ds: (i32, i32, i32, i32),
+    dt: (i32, i32, i32, i32),
+    fs: i32,
+    ft: i32,
+) -> Color<Rgba8888> {
+    let lerp = |b: i32, s: i32, t: i32| b + ((fs * (s - b) + ft * (t - b) + 0x10) >> 5);
+    Color::new_clamped(
+        lerp(base.0, ds.0, dt.0),
+        lerp(base.1, ds.1, dt.1),
+        lerp(base.2, ds.2, dt.2),
+        lerp(base.3, ds.3, dt.3),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tmem_odd_line_swap() {
+        let line0 = [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07];
+        let line1 = [0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17];
+        let mut tmem = vec![0u8; 4096];
+        tmem_store(&mut tmem, 0x100, 0, &line0, 16);
+        tmem_store(&mut tmem, 0x108, 1, &line1, 16);
+        assert_eq!(&tmem[0x100..0x108], &line0);
+        assert_eq!(
+            &tmem[0x108..0x110],
+            &[0x14, 0x15, 0x16, 0x17, 0x10, 0x11, 0x12, 0x13]
+        );
+
+        let (buf, pitch) = tmem_linear(&tmem, 0x100, 8, 2, 16);
+        assert_eq!(pitch, 8);
+        assert_eq!(&buf[..8], &line0);
+        assert_eq!(&buf[8..], &line1);
+    }
+
+    #[test]
+    fn tmem_32bpp_split() {
+        // Four RGBA texels per line: red and green go to the low half, blue
+        // and alpha to the high half, with odd lines swapped in both.
+        let line0: Vec<u8> = (0x00..0x10).collect();
+        let line1: Vec<u8> = (0x10..0x20).collect();
+        let mut tmem = vec![0u8; 4096];
+        tmem_store(&mut tmem, 0x100, 0, &line0, 32);
+        tmem_store(&mut tmem, 0x108, 1, &line1, 32);
+        assert_eq!(
+            &tmem[0x100..0x110],
+            &[
+                0x00, 0x01, 0x04, 0x05, 0x08, 0x09, 0x0C, 0x0D, // line 0
+                0x18, 0x19, 0x1C, 0x1D, 0x10, 0x11, 0x14, 0x15, // line 1
+            ]
+        );
+        assert_eq!(
+            &tmem[0x900..0x910],
+            &[
+                0x02, 0x03, 0x06, 0x07, 0x0A, 0x0B, 0x0E, 0x0F, // line 0
+                0x1A, 0x1B, 0x1E, 0x1F, 0x12, 0x13, 0x16, 0x17, // line 1
+            ]
+        );
+
+        let (buf, pitch) = tmem_linear(&tmem, 0x100, 8, 2, 32);
+        assert_eq!(pitch, 16);
+        assert_eq!(&buf[..16], &line0[..]);
+        assert_eq!(&buf[16..], &line1[..]);
+    }
+}
@@ -0,0 +1,284 @@
+// Depth buffer
+
+extern crate byteorder;
+
+use self::byteorder::{BigEndian, ByteOrder};
+
+/// Maximum value of an (uncompressed) 18-bit depth value.
+pub(crate) const Z_MAX: u32 = 0x3FFFF;
+
+// Floating point-like encoding of 18-bit depth values into 14 bits: a 3-bit
+// exponent counts the number of leading ones (up to 7), and the 11-bit
+// mantissa holds the following bits.
+const Z_BASE: [u32; 8] = [
+    0x00000, 0x20000, 0x30000, 0x38000, 0x3C000, 0x3E000, 0x3F000, 0x3F800,
+];
+const Z_SHIFT: [u32; 8] = [6, 5, 4, 3, 2, 1, 0, 0];
+
+/// Compress a 18-bit depth value into the 14-bit format stored in memory.
+pub(crate) fn z_compress(z: u32) -> u16 {
+    let z = z.min(Z_MAX);
+    let exp = ((!(z << 14)).leading_zeros() as usize).min(7);
+    let mant = ((z - Z_BASE[exp]) >> Z_SHIFT[exp]) & 0x7FF;
+    ((exp as u16) << 11) | mant as u16
+}
+
+/// Decompress a 14-bit depth value into 18 bits.
+pub(crate) fn z_decompress(z: u16) -> u32 {
+    let exp = (z >> 11) as usize & 7;
+    let mant = (z & 0x7FF) as u32;
+    Z_BASE[exp] + (mant << Z_SHIFT[exp])
+}
+
+/// Compress a 16-bit delta Z into its 4-bit logarithmic representation.
+pub(crate) fn dz_compress(dz: u32) -> u16 {
+    let dz = dz.min(0xFFFF).max(1);
+    (31 - dz.leading_zeros()) as u16
+}
+
+/// Decompress a 4-bit delta Z into its 16-bit value.
+pub(crate) fn dz_decompress(dz: u16) -> u32 {
+    1 << (dz & 0xF)
+}
+
+/// Depth compare mode, as selected in Other Modes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum ZMode {
+    /// Plain surfaces: the pixel is drawn if it is nearer than the buffer
+    /// (within the delta Z tolerance on edges).
+    Opaque,
+    /// Like opaque, but the coverage of intersecting surfaces is scaled
+    /// along the intersection, to antialias it.
+    Interpenetrating,
+    /// Transparent surfaces: the pixel is drawn only if strictly in front.
+    Transparent,
+    /// Decals: the pixel is drawn only if coplanar with the buffer (within
+    /// the delta Z tolerance).
+    Decal,
+}
+
+impl ZMode {
+    pub(crate) fn from_bits(bits: u64) -> ZMode {
+        match bits & 3 {
+            0 => ZMode::Opaque,
+            1 => ZMode::Interpenetrating,
+            2 => ZMode::Transparent,
+            _ => ZMode::Decal,
+        }
+    }
+}
+
+/// Compare a pixel depth and delta Z against the values stored in the depth
+/// buffer (`old`), for a pixel with coverage `cvg` (0-8). `overflow` tells
+/// whether the pixel coverage plus the memory coverage exceeds a full pixel.
+/// Returns the coverage to draw the pixel with (which is changed only along
+/// interpenetrating surfaces), or None if the pixel fails the test.
+#[inline(always)]
+pub(crate) fn z_compare(
+    old: (u32, u32),
+    z: u32,
+    dz: u32,
+    cvg: u8,
+    overflow: bool,
+    mode: ZMode,
+) -> Option<u8> {
+    let (oldz, olddz) = old;
+
+    // The tolerance is the larger of the two delta Z, converted to the
+    // precision of 18-bit depth values.
+    let dzmax = dz_decompress(dz_compress(dz)).max(olddz);
+    let tolerance = dzmax << 3;
+    let (z, oldz_i) = (z as i32, oldz as i32);
+
+    let max = oldz == Z_MAX;
+    let infront = z < oldz_i;
+    let farther = z + tolerance as i32 >= oldz_i;
+    let nearer = z - tolerance as i32 <= oldz_i;
+    let opaque = max || if overflow { infront } else { nearer };
+
+    let pass = match mode {
+        ZMode::Opaque => opaque,
+        ZMode::Interpenetrating => {
+            if infront && farther && overflow {
+                // Scale the coverage by how far in front the pixel is,
+                // within the tolerance.
+                let shift = dz_compress(dzmax);
+                let coeff = ((oldz >> shift) - ((z as u32) >> shift)) & 0xF;
+                return Some(((coeff * cvg as u32) >> 3).min(8) as u8);
+            }
+            opaque
+        }
+        ZMode::Transparent => infront || max,
+        ZMode::Decal => farther && nearer && !max,
+    };
+    if pass {
+        Some(cvg)
+    } else {
+        None
+    }
+}
+
+/// A depth buffer in RDRAM. Each pixel is a 16-bit big-endian word holding
+/// the compressed depth in the upper 14 bits and the upper two bits of the
+/// compressed delta Z in the lower 2 bits (the remaining two bits live in
+/// the RDRAM hidden bits, which are not emulated).
+pub(crate) struct ZBuffer<'a> {
+    mem: &'a mut [u8],
+    pitch: usize,
+}
+
+impl<'a> ZBuffer<'a> {
+    pub(crate) fn new(mem: &'a mut [u8], width: usize) -> ZBuffer<'a> {
+        ZBuffer {
+            mem,
+            pitch: width * 2,
+        }
+    }
+
+    #[inline(always)]
+    fn offset(&self, x: usize, y: usize) -> Option<usize> {
+        let off = y * self.pitch + x * 2;
+        if off + 2 <= self.mem.len() {
+            Some(off)
+        } else {
+            None
+        }
+    }
+
+    /// Read the depth (18 bits) and delta Z (16 bits) of the specified pixel.
+    #[inline(always)]
+    pub(crate) fn get(&self, x: usize, y: usize) -> (u32, u32) {
+        match self.offset(x, y) {
+            Some(off) => {
+                let v = BigEndian::read_u16(&self.mem[off..]);
+                (z_decompress(v >> 2), dz_decompress((v & 3) << 2))
+            }
+            None => (Z_MAX, 0xFFFF),
+        }
+    }
+
+    /// Write depth (18 bits) and delta Z (16 bits) for the specified pixel.
+    #[inline(always)]
+    pub(crate) fn set(&mut self, x: usize, y: usize, z: u32, dz: u32) {
+        if let Some(off) = self.offset(x, y) {
+            let v = (z_compress(z) << 2) | (dz_compress(dz) >> 2);
+            BigEndian::write_u16(&mut self.mem[off..], v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn z_compress_boundaries() {
+        // Known values at the exponent boundaries of the compressed format.
+        let table = [
+            (0x00000, 0x0000),
+            (0x1FFFF, 0x07FF),
+            (0x20000, 0x0800),
+            (0x2FFFF, 0x0FFF),
+            (0x30000, 0x1000),
+            (0x38000, 0x1800),
+            (0x3C000, 0x2000),
+            (0x3E000, 0x2800),
+            (0x3F000, 0x3000),
+            (0x3F7FF, 0x37FF),
+            (0x3F800, 0x3800),
+            (0x3FFFF, 0x3FFF),
+        ];
+        for &(z, c) in table.iter() {
+            assert_eq!(z_compress(z), c, "z={:x}", z);
+            assert_eq!(z_decompress(c), z & !((1 << Z_SHIFT[c as usize >> 11]) - 1));
+        }
+        assert_eq!(z_compress(0x40000), 0x3FFF);
+    }
+
+    #[test]
+    fn z_roundtrip() {
+        for c in 0..0x4000u16 {
+            assert_eq!(z_compress(z_decompress(c)), c, "c={:x}", c);
+        }
+        // Compression truncates the bits that do not fit the mantissa.
+        for z in 0..=Z_MAX {
+            let c = z_compress(z);
+            let z2 = z_decompress(c);
+            assert!(
+                z2 <= z && z - z2 < 1 << Z_SHIFT[c as usize >> 11],
+                "z={:x}",
+                z
+            );
+        }
+    }
+
+    #[test]
+    fn dz_encoding() {
+        assert_eq!(dz_compress(0), 0);
+        assert_eq!(dz_compress(1), 0);
+        assert_eq!(dz_compress(2), 1);
+        assert_eq!(dz_compress(3), 1);
+        assert_eq!(dz_compress(0x8000), 15);
+        assert_eq!(dz_compress(0x1_0000), 15);
+        for dz in 0..16 {
+            assert_eq!(dz_compress(dz_decompress(dz)), dz);
+        }
+    }
+
+    #[test]
+    fn z_compare_modes() {
+        // Tolerance is 8 (delta Z 1, in 18-bit precision).
+        let old = (0x1000, 1);
+        let cmp = |z, overflow, mode| z_compare(old, z, 1, 5, overflow, mode);
+
+        assert_eq!(cmp(0x0F00, false, ZMode::Opaque), Some(5));
+        assert_eq!(cmp(0x1008, false, ZMode::Opaque), Some(5));
+        assert_eq!(cmp(0x1009, false, ZMode::Opaque), None);
+        assert_eq!(cmp(0x1000, true, ZMode::Opaque), None);
+        assert_eq!(cmp(0x0FFF, true, ZMode::Opaque), Some(5));
+
+        assert_eq!(cmp(0x1000, false, ZMode::Transparent), None);
+        assert_eq!(cmp(0x0FFF, false, ZMode::Transparent), Some(5));
+
+        assert_eq!(cmp(0x0FF8, false, ZMode::Decal), Some(5));
+        assert_eq!(cmp(0x1008, false, ZMode::Decal), Some(5));
+        assert_eq!(cmp(0x0FF7, false, ZMode::Decal), None);
+        assert_eq!(cmp(0x1009, false, ZMode::Decal), None);
+
+        // An empty buffer passes all tests but decals.
+        let max = (Z_MAX, 1);
+        assert_eq!(z_compare(max, 0x3FFF0, 1, 5, true, ZMode::Opaque), Some(5));
+        assert_eq!(
+            z_compare(max, 0x3FFF0, 1, 5, false, ZMode::Transparent),
+            Some(5)
+        );
+        assert_eq!(z_compare(max, 0x3FFF0, 1, 5, false, ZMode::Decal), None);
+
+        // Interpenetrating surfaces scale the coverage by the distance
+        // within the tolerance (0x80 here).
+        let old = (0x1000, 0x10);
+        assert_eq!(
+            z_compare(old, 0x0FC0, 1, 8, true, ZMode::Interpenetrating),
+            Some(4)
+        );
+        assert_eq!(
+            z_compare(old, 0x0FC0, 1, 8, false, ZMode::Interpenetrating),
+            Some(8)
+        );
+    }
+
+    #[test]
+    fn zbuffer_get_set() {
+        let mut mem = [0u8; 8];
+        {
+            let mut zb = ZBuffer::new(&mut mem, 2);
+            zb.set(1, 0, 0x12345, 0x100);
+            zb.set(0, 1, 0x3FFFF, 0x20);
+            assert_eq!(zb.get(1, 0), (0x12340, 0x100));
+            // Only the upper two bits of the compressed delta Z are stored.
+            assert_eq!(zb.get(0, 1), (0x3FFFF, 0x10));
+            assert_eq!(zb.get(0, 2), (Z_MAX, 0xFFFF));
+        }
+        assert_eq!(mem, [0x00, 0x00, 0x12, 0x36, 0xFF, 0xFD, 0x00, 0x00]);
+    }
+}
@@ -1,11 +1,18 @@
 extern crate emu;
 extern crate slog;
-use emu::bus::be::{Mem, Reg32};
+use emu::bus::be::{Mem, MemFlags, Reg32};
+
+/// RDRAM size, without and with the Expansion Pak.
+pub const RDRAM_SIZE: usize = 4 * 1024 * 1024;
+pub const RDRAM_SIZE_EXPANDED: usize = 8 * 1024 * 1024;
 
 /// RDRAM
 #[derive(DeviceBE)]
 pub struct Ri {
-    #[mem(bank = 0, size = 4194304, offset = 0x0000_0000)]
+    // Without the Expansion Pak, the upper 4MB are not mapped: they read as
+    // zero (rather than mirroring the lower 4MB), which is how the OS detects
+    // the memory size.
+    #[mem(offset = 0x0000_0000, vsize = 0x0080_0000, fill = "Fixed(0x00)")]
     pub(crate) rdram: Mem,
 
     #[reg(bank = 1, offset = 0x00)]
@@ -83,9 +90,9 @@ pub struct Ri {
 }
 
 impl Ri {
-    pub fn new(logger: slog::Logger) -> Box<Ri> {
+    pub fn new(logger: slog::Logger, rdram_size: usize) -> Box<Ri> {
         Box::new(Ri {
-            rdram: Mem::default(),
+            rdram: Mem::new("Ri::rdram", rdram_size, MemFlags::default(), None),
 
             reg_rdram_config: Reg32::default(),
             reg_rdram_device_id: Reg32::default(),
@@ -110,4 +117,8 @@ impl Ri {
             _logger: logger,
         })
     }
+
+    pub fn rdram_size(&self) -> usize {
+        self.rdram.len()
+    }
 }
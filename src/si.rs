@@ -6,7 +6,9 @@ use super::pi::Pi;
 
 use emu::bus::be::Reg32;
 use emu::bus::Device;
+use emu::dma::{DmaChannel, DmaTiming};
 use emu::int::Numerics;
+use emu::sync::Subsystem;
 use emu_derive::DeviceBE;
 
 #[derive(DeviceBE)]
@@ -29,9 +31,19 @@ pub struct Si {
     #[reg(bank = 0, offset = 0x18, rwmask = 0, wcb)]
     status: Reg32,
 
+    dma: DmaChannel,
     logger: slog::Logger,
 }
 
+// The serial link to PIF is slow: a transfer of the whole PIF RAM (64 bytes)
+// takes roughly 2300 RCP cycles.
+const DMA_TIMING: DmaTiming = DmaTiming {
+    latency: 0,
+    burst_size: 64,
+    word_size: 4,
+    cycles_per_word: 144,
+};
+
 impl Si {
     pub fn new(logger: slog::Logger) -> Box<Si> {
         Box::new(Si {
@@ -39,6 +51,7 @@ impl Si {
             dma_address: Reg32::default(),
             start_dma_read: Reg32::default(),
             start_dma_write: Reg32::default(),
+            dma: DmaChannel::new("Si::dma", DMA_TIMING, 1),
             logger,
         })
     }
@@ -59,6 +72,29 @@ impl Si {
         Mi::get_mut().set_irq_line(IrqMask::SI, true);
     }
 
+    // Schedule the completion of a DMA transfer, returning false if another
+    // transfer is in progress. Data is moved immediately, but the DMA busy
+    // bit and the interrupt are only updated when the transfer is completed.
+    // Transfers are timed on the PI clock, as they are completed by PIF
+    // (see Pi::run()).
+    fn start_dma(&mut self) -> bool {
+        if self.dma.is_busy() {
+            error!(self.logger, "SI DMA started while busy");
+            self.status.set(self.status.get() | (1 << 3));
+            return false;
+        }
+        self.dma.start(Pi::get().cycles(), 64);
+        self.status.set(self.status.get() | (1 << 0));
+        true
+    }
+
+    pub(crate) fn advance_dma(&mut self, now: i64) {
+        if self.dma.advance(now) > 0 {
+            self.status.set(self.status.get() & !(1 << 0));
+            self.raise_irq();
+        }
+    }
+
     fn cb_write_status(&mut self, old: u32, new: u32) {
         // Any write to SI status clears the IRQ line
         self.status.set(old & !(1 << 12));
@@ -71,6 +107,9 @@ impl Si {
         let mut src = new;
         let mut dst = self.dma_address.get();
         info!(self.logger, "SI DMA read"; "pifram" => src.hex(), "rdram" => dst.hex());
+        if !self.start_dma() {
+            return;
+        }
 
         let bus = &mut R4300::get_mut().bus;
         for _ in 0..16 {
@@ -79,13 +118,15 @@ impl Si {
             src += 4;
             dst += 4;
         }
-        self.raise_irq();
     }
 
     fn cb_write_start_dma_write(&mut self, _old: u32, new: u32) {
         let mut src = self.dma_address.get();
         let mut dst = new;
         info!(self.logger, "SI DMA write"; "rdram" => src.hex(), "pifram" => dst.hex());
+        if !self.start_dma() {
+            return;
+        }
 
         let bus = &mut R4300::get_mut().bus;
         for _ in 0..16 {
@@ -94,7 +135,6 @@ impl Si {
             src += 4;
             dst += 4;
         }
-        self.raise_irq();
 
         if bus.read::<u8>(0x1fc0_07c0) & 1 != 0 {
             self.set_busy(true);
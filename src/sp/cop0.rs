@@ -1,5 +1,5 @@
 use super::super::dp::Dp;
-use super::{Sp, StatusFlags};
+use super::Sp;
 use crate::errors::*;
 use emu::bus::be::{Bus, Device};
 use emu::dbg;
@@ -116,10 +116,7 @@ impl mips64::Cop0 for SpCop0 {
             // Breakpoint exception is used by RSP to halt itself
             Breakpoint => {
                 info!(self._logger, "RSP break");
-                let sp = Sp::get_mut();
-                let mut status = sp.get_status();
-                status.insert(StatusFlags::HALT | StatusFlags::BROKE);
-                match sp.set_status(status) {
+                match Sp::get_mut().brk() {
                     Some(halt) => ctx.set_halt_line(halt),
                     None => {}
                 }
@@ -86,6 +86,11 @@ impl SpCop2 {
     }
 
     pub fn new(name: &str, logger: slog::Logger) -> Result<SpCop2> {
+        // The vector unit maps each vector register to a SSE register, and
+        // the accumulator and multiplication ops use SSE 4.1 instructions.
+        if !is_x86_feature_detected!("sse4.1") {
+            bail!("RSP vector unit requires a CPU with SSE 4.1");
+        }
         Ok(SpCop2 {
             name: name.to_owned(),
             ctx: Field::new("sp::cop2", SpCop2Context::default()),
@@ -312,12 +317,29 @@ impl SpCop2 {
             match op.func() {
                 0x00 => op_vmul!(op, vmulf), // VMULF
                 0x01 => op_vmul!(op, vmulu), // VMULU
+                0x02 | 0x0A => {
+                    // VRNDP / VRNDN
+                    let (res, acc_lo, acc_md, acc_hi) = vmul::vrnd(
+                        op.vte(),
+                        op.accum(0),
+                        op.accum(1),
+                        op.accum(2),
+                        op.rs() & 1 != 0,
+                        op.func() == 0x02,
+                    );
+                    op.setvd(res);
+                    op.setaccum(0, acc_lo);
+                    op.setaccum(1, acc_md);
+                    op.setaccum(2, acc_hi);
+                }
+                0x03 => op_vmul!(op, vmulq), // VMULQ
                 0x04 => op_vmul!(op, vmudl), // VMUDL
                 0x05 => op_vmul!(op, vmudm), // VMUDM
                 0x06 => op_vmul!(op, vmudn), // VMUDN
                 0x07 => op_vmul!(op, vmudh), // VMUDH
                 0x08 => op_vmul!(op, vmacf), // VMACF
                 0x09 => op_vmul!(op, vmacu), // VMACU
+                0x0B => op_vmul!(op, vmacq), // VMACQ
                 0x0C => op_vmul!(op, vmadl), // VMADL
                 0x0D => op_vmul!(op, vmadm), // VMADM
                 0x0E => op_vmul!(op, vmadn), // VMADN
@@ -419,16 +441,16 @@ impl SpCop2 {
                     // VSAR
                     let e = op.e();
                     match e {
-                        0..=2 => {
-                            op.setvd(vzero);
-                        }
                         8..=10 => {
                             // NOTE: VSAR is not able to write the accumulator,
                             // contrary to what documentation says.
                             let sar = op.accum(2 - (e - 8));
                             op.setvd(sar);
                         }
-                        _ => unimplemented!(),
+                        // All other element values (including 0..2, which
+                        // used to select the accumulator slices on early
+                        // RCP revisions) read back as zero.
+                        _ => op.setvd(vzero),
                     }
                 }
                 0x20 => {
@@ -593,8 +615,9 @@ impl SpCop2 {
                     let x = op.vt_lane(op.e() & 7);
                     let res = vrcp::vrcp(x.sx32());
                     op.setvd_lane(op.rs() & 7, res as u16);
-                    op.setaccum(0, op.vt());
+                    op.setaccum(0, op.vte());
                     op.ctx.div_out = res;
+                    op.ctx.div_in = None;
                 }
                 0x31 => {
                     // VRCPL
@@ -604,7 +627,7 @@ impl SpCop2 {
                         None => vrcp::vrcp(x.sx32()),
                     };
                     op.setvd_lane(op.rs() & 7, res as u16);
-                    op.setaccum(0, op.vt());
+                    op.setaccum(0, op.vte());
                     op.ctx.div_out = res;
                     op.ctx.div_in = None;
                 }
@@ -612,7 +635,7 @@ impl SpCop2 {
                     // VRCPH
                     let x = op.vt_lane(op.e() & 7);
                     op.setvd_lane(op.rs() & 7, (op.ctx.div_out >> 16) as u16);
-                    op.setaccum(0, op.vt());
+                    op.setaccum(0, op.vte());
                     op.ctx.div_in = Some((x as u32) << 16);
                 }
                 0x33 => {
@@ -627,16 +650,16 @@ impl SpCop2 {
 
                     let res = op.vt_lane(se);
                     op.setvd_lane(op.rs() & 7, res);
-                    // FIXME: update ACCUM with VMOV?
-                    op.setaccum(0, op.vt());
+                    op.setaccum(0, op.vte());
                 }
                 0x34 => {
                     // VRSQ
                     let x = op.vt_lane(op.e() & 7);
                     let res = vrcp::vrsq(x.sx32());
                     op.setvd_lane(op.rs() & 7, res as u16);
-                    op.setaccum(0, op.vt());
+                    op.setaccum(0, op.vte());
                     op.ctx.div_out = res;
+                    op.ctx.div_in = None;
                 }
                 0x35 => {
                     // VRSQL
@@ -646,7 +669,7 @@ impl SpCop2 {
                         None => vrcp::vrsq(x.sx32()),
                     };
                     op.setvd_lane(op.rs() & 7, res as u16);
-                    op.setaccum(0, op.vt());
+                    op.setaccum(0, op.vte());
                     op.ctx.div_out = res;
                     op.ctx.div_in = None;
                 }
@@ -654,7 +677,7 @@ impl SpCop2 {
                     // VRSQH
                     let x = op.vt_lane(op.e() & 7);
                     op.setvd_lane(op.rs() & 7, (op.ctx.div_out >> 16) as u16);
-                    op.setaccum(0, op.vt());
+                    op.setaccum(0, op.vte());
                     op.ctx.div_in = Some((x as u32) << 16);
                 }
                 0x37 => {} // VNOP
@@ -672,12 +695,12 @@ impl SpCop2 {
                     val |= op.vs_byte((e + 1) & 15) as u16;
                     cpu.regs[op.rt()] = val.sx64();
                 }
-                0x2 => match op.rs() {
-                    // CFC2
+                0x2 => match op.rs() & 3 {
+                    // CFC2. Only the lower two bits of the register index are
+                    // decoded, and both 2 and 3 select VCE.
                     0 => cpu.regs[op.rt()] = op.ctx.vco().sx64(),
                     1 => cpu.regs[op.rt()] = op.ctx.vcc().sx64(),
-                    2 => cpu.regs[op.rt()] = op.ctx.vce() as u64,
-                    _ => panic!("unimplement COP2 CFC2 reg:{}", op.rs()),
+                    _ => cpu.regs[op.rt()] = op.ctx.vce() as u64,
                 },
                 0x4 => {
                     // MTC2
@@ -687,12 +710,11 @@ impl SpCop2 {
                         op.setvs_byte(e + 1, cpu.regs[op.rt()] as u8);
                     }
                 }
-                0x6 => match op.rs() {
+                0x6 => match op.rs() & 3 {
                     // CTC2
                     0 => op.ctx.set_vco(cpu.regs[op.rt()] as u16),
                     1 => op.ctx.set_vcc(cpu.regs[op.rt()] as u16),
-                    2 => op.ctx.set_vce(cpu.regs[op.rt()] as u8),
-                    _ => panic!("unimplement COP2 CTC2 reg:{}", op.rd()),
+                    _ => op.ctx.set_vce(cpu.regs[op.rt()] as u8),
                 },
                 _ => {
                     error!(
@@ -754,7 +776,8 @@ fn lxv<T: MemInt>(reg: &mut VectorReg, element: usize, dmem: &mut [u8], base: u3
     write_partial_right::<LittleEndian>(&mut reg.0, mem, element as usize * 8, T::SIZE * 8);
 }
 
-// Plain "store vector subword into memory"
+// Plain "store vector subword into memory". Misaligned stores that cross
+// the end of DMEM wrap around to its beginning.
 fn sxv<T: MemInt>(dmem: &mut [u8], base: u32, offset: u32, reg: &VectorReg, element: usize) {
     let ea = ((base + (offset << T::SIZE_LOG)) & 0xFFF) as usize;
 
@@ -762,7 +785,11 @@ fn sxv<T: MemInt>(dmem: &mut [u8], base: u32, offset: u32, reg: &VectorReg, elem
     reg = reg.rotate_left(element as u32 * 8);
     reg >>= 128 - T::SIZE * 8;
 
-    T::endian_write_to::<BigEndian>(&mut dmem[ea..ea + T::SIZE], T::truncate_from(reg as u64));
+    let mut buf = [0u8; 8];
+    T::endian_write_to::<BigEndian>(&mut buf[..T::SIZE], T::truncate_from(reg as u64));
+    for (i, b) in buf[..T::SIZE].iter().enumerate() {
+        dmem[(ea + i) & 0xFFF] = *b;
+    }
 }
 
 impl Cop for SpCop2 {
@@ -902,6 +929,10 @@ impl Cop for SpCop2 {
                 let r = self.ctx.vregs[vtidx].u128();
                 self.ctx.vregs[vtidx].setu128((r & !mask) | (new & mask));
             }
+            0x0A => {
+                // LWV: not implemented by the hardware, the register is left
+                // untouched.
+            }
             0x0B => {
                 // LTV
                 let ea = (base + (offset << 4)) & 0xFFF;
@@ -973,20 +1004,18 @@ impl Cop for SpCop2 {
                 // SPV
                 let ea = ((base + (offset << 3)) & 0xFFF) as usize;
 
-                let memptr = &mut dmem[ea..ea + 0x10];
                 for e in 0 as usize..8 as usize {
                     let eidx = (e + element as usize) & 0xF;
-                    memptr[e] = ((vt.lane(eidx & 0x7) << (eidx >> 3)) >> 8) as u8;
+                    dmem[(ea + e) & 0xFFF] = ((vt.lane(eidx & 0x7) << (eidx >> 3)) >> 8) as u8;
                 }
             }
             0x07 => {
                 // SUV
                 let ea = ((base + (offset << 3)) & 0xFFF) as usize;
 
-                let memptr = &mut dmem[ea..ea + 0x10];
                 for e in 0 as usize..8 as usize {
                     let eidx = (e + element as usize) & 0xF;
-                    memptr[e] = ((vt.lane(eidx & 0x7) >> (eidx >> 3)) >> 7) as u8;
+                    dmem[(ea + e) & 0xFFF] = ((vt.lane(eidx & 0x7) >> (eidx >> 3)) >> 7) as u8;
                 }
             }
             0x08 => {
@@ -1147,3 +1176,40 @@ impl dbg::RegisterView for SpCop2 {
         }
     }
 }
+
+// The expected values below are derived from the documented behaviour of
+// VSAR; they have not been recorded on hardware (see tests/gengolden).
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vsar() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut cop2 = SpCop2::new("RSP-VU", logger).unwrap();
+        let mut cpu = CpuContext::default();
+        let accum = [
+            0x0011_2233_4455_6677_8899_AABB_CCDD_EEFFu128,
+            0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210u128,
+            0x8000_7FFF_0001_FFFF_5555_AAAA_0F0F_F0F0u128,
+        ];
+        cop2.set_reg(&mut cpu, SpCop2::REG_ACCUM_LO, accum[0]);
+        cop2.set_reg(&mut cpu, SpCop2::REG_ACCUM_MD, accum[1]);
+        cop2.set_reg(&mut cpu, SpCop2::REG_ACCUM_HI, accum[2]);
+
+        for e in 0..16 {
+            // Preload the destination (which is also the source), so that an
+            // accumulator write would be visible in the later reads.
+            cop2.set_reg(&mut cpu, 1, 0x1234_5678_9ABC_DEF0_1234_5678_9ABC_DEF0);
+            let op = 0x4A01_085D | (e << 21); // VSAR v1,v1[e]
+            cop2.op(&mut cpu, op, &dbg::Tracer::null()).unwrap();
+            let exp = match e {
+                8 => accum[2],
+                9 => accum[1],
+                10 => accum[0],
+                _ => 0,
+            };
+            assert_eq!(cop2.reg(&cpu, 1), exp, "e={}", e);
+        }
+    }
+}
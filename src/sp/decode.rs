@@ -76,12 +76,16 @@ pub(crate) fn decode(opcode: u32, _pc: u64) -> DecodedInsn {
                 match func {
                     0x00 => vmulinsn_new("vmulf"),
                     0x01 => vmulinsn_new("vmulu"),
+                    0x02 => vmulinsn_new("vrndp"),
+                    0x03 => vmulinsn_new("vmulq"),
                     0x04 => vmulinsn_new("vmudl"),
                     0x05 => vmulinsn_new("vmudm"),
                     0x06 => vmulinsn_new("vmudn"),
                     0x07 => vmulinsn_new("vmudh"),
                     0x08 => vmulinsn_new("vmacf"),
                     0x09 => vmulinsn_new("vmacu"),
+                    0x0A => vmulinsn_new("vrndn"),
+                    0x0B => vmulinsn_new("vmacq"),
                     0x0C => vmulinsn_new("vmadl"),
                     0x0D => vmulinsn_new("vmadm"),
                     0x0E => vmulinsn_new("vmadn"),
@@ -125,29 +129,28 @@ pub(crate) fn decode(opcode: u32, _pc: u64) -> DecodedInsn {
                         Imm8(e),
                     )
                     .with_fmt(VMOV_FMT),
-                    0x34 => vreg2insn_new("vsqr"),
-                    0x35 => vreg2insn_new("vsqrl"),
-                    0x36 => vreg2insn_new("vsqrh"),
+                    0x34 => vreg2insn_new("vrsq"),
+                    0x35 => vreg2insn_new("vrsql"),
+                    0x36 => vreg2insn_new("vrsqh"),
                     0x37 => vreg2insn_new("vnop"),
+                    0x3F => vreg2insn_new("vnull"),
                     _ => DecodedInsn::new1("cop2", Imm32(func)),
                 }
             } else {
                 match e {
                     0x0 => DecodedInsn::new3("mfc2", IReg(grt), OReg(vrs), Imm8(rdx as u8 >> 1))
                         .with_fmt(VREG2_FMT),
-                    0x2 => match rsx {
+                    0x2 => match rsx & 3 {
                         0 => DecodedInsn::new2("cfc2", OReg(grt), IReg("vco")),
                         1 => DecodedInsn::new2("cfc2", OReg(grt), IReg("vcc")),
-                        2 => DecodedInsn::new2("cfc2", OReg(grt), IReg("vce")),
-                        _ => DecodedInsn::new2("cfc2?", OReg(grt), Imm8(rsx as u8)),
+                        _ => DecodedInsn::new2("cfc2", OReg(grt), IReg("vce")),
                     },
                     0x4 => DecodedInsn::new3("mtc2", IReg(grt), OReg(vrs), Imm8(rdx as u8 >> 1))
                         .with_fmt(VREG2_FMT),
-                    0x6 => match rsx {
+                    0x6 => match rsx & 3 {
                         0 => DecodedInsn::new2("ctc2", OReg(grt), IReg("vco")),
                         1 => DecodedInsn::new2("ctc2", OReg(grt), IReg("vcc")),
-                        2 => DecodedInsn::new2("ctc2", OReg(grt), IReg("vce")),
-                        _ => DecodedInsn::new2("ctc2?", OReg(grt), Imm8(rsx as u8)),
+                        _ => DecodedInsn::new2("ctc2", OReg(grt), IReg("vce")),
                     },
                     _ => DecodedInsn::new1("cop2su?", Imm8(e)),
                 }
@@ -158,10 +161,10 @@ pub(crate) fn decode(opcode: u32, _pc: u64) -> DecodedInsn {
             let e = ((opcode >> 7) & 0xF) as u8;
             let base = REG_NAMES[((opcode >> 21) & 0x1F) as usize];
             let off = (opcode & 0x7F) as i32;
-            let off = ((off << 25) >> 25) as u16;
+            let off = (off << 25) >> 25;
 
-            let vloadinsn_new = |name, off| {
-                DecodedInsn::new4(name, OReg(vrt), Imm8(e), Imm16(off), IReg(base))
+            let vloadinsn_new = |name, off: i32| {
+                DecodedInsn::new4(name, OReg(vrt), Imm8(e), Imm16(off as u16), IReg(base))
                     .with_fmt(VMEM_FMT)
             };
             match oploadstore {
@@ -175,6 +178,7 @@ pub(crate) fn decode(opcode: u32, _pc: u64) -> DecodedInsn {
                 0x07 => vloadinsn_new("luv", off * 8),
                 0x08 => vloadinsn_new("lhv", off * 16),
                 0x09 => vloadinsn_new("lfv", off * 16),
+                0x0A => vloadinsn_new("lwv", off * 16),
                 0x0B => vloadinsn_new("ltv", off * 16),
                 _ => DecodedInsn::new1("lwc2", Imm32(oploadstore)),
             }
@@ -183,10 +187,11 @@ pub(crate) fn decode(opcode: u32, _pc: u64) -> DecodedInsn {
             let oploadstore = (opcode >> 11) & 0x1F;
             let e = ((opcode >> 7) & 0xF) as u8;
             let base = REG_NAMES[((opcode >> 21) & 0x1F) as usize];
-            let off = (opcode & 0x7F) as u16;
+            let off = (opcode & 0x7F) as i32;
+            let off = (off << 25) >> 25;
 
-            let vstoreinsn_new = |name, off| {
-                DecodedInsn::new4(name, IReg(vrt), Imm8(e), Imm16(off), IReg(base))
+            let vstoreinsn_new = |name, off: i32| {
+                DecodedInsn::new4(name, IReg(vrt), Imm8(e), Imm16(off as u16), IReg(base))
                     .with_fmt(VMEM_FMT)
             };
             match oploadstore {
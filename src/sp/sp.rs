@@ -3,9 +3,13 @@ use super::super::r4300::R4300;
 use super::cop0::SpCop0;
 use super::cop2::SpCop2;
 use crate::errors::*;
+use byteorder::{BigEndian, ByteOrder};
 use emu::bus::be::{Bus, Device, Mem, Reg32};
+use emu::dbg;
+use emu::dma::{DmaChannel, DmaTiming};
 use emu::int::Numerics;
 use emu::memint::MemInt;
+use emu::sync::Subsystem;
 use mips64;
 
 use slog;
@@ -44,6 +48,7 @@ impl mips64::Arch for ArchRSP {
             "lwl" | "lwr" | "swl" | "swr" => false,
             "mult" | "multu" | "div" | "divu" => false,
             "mfhi" | "mflo" | "mthi" | "mhlo" => false,
+            "syscall" => false,
             _ => true,
         }
     }
@@ -63,6 +68,10 @@ impl mips64::Config for RSPCPUConfig {
         // do not mask lower bits here
         addr & 0xFFF
     }
+    fn addr_wrap() -> Option<u32> {
+        // Accesses crossing the end of DMEM wrap to its beginning
+        Some(0x1000)
+    }
 }
 
 #[derive(DeviceBE)]
@@ -99,6 +108,38 @@ impl RSPCPU {
     }
 }
 
+impl Subsystem for RSPCPU {
+    fn name(&self) -> &str {
+        self.cpu.name()
+    }
+
+    fn run(&mut self, until: i64, t: &dbg::Tracer) -> dbg::Result<()> {
+        let sp = Sp::get_mut();
+        let status = sp.get_status();
+        if status.contains(StatusFlags::SINGLESTEP) && !status.contains(StatusFlags::HALT) {
+            // In single-step mode, the RSP executes a single instruction
+            // each time it is started, and then halts again.
+            self.cpu.run(self.cycles() + 1, t)?;
+            if let Some(halt) = sp.set_status(sp.get_status() | StatusFlags::HALT) {
+                self.ctx_mut().set_halt_line(halt);
+            }
+        }
+        self.cpu.run(until, t)
+    }
+
+    fn step(&mut self, t: &dbg::Tracer) -> dbg::Result<()> {
+        self.run(self.cycles() + 1, t)
+    }
+
+    fn cycles(&self) -> i64 {
+        self.cpu.cycles()
+    }
+
+    fn pc(&self) -> Option<u64> {
+        self.cpu.pc()
+    }
+}
+
 impl Deref for RSPCPU {
     type Target = mips64::Cpu<RSPCPUConfig>;
     fn deref(&self) -> &Self::Target {
@@ -124,7 +165,7 @@ pub struct Sp {
     #[mem(bank = 0, offset = 0x1000, size = 4096)]
     pub imem: Mem,
 
-    #[reg(bank = 2, offset = 0x0, rwmask = 0xFFF, wcb, rcb)]
+    #[reg(bank = 2, offset = 0x0, rwmask = 0xFFC, wcb, rcb)]
     reg_rsp_pc: Reg32,
 
     #[reg(bank = 1, offset = 0x00, rwmask = 0x1FF8)]
@@ -139,7 +180,7 @@ pub struct Sp {
     #[reg(bank = 1, offset = 0x0C, wcb)]
     reg_dma_wr_len: Reg32,
 
-    #[reg(bank = 1, offset = 0x10, init = 0x1, wcb)]
+    #[reg(bank = 1, offset = 0x10, init = 0x1, wcb, rcb)]
     reg_status: Reg32,
 
     #[reg(bank = 1, offset = 0x14, readonly, rcb)]
@@ -148,12 +189,22 @@ pub struct Sp {
     #[reg(bank = 1, offset = 0x18, readonly, rcb)]
     reg_dma_busy: Reg32,
 
-    #[reg(bank = 1, offset = 0x1C, init = 0x0, rwmask = 0x1, rcb)]
+    #[reg(bank = 1, offset = 0x1C, init = 0x0, rwmask = 0x1, rcb, wcb)]
     reg_semaphore: Reg32,
 
+    dma: DmaChannel,
     logger: slog::Logger,
 }
 
+// DMA transfers between RDRAM and DMEM/IMEM move 8 bytes per cycle, after
+// the RDRAM latency (approximate).
+const DMA_TIMING: DmaTiming = DmaTiming {
+    latency: 16,
+    burst_size: 0x1000,
+    word_size: 8,
+    cycles_per_word: 1,
+};
+
 impl Sp {
     pub fn new(logger: slog::Logger) -> Result<Box<Sp>> {
         // Create the RSP internal MIPS CPU and its associated bus
@@ -172,6 +223,8 @@ impl Sp {
             reg_rsp_pc: Reg32::default(),
             reg_dma_full: Reg32::default(),
             reg_semaphore: Reg32::default(),
+            // One transfer in progress, and one pending
+            dma: DmaChannel::new("Sp::dma", DMA_TIMING, 2),
         }))
     }
 
@@ -196,82 +249,42 @@ impl Sp {
     pub(crate) fn write_status(&mut self, writebits: u32) -> Option<bool> {
         let mut status = self.get_status();
         let new = writebits;
-        if new & (1 << 0) != 0 {
-            status.remove(StatusFlags::HALT);
-        }
-        if new & (1 << 1) != 0 {
-            status.insert(StatusFlags::HALT);
+
+        // Most flags are controlled by a pair of clear/set bits. Writing
+        // both bits of a pair at the same time leaves the flag unchanged.
+        let pairs = [
+            (0, StatusFlags::HALT),
+            (5, StatusFlags::SINGLESTEP),
+            (7, StatusFlags::INTBREAK),
+            (9, StatusFlags::SIG0),
+            (11, StatusFlags::SIG1),
+            (13, StatusFlags::SIG2),
+            (15, StatusFlags::SIG3),
+            (17, StatusFlags::SIG4),
+            (19, StatusFlags::SIG5),
+            (21, StatusFlags::SIG6),
+            (23, StatusFlags::SIG7),
+        ];
+        for &(bit, flag) in pairs.iter() {
+            match (new >> bit) & 3 {
+                1 => status.remove(flag),
+                2 => status.insert(flag),
+                _ => {}
+            }
         }
         if new & (1 << 2) != 0 {
             status.remove(StatusFlags::BROKE);
         }
-        if new & (1 << 3) != 0 {
-            info!(self.logger, "clear RSP Interrupt");
-            Mi::get_mut().set_irq_line(IrqMask::SP, false);
-        }
-        if new & (1 << 4) != 0 {
-            info!(self.logger, "force-set RSP Interrupt");
-            Mi::get_mut().set_irq_line(IrqMask::SP, true);
-        }
-        if new & (1 << 5) != 0 {
-            status.remove(StatusFlags::SINGLESTEP);
-        }
-        if new & (1 << 6) != 0 {
-            status.insert(StatusFlags::SINGLESTEP);
-        }
-        if new & (1 << 7) != 0 {
-            status.remove(StatusFlags::INTBREAK);
-        }
-        if new & (1 << 8) != 0 {
-            status.insert(StatusFlags::INTBREAK);
-        }
-        if new & (1 << 9) != 0 {
-            status.remove(StatusFlags::SIG0);
-        }
-        if new & (1 << 10) != 0 {
-            status.insert(StatusFlags::SIG0);
-        }
-        if new & (1 << 11) != 0 {
-            status.remove(StatusFlags::SIG1);
-        }
-        if new & (1 << 12) != 0 {
-            status.insert(StatusFlags::SIG1);
-        }
-        if new & (1 << 13) != 0 {
-            status.remove(StatusFlags::SIG2);
-        }
-        if new & (1 << 14) != 0 {
-            status.insert(StatusFlags::SIG2);
-        }
-        if new & (1 << 15) != 0 {
-            status.remove(StatusFlags::SIG3);
-        }
-        if new & (1 << 16) != 0 {
-            status.insert(StatusFlags::SIG3);
-        }
-        if new & (1 << 17) != 0 {
-            status.remove(StatusFlags::SIG4);
-        }
-        if new & (1 << 18) != 0 {
-            status.insert(StatusFlags::SIG4);
-        }
-        if new & (1 << 19) != 0 {
-            status.remove(StatusFlags::SIG5);
-        }
-        if new & (1 << 20) != 0 {
-            status.insert(StatusFlags::SIG5);
-        }
-        if new & (1 << 21) != 0 {
-            status.remove(StatusFlags::SIG6);
-        }
-        if new & (1 << 22) != 0 {
-            status.insert(StatusFlags::SIG6);
-        }
-        if new & (1 << 23) != 0 {
-            status.remove(StatusFlags::SIG7);
-        }
-        if new & (1 << 24) != 0 {
-            status.insert(StatusFlags::SIG7);
+        match (new >> 3) & 3 {
+            1 => {
+                info!(self.logger, "clear RSP Interrupt");
+                Mi::get_mut().set_irq_line(IrqMask::SP, false);
+            }
+            2 => {
+                info!(self.logger, "force-set RSP Interrupt");
+                Mi::get_mut().set_irq_line(IrqMask::SP, true);
+            }
+            _ => {}
         }
 
         info!(self.logger, "write status reg"; "val" => new.hex(), "status" => ?status);
@@ -279,6 +292,19 @@ impl Sp {
         self.set_status(status)
     }
 
+    // Halt the RSP because of a BREAK instruction. This raises the SP
+    // interrupt if INTBREAK is set. The return value is the same of
+    // set_status().
+    #[must_use]
+    pub(crate) fn brk(&mut self) -> Option<bool> {
+        let mut status = self.get_status();
+        status.insert(StatusFlags::HALT | StatusFlags::BROKE);
+        if status.contains(StatusFlags::INTBREAK) {
+            Mi::get_mut().set_irq_line(IrqMask::SP, true);
+        }
+        self.set_status(status)
+    }
+
     // Change the RSP status. Return an Option that says whether the the halt
     // line of the RSP must be changed, and how.
     #[must_use]
@@ -289,9 +315,6 @@ impl Sp {
         // HALT status changed, propagate effects to CPU
         if changed.contains(StatusFlags::HALT) {
             if status.contains(StatusFlags::HALT) {
-                if status.contains(StatusFlags::INTBREAK) {
-                    Mi::get_mut().set_irq_line(IrqMask::SP, true);
-                }
                 return Some(true);
             } else {
                 // Restore execution. RESET is *NOT* performed:
@@ -304,106 +327,122 @@ impl Sp {
         None
     }
 
-    fn cb_read_reg_dma_full(&self, _old: u32) -> u32 {
-        self.get_status().contains(StatusFlags::DMAFULL) as u32
+    // Retire the completed DMA transfers, and update the DMA flags in the
+    // status register. Transfers are timed on the RSP clock.
+    fn update_dma_status(&mut self) -> StatusFlags {
+        self.dma.advance(RSPCPU::get().cycles());
+        let mut status = self.get_status();
+        status.set(StatusFlags::DMABUSY, self.dma.is_busy());
+        status.set(StatusFlags::DMAFULL, self.dma.is_full());
+        self.reg_status.set(status.bits());
+        status
     }
-    fn cb_read_reg_dma_busy(&self, _old: u32) -> u32 {
-        self.get_status().contains(StatusFlags::DMABUSY) as u32
+
+    fn cb_read_reg_status(&mut self, _old: u32) -> u32 {
+        self.update_dma_status().bits()
+    }
+    fn cb_read_reg_dma_full(&mut self, _old: u32) -> u32 {
+        self.update_dma_status().contains(StatusFlags::DMAFULL) as u32
+    }
+    fn cb_read_reg_dma_busy(&mut self, _old: u32) -> u32 {
+        self.update_dma_status().contains(StatusFlags::DMABUSY) as u32
     }
 
-    fn cb_read_reg_semaphore(&self, old: u32) -> u32 {
-        if old == 0 {
-            // Semaphore is acquired when read as 0.
-            // self.reg_semaphore.set(1);
-        }
+    fn cb_read_reg_semaphore(&mut self, old: u32) -> u32 {
+        // Reading is a test-and-set: the semaphore is acquired when read
+        // as 0, and is left set in any case.
+        self.reg_semaphore.set(1);
         old
     }
 
-    fn dma_xfer(
-        &self,
-        mut src: u32,
-        mut dst: u32,
-        width: usize,
-        count: usize,
-        skip_src: usize,
-        skip_dst: usize,
-    ) {
-        let bus = &mut R4300::get_mut().bus;
-        for _ in 0..count {
-            let src_hwio = bus.fetch_read::<u8>(src);
-            let mut dst_hwio = bus.fetch_write::<u8>(dst);
-            if !src_hwio.is_mem() {
-                error!(self.logger, "DMA src address not in linear memory!");
-                break;
-            }
-            if !dst_hwio.is_mem() {
-                error!(self.logger, "DMA dst address not in linear memory!");
-                break;
-            }
-            let src_mem = src_hwio.mem().unwrap();
-            let dst_mem = dst_hwio.mem().unwrap();
-            dst_mem[0..width].copy_from_slice(&src_mem[0..width]);
-
-            src += (width + skip_src) as u32;
-            dst += (width + skip_dst) as u32;
-        }
+    fn cb_write_reg_semaphore(&mut self, _old: u32, _new: u32) {
+        // Any write releases the semaphore, regardless of the value.
+        self.reg_semaphore.set(0);
     }
 
-    fn cb_write_reg_dma_rd_len(&self, _old: u32, val: u32) {
-        // All DMA transfers are at least 8 bytes, and the RSP basically
+    // Run a DMA transfer between RDRAM and DMEM/IMEM, with the geometry
+    // written to one of the length registers: length of each line (minus
+    // one), number of lines (minus one) and RDRAM skip between lines.
+    //
+    // Data is moved immediately, but the transfer is queued in the DMA
+    // channel, so that DMA_BUSY and DMA_FULL are set until it would be
+    // completed. A transfer requested while DMA_FULL is set is ignored.
+    fn dma_xfer(&mut self, val: u32, to_rsp: bool) {
+        // All DMA transfers are a multiple of 8 bytes, and the RSP basically
         // ignores the last 3 bits.
-        let val = val | 0x7;
-        let width = (val & 0xFFF) as usize + 1;
-        let count = ((val >> 12) & 0xFF) as usize + 1;
-        let skip = ((val >> 20) & 0xFFF) as usize;
-
-        // Addresses are treated as 64-bit aligned.
-        let src = self.reg_dma_rdram_addr.get() & !0x7;
-        let dst = self.reg_dma_rsp_addr.get() & !0x7;
-
-        info!(self.logger, "DMA xfer: RDRAM -> RSP"; o!(
-            "rdram" => src.hex(),
-            "rsp" =>  dst.hex(),
+        let width = ((val & 0xFFF) | 0x7) + 1;
+        let count = ((val >> 12) & 0xFF) + 1;
+        let skip = (val >> 20) & 0xFF8;
+
+        // Addresses are treated as 64-bit aligned. Bit 12 of the SP address
+        // selects IMEM, and the transfer wraps within the selected memory.
+        let rsp_addr = self.reg_dma_rsp_addr.get();
+        let imem = rsp_addr & 0x1000 != 0;
+        let mut mem_addr = rsp_addr & 0xFF8;
+        let mut dram_addr = self.reg_dma_rdram_addr.get() & !0x7;
+
+        info!(self.logger, "DMA xfer"; o!(
+            "dir" => if to_rsp { "RDRAM -> RSP" } else { "RSP -> RDRAM" },
+            "rdram" => dram_addr.hex(),
+            "rsp" => rsp_addr.hex(),
             "width" => width,
             "count" => count,
             "skip" => skip,
         ));
 
-        self.dma_xfer(src, dst + 0x0400_0000, width, count, skip, 0);
+        if self.update_dma_status().contains(StatusFlags::DMAFULL) {
+            error!(self.logger, "DMA requested while full");
+            return;
+        }
+        self.dma.start(RSPCPU::get().cycles(), (width * count) as usize);
+        self.update_dma_status();
+
+        let bus = &mut R4300::get_mut().bus;
+        let mem = if imem { &mut self.imem } else { &mut self.dmem };
+        for _ in 0..count {
+            for _ in 0..width / 8 {
+                let m = &mut mem[mem_addr as usize..mem_addr as usize + 8];
+                if to_rsp {
+                    let val = bus.read::<u64>(dram_addr);
+                    BigEndian::write_u64(m, val);
+                    // DMEM/IMEM is written directly, so report it to the bus
+                    // watchpoints ourselves.
+                    bus.notify_write::<u64>(0x0400_0000 | (rsp_addr & 0x1000) | mem_addr, val);
+                } else {
+                    bus.write::<u64>(dram_addr, BigEndian::read_u64(m));
+                }
+                mem_addr = (mem_addr + 8) & 0xFFF;
+                dram_addr = (dram_addr + 8) & 0xFF_FFFF;
+            }
+            dram_addr = (dram_addr + skip) & 0xFF_FFFF;
+        }
+
+        // At the end of the transfer, the address registers point past the
+        // last transferred byte, and the length registers read back with
+        // count 0 and length 0xFF8.
+        self.reg_dma_rsp_addr.set((rsp_addr & 0x1000) | mem_addr);
+        self.reg_dma_rdram_addr.set(dram_addr);
+        let len = (val & 0xFFF0_0000) | 0xFF8;
+        self.reg_dma_rd_len.set(len);
+        self.reg_dma_wr_len.set(len);
     }
 
-    fn cb_write_reg_dma_wr_len(&self, _old: u32, val: u32) {
-        // All DMA transfers are at least 8 bytes, and the RSP basically
-        // ignores the last 3 bits.
-        let val = val | 0x7;
-        let width = (val & 0xFFF) as usize + 1;
-        let count = ((val >> 12) & 0xFF) as usize + 1;
-        let skip = ((val >> 20) & 0xFFF) as usize;
-
-        info!(self.logger, "DMA xfer: RSP -> RDRAM"; o!(
-            "rsp" =>  self.reg_dma_rsp_addr.get().hex(),
-            "rdram" => self.reg_dma_rdram_addr.get().hex(),
-            "width" => width,
-            "count" => count,
-            "skip" => skip,
-        ));
+    fn cb_write_reg_dma_rd_len(&mut self, _old: u32, val: u32) {
+        self.dma_xfer(val, true);
+    }
 
-        self.dma_xfer(
-            self.reg_dma_rsp_addr.get() + 0x0400_0000,
-            self.reg_dma_rdram_addr.get(),
-            width,
-            count,
-            0,
-            skip,
-        );
+    fn cb_write_reg_dma_wr_len(&mut self, _old: u32, val: u32) {
+        self.dma_xfer(val, false);
     }
 
     fn cb_write_reg_rsp_pc(&self, _old: u32, val: u32) {
+        // PC is word-aligned and always points within IMEM.
+        let val = val & 0xFFC;
         info!(self.logger, "RSP set PC"; o!("pc" => val.hex()));
         RSPCPU::get_mut().ctx_mut().set_pc(val as u64);
     }
 
     fn cb_read_reg_rsp_pc(&self, _old: u32) -> u32 {
-        RSPCPU::get().ctx().get_pc() as u32 & 0xFFF
+        RSPCPU::get().ctx().get_pc() as u32 & 0xFFC
     }
 }
@@ -104,14 +104,13 @@ pub(crate) unsafe fn vcr(
     //
     // Optimize as:
     //   (VS & SIGN) + VT < 0
-    // (with saturation on last addition to avoid overflow)
-
-    // FIXME: missing test! MUST REMOVE ADDS(SIGN)
+    // (no saturation needed: with SIGN=-1, VS and VT have opposite signs)
     let le = _mm_srai_epi16(_mm_add_epi16(_mm_and_si128(sign, vs), vt), 15);
 
+    // Contrary to VCH, VCR clips against the one's complement of VT.
     let res = vselect(
         sign,
-        vselect(le, _mm_mullo_epi16(vones, vt), vs),
+        vselect(le, _mm_xor_si128(vones, vt), vs),
         vselect(ge, vt, vs),
     );
 
@@ -165,3 +164,49 @@ pub(crate) unsafe fn vcl(
         vzero,
     )
 }
+
+// The expected values below are derived from the documented behaviour of
+// VCR; they have not been recorded on hardware (see tests/gengolden).
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(l: [u16; 8]) -> __m128i {
+        unsafe { _mm_loadu_si128(l.as_ptr() as *const _) }
+    }
+
+    fn u(v: __m128i) -> [u16; 8] {
+        let mut l = [0u16; 8];
+        unsafe { _mm_storeu_si128(l.as_mut_ptr() as *mut _, v) };
+        l
+    }
+
+    #[test]
+    fn vcr_ones_complement() {
+        // Opposite signs: VT is replaced by its one's complement when
+        // VS+VT+1 <= 0.
+        let vs = [
+            0x0001, 0x0005, 0x0005, 0x7FFF, 0x0000, 0x1234, 0x0010, 0x4000,
+        ];
+        let vt = [
+            0x8000, 0xFFFA, 0xFFFB, 0x8000, 0xFFFF, 0xEDCB, 0xFFE0, 0xC000,
+        ];
+        let le = [0xFFFF, 0xFFFF, 0, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0];
+
+        let (res, _, _, le1, ge1, _) = unsafe { vcr(m(vs), m(vt)) };
+        assert_eq!(
+            u(res),
+            [0x7FFF, 0x0005, 0x0005, 0x7FFF, 0x0000, 0x1234, 0x001F, 0x4000]
+        );
+        assert_eq!(u(le1), le);
+        assert_eq!(u(ge1), [0xFFFF; 8]);
+
+        let (res, _, _, le2, ge2, _) = unsafe { vcr(m(vt), m(vs)) };
+        assert_eq!(
+            u(res),
+            [0xFFFE, 0xFFFA, 0xFFFB, 0x8000, 0xFFFF, 0xEDCB, 0xFFEF, 0xC000]
+        );
+        assert_eq!(u(le2), le);
+        assert_eq!(u(ge2), [0; 8]);
+    }
+}
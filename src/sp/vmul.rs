@@ -211,3 +211,225 @@ gen_mul_variant!(vmulf, internal_vmulfu, "sse2", true, false);
 gen_mul_variant!(vmulu, internal_vmulfu, "sse2", false, false);
 gen_mul_variant!(vmacf, internal_vmulfu, "sse2", true, true);
 gen_mul_variant!(vmacu, internal_vmulfu, "sse2", false, true);
+
+// The MPEG quantization (VMULQ/VMACQ) and rounding (VRNDP/VRNDN) ops are
+// rarely used, so they are implemented lane by lane rather than with SSE.
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn lanes(v: __m128i) -> [i16; 8] {
+    let mut l = [0i16; 8];
+    _mm_storeu_si128(l.as_mut_ptr() as *mut _, v);
+    l
+}
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn from_lanes(l: [i16; 8]) -> __m128i {
+    _mm_loadu_si128(l.as_ptr() as *const _)
+}
+
+fn clamp_signed(x: i64) -> i16 {
+    x.max(i16::min_value() as i64).min(i16::max_value() as i64) as i16
+}
+
+#[target_feature(enable = "sse2")]
+pub unsafe fn vmulq(
+    vs: __m128i,
+    vt: __m128i,
+    _aclo: __m128i,
+    _acmd: __m128i,
+    _achi: __m128i,
+) -> (__m128i, __m128i, __m128i, __m128i) {
+    let (vs, vt) = (lanes(vs), lanes(vt));
+    let (mut res, mut md, mut hi) = ([0i16; 8], [0i16; 8], [0i16; 8]);
+    for i in 0..8 {
+        let mut prod = vs[i] as i32 * vt[i] as i32;
+        if prod < 0 {
+            // Round towards zero
+            prod += 31;
+        }
+        hi[i] = (prod >> 16) as i16;
+        md[i] = prod as i16;
+        res[i] = clamp_signed((prod >> 1) as i64) & !15;
+    }
+    (
+        from_lanes(res),
+        _mm_setzero_si128(),
+        from_lanes(md),
+        from_lanes(hi),
+    )
+}
+
+#[target_feature(enable = "sse2")]
+pub unsafe fn vmacq(
+    _vs: __m128i,
+    _vt: __m128i,
+    aclo: __m128i,
+    acmd: __m128i,
+    achi: __m128i,
+) -> (__m128i, __m128i, __m128i, __m128i) {
+    let (mut md, mut hi) = (lanes(acmd), lanes(achi));
+    let mut res = [0i16; 8];
+    for i in 0..8 {
+        let mut acc = ((hi[i] as i32) << 16) | (md[i] as u16 as i32);
+        // Move the accumulator one step (32) towards zero, unless bit 5
+        // is already set.
+        if acc & (1 << 5) == 0 {
+            if acc < 0 {
+                acc += 32;
+            } else if acc >= 32 {
+                acc -= 32;
+            }
+        }
+        hi[i] = (acc >> 16) as i16;
+        md[i] = acc as i16;
+        res[i] = clamp_signed((acc >> 1) as i64) & !15;
+    }
+    (from_lanes(res), aclo, from_lanes(md), from_lanes(hi))
+}
+
+/// VRNDP/VRNDN: add VT to the accumulator if it is positive (VRNDP) or
+/// negative (VRNDN). If `mid` is set, VT is added to the middle slice.
+#[target_feature(enable = "sse2")]
+pub unsafe fn vrnd(
+    vt: __m128i,
+    aclo: __m128i,
+    acmd: __m128i,
+    achi: __m128i,
+    mid: bool,
+    positive: bool,
+) -> (__m128i, __m128i, __m128i, __m128i) {
+    let vt = lanes(vt);
+    let (mut lo, mut md, mut hi) = (lanes(aclo), lanes(acmd), lanes(achi));
+    let mut res = [0i16; 8];
+    for i in 0..8 {
+        let mut acc =
+            ((hi[i] as i64) << 32) | ((md[i] as u16 as i64) << 16) | (lo[i] as u16 as i64);
+        if (acc >= 0) == positive {
+            let add = if mid {
+                (vt[i] as i64) << 16
+            } else {
+                vt[i] as i64
+            };
+            // Wrap to 48 bits
+            acc = ((acc + add) << 16) >> 16;
+        }
+        hi[i] = (acc >> 32) as i16;
+        md[i] = (acc >> 16) as i16;
+        lo[i] = acc as i16;
+        res[i] = clamp_signed(acc >> 16);
+    }
+    (
+        from_lanes(res),
+        from_lanes(lo),
+        from_lanes(md),
+        from_lanes(hi),
+    )
+}
+
+// The expected values below are derived from the documented behaviour of
+// these ops; they have not been recorded on hardware (see tests/gengolden).
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(l: [u16; 8]) -> __m128i {
+        let mut v = [0i16; 8];
+        for i in 0..8 {
+            v[i] = l[i] as i16;
+        }
+        unsafe { from_lanes(v) }
+    }
+
+    fn u(v: __m128i) -> [u16; 8] {
+        let l = unsafe { lanes(v) };
+        let mut r = [0u16; 8];
+        for i in 0..8 {
+            r[i] = l[i] as u16;
+        }
+        r
+    }
+
+    #[test]
+    fn vmulq() {
+        let vs = m([0x1234, 0xFFFF, 0x8000, 0x8000, 0x0100, 0x0002, 0xFFFD, 0]);
+        let vt = m([0x0010, 0x0001, 0x8000, 0x7FFF, 0x0100, 0x0040, 0x0010, 0]);
+        let z = unsafe { _mm_setzero_si128() };
+        let (res, lo, md, hi) = unsafe { super::vmulq(vs, vt, z, z, z) };
+        assert_eq!(
+            u(res),
+            [0x7FF0, 0, 0x7FF0, 0x8000, 0x7FF0, 0x0040, 0xFFF0, 0]
+        );
+        assert_eq!(u(lo), [0; 8]);
+        assert_eq!(u(md), [0x2340, 0x001E, 0, 0x801F, 0, 0x0080, 0xFFEF, 0]);
+        assert_eq!(u(hi), [0x0001, 0, 0x4000, 0xC000, 0x0001, 0, 0xFFFF, 0]);
+    }
+
+    #[test]
+    fn vmacq() {
+        let md = m([0x0040, 0x0020, 0x001F, 0xFFC0, 0xFFE0, 0x0000, 0x0000, 0]);
+        let hi = m([0x0000, 0x0000, 0x0000, 0xFFFF, 0xFFFF, 0x7FFF, 0x8000, 0]);
+        let lo = m([1, 2, 3, 4, 5, 6, 7, 8]);
+        let z = unsafe { _mm_setzero_si128() };
+        let (res, lo2, md2, hi2) = unsafe { super::vmacq(z, z, lo, md, hi) };
+        assert_eq!(
+            u(res),
+            [0x0010, 0x0010, 0, 0xFFF0, 0xFFF0, 0x7FF0, 0x8000, 0]
+        );
+        assert_eq!(u(lo2), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(
+            u(md2),
+            [0x0020, 0x0020, 0x001F, 0xFFE0, 0xFFE0, 0xFFE0, 0x0020, 0]
+        );
+        assert_eq!(
+            u(hi2),
+            [0x0000, 0x0000, 0x0000, 0xFFFF, 0xFFFF, 0x7FFE, 0x8000, 0]
+        );
+    }
+
+    #[test]
+    fn vrnd() {
+        let lo = m([0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x1234, 0xFFFF, 0]);
+        let md = m([0x0001, 0xFFFF, 0x0000, 0x7FFF, 0xFFFF, 0x0000, 0xFFFF, 0]);
+        let hi = m([0x0000, 0xFFFF, 0x0000, 0x0000, 0x7FFF, 0x8000, 0xFFFF, 0]);
+        let vt = m([
+            0x7FFF, 0x0005, 0xFFFF, 0x0001, 0x7FFF, 0x0001, 0x0001, 0x0001,
+        ]);
+
+        // VRNDP, VT added to the low slice of non-negative accumulators
+        let (res, lo2, md2, hi2) = unsafe { super::vrnd(vt, lo, md, hi, false, true) };
+        assert_eq!(
+            u(res),
+            [0x0001, 0xFFFF, 0xFFFF, 0x7FFF, 0x7FFF, 0x8000, 0xFFFF, 0]
+        );
+        assert_eq!(
+            u(lo2),
+            [0x7FFF, 0, 0xFFFF, 0x0001, 0x7FFF, 0x1234, 0xFFFF, 1]
+        );
+        assert_eq!(
+            u(md2),
+            [0x0001, 0xFFFF, 0xFFFF, 0x7FFF, 0xFFFF, 0, 0xFFFF, 0]
+        );
+        assert_eq!(u(hi2), [0, 0xFFFF, 0xFFFF, 0, 0x7FFF, 0x8000, 0xFFFF, 0]);
+
+        // VRNDP, VT added to the middle slice (wrapping at 48 bits)
+        let (res, lo2, md2, hi2) = unsafe { super::vrnd(vt, lo, md, hi, true, true) };
+        assert_eq!(
+            u(res),
+            [0x7FFF, 0xFFFF, 0xFFFF, 0x7FFF, 0x8000, 0x8000, 0xFFFF, 0x0001]
+        );
+        assert_eq!(u(lo2), [0, 0, 0, 0, 0, 0x1234, 0xFFFF, 0]);
+        assert_eq!(
+            u(md2),
+            [0x8000, 0xFFFF, 0xFFFF, 0x8000, 0x7FFE, 0, 0xFFFF, 0x0001]
+        );
+        assert_eq!(u(hi2), [0, 0xFFFF, 0xFFFF, 0, 0x8000, 0x8000, 0xFFFF, 0]);
+
+        // VRNDN, VT added to the low slice of negative accumulators
+        let (res, lo2, md2, hi2) = unsafe { super::vrnd(vt, lo, md, hi, false, false) };
+        assert_eq!(u(res), [0x0001, 0xFFFF, 0, 0x7FFF, 0x7FFF, 0x8000, 0, 0]);
+        assert_eq!(u(lo2), [0, 0x0005, 0, 0, 0, 0x1235, 0x0000, 0]);
+        assert_eq!(u(md2), [0x0001, 0xFFFF, 0, 0x7FFF, 0xFFFF, 0, 0x0000, 0]);
+        assert_eq!(u(hi2), [0, 0xFFFF, 0, 0, 0x7FFF, 0x8000, 0x0000, 0]);
+    }
+}